@@ -0,0 +1,64 @@
+//! Command line arguments of the server
+
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+/// Format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+/// Quality of service server for Pocket Relay
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Path to the config file to load instead of config.toml or config.json
+    /// in the working directory, a `.toml` extension is loaded as TOML
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Sets a config field, taking precedence over the config file and
+    /// environment variables. Can be given multiple times
+    #[arg(long = "set", value_name = "FIELD=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Prints the default config in the --format and exits
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    /// Format the default config is printed in
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Json)]
+    pub format: ConfigFormat,
+
+    /// Waits until the local TCP port accepts connections and exits,
+    /// with a failure code if it didn't within the wait timeout
+    #[arg(long, value_name = "PORT")]
+    pub wait_for_port: Option<u16>,
+
+    /// Time in seconds to wait for the port given to --wait-for-port
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub wait_timeout: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{Args, ConfigFormat};
+
+    /// The default config is printed as JSON unless TOML is chosen
+    #[test]
+    fn print_default_config_format() {
+        let args = Args::try_parse_from(["qos", "--print-default-config"]).unwrap();
+        assert_eq!(args.format, ConfigFormat::Json);
+
+        let args =
+            Args::try_parse_from(["qos", "--print-default-config", "--format", "toml"]).unwrap();
+        assert_eq!(args.format, ConfigFormat::Toml);
+
+        assert!(Args::try_parse_from(["qos", "--format", "yaml"]).is_err());
+    }
+}
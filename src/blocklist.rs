@@ -0,0 +1,70 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use ipnet::Ipv4Net;
+
+use crate::config::Config;
+
+/// Set of addresses made up of individual addresses and CIDR ranges
+#[derive(Debug, Default)]
+pub struct AddressMatcher {
+    /// Individual addresses
+    addresses: HashSet<Ipv4Addr>,
+    /// Address ranges
+    ranges: Vec<Ipv4Net>,
+}
+
+impl AddressMatcher {
+    /// Creates a new address matcher from the provided addresses and
+    /// CIDR ranges, invalid ranges are reported by [Config::validate]
+    /// and skipped here
+    pub fn new(addresses: &[Ipv4Addr], ranges: &[String]) -> Self {
+        let addresses = addresses.iter().copied().collect();
+        let ranges = ranges
+            .iter()
+            .filter_map(|value| value.parse::<Ipv4Net>().ok())
+            // Normalize the ranges so contains checks are exact
+            .map(|range| range.trunc())
+            .collect();
+
+        Self { addresses, ranges }
+    }
+
+    /// Checks whether the provided address is contained in this set
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                Some(addr) => addr,
+                None => return false,
+            },
+        };
+
+        self.addresses.contains(&addr) || self.ranges.iter().any(|range| range.contains(&addr))
+    }
+}
+
+/// Checker for determining whether a source address has been blocked
+/// through the configured blocklist addresses and CIDR ranges
+#[derive(Debug, Default)]
+pub struct BlocklistChecker {
+    /// The blocked addresses
+    blocked: AddressMatcher,
+}
+
+impl BlocklistChecker {
+    /// Creates a new blocklist checker from the blocklist
+    /// values in the provided `config`
+    pub fn new(config: &Config) -> Self {
+        Self {
+            blocked: AddressMatcher::new(&config.ip_blocklist, &config.ip_blocklist_cidr),
+        }
+    }
+
+    /// Checks whether the provided address is blocked
+    pub fn is_blocked(&self, addr: IpAddr) -> bool {
+        self.blocked.contains(addr)
+    }
+}
@@ -0,0 +1,156 @@
+//! Global accounting of the memory used by buffered firewall probes.
+//!
+//! Every firewall session buffers the probes it receives, both in the channel
+//! to the waiting firetype request and in the copy kept for inspection. The
+//! number of sessions isn't bounded so the bytes buffered across all of them
+//! are tracked here against a configurable ceiling, buffering beyond the
+//! ceiling is rejected rather than growing memory without limit
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::{info, warn};
+
+use crate::metrics;
+
+/// Global accounting of the buffered firewall probes
+static BUFFER_LIMIT: BufferLimit = BufferLimit::new();
+
+/// Sets the maximum number of bytes that can be buffered, [None] removes
+/// the ceiling
+pub fn set_max_buffered_bytes(limit: Option<usize>) {
+    BUFFER_LIMIT.set_max(limit);
+}
+
+/// Reserves `bytes` of buffer space, returns false without reserving
+/// anything if the reservation would exceed the ceiling
+pub fn try_reserve(bytes: usize) -> bool {
+    BUFFER_LIMIT.try_reserve(bytes)
+}
+
+/// Releases `bytes` of previously reserved buffer space
+pub fn release(bytes: usize) {
+    BUFFER_LIMIT.release(bytes);
+}
+
+/// Number of buffered bytes tracked against a ceiling
+pub struct BufferLimit {
+    /// Total number of bytes currently buffered
+    buffered: AtomicUsize,
+    /// Maximum number of bytes that can be buffered
+    max: AtomicUsize,
+    /// Whether buffering is currently being rejected, used to only log
+    /// when the ceiling is first reached rather than for every rejection
+    limited: AtomicBool,
+}
+
+impl BufferLimit {
+    pub const fn new() -> Self {
+        Self {
+            buffered: AtomicUsize::new(0),
+            max: AtomicUsize::new(usize::MAX),
+            limited: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the maximum number of bytes that can be buffered, [None]
+    /// removes the ceiling
+    pub fn set_max(&self, limit: Option<usize>) {
+        self.max
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Reserves `bytes` of buffer space, returns false without reserving
+    /// anything if the reservation would exceed the ceiling
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        let limit = self.max.load(Ordering::Relaxed);
+        let reserved = self
+            .buffered
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                current.checked_add(bytes).filter(|total| *total <= limit)
+            });
+
+        match reserved {
+            Ok(_) => {
+                metrics::BUFFERED_BYTES.add(bytes as i64);
+                true
+            }
+            Err(current) => {
+                metrics::BUFFER_LIMIT_REJECTED.inc();
+                if !self.limited.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Buffered bytes reached the limit ({} of {} bytes), rejecting new probes",
+                        current, limit
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Releases `bytes` of previously reserved buffer space
+    pub fn release(&self, bytes: usize) {
+        let previous = self.buffered.fetch_sub(bytes, Ordering::AcqRel);
+        metrics::BUFFERED_BYTES.sub(bytes as i64);
+
+        if previous - bytes < self.max.load(Ordering::Relaxed)
+            && self.limited.swap(false, Ordering::Relaxed)
+        {
+            info!("Buffered bytes back under the limit, accepting new probes");
+        }
+    }
+}
+
+impl Default for BufferLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::Level;
+
+    use super::BufferLimit;
+    use crate::{logging::capture, metrics};
+
+    /// Reservations past the ceiling are rejected with a warning and counted
+    /// until enough is released to get back under it
+    #[tokio::test]
+    async fn reservations_past_ceiling_rejected() {
+        let _metrics = metrics::TEST_LOCK.read().await;
+        let limit = BufferLimit::new();
+        limit.set_max(Some(100));
+
+        assert!(limit.try_reserve(60));
+        assert!(limit.try_reserve(40));
+
+        let rejected = metrics::BUFFER_LIMIT_REJECTED.get();
+        capture::start();
+        assert!(!limit.try_reserve(1));
+        assert!(!limit.try_reserve(10));
+        let records = capture::take();
+        assert_eq!(metrics::BUFFER_LIMIT_REJECTED.get(), rejected + 2);
+
+        // Only the first rejection is logged
+        let warnings: Vec<_> = records
+            .iter()
+            .filter(|(level, _)| *level == Level::Warn)
+            .collect();
+        assert_eq!(warnings.len(), 1, "{:?}", records);
+        assert!(warnings[0].1.contains("100 of 100 bytes"));
+
+        // Rejected reservations don't count towards the buffered bytes
+        capture::start();
+        limit.release(40);
+        assert!(capture::take()
+            .iter()
+            .any(|(level, message)| *level == Level::Info && message.contains("back under")));
+        assert!(limit.try_reserve(40));
+        assert!(!limit.try_reserve(1));
+
+        limit.release(100);
+        limit.set_max(None);
+        assert!(limit.try_reserve(usize::MAX / 2));
+        limit.release(usize::MAX / 2);
+    }
+}
@@ -1,13 +1,260 @@
-use serde::Deserialize;
-use std::{net::Ipv4Addr, path::Path};
+use crate::{
+    config_source::{
+        find_config_file, CliSource, ConfigLoader, ConfigSourceError, DefaultsSource, EnvSource,
+        FileSource, ENV_PREFIX,
+    },
+    firewall::{DEFAULT_CLASSIFIER, FIREWALL_MESSAGE_SIZE},
+    http::{UserAgentVariant, MIN_HTTP_MAX_HEADER_SIZE},
+    identity::ClientIdentityMode,
+    metrics::DEFAULT_MAX_VERSION_LABELS,
+    probe_channel::BackpressurePolicy,
+    service::{
+        DuplicateProbePolicy, PortMismatchPolicy, MAX_SESSION_ID_PREFIX, MIN_SESSION_ID,
+        SESSION_ID_BITS,
+    },
+    udp::{IpSource, RequestByteOrder, SharedPublicIpPolicy, V2_RESPONSE_FIELDS_SIZE},
+};
+use ipnet::{AddrParseError, Ipv4Net};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Config {
     pub http_port: u16,
     pub udp_port_1: u16,
     pub udp_port_2: u16,
     pub self_address: Ipv4Addr,
+    /// Artificial delay in milliseconds added before sending QoS responses
+    pub artificial_delay_ms: u64,
+    /// Maximum random jitter in milliseconds added on top of the artificial delay
+    pub artificial_delay_jitter_ms: u64,
+    /// Whether the debugging HTTP endpoints are enabled
+    pub debug_endpoints: bool,
+    /// Whether HTTP responses should be compressed for clients that accept it
+    pub http_compression: bool,
+    /// Whether the admin HTTP endpoints are enabled
+    pub admin_endpoints: bool,
+    /// Time in seconds latency sessions are kept before being pruned
+    pub session_ttl_secs: u64,
+    /// Source of the address given to clients connecting from private or
+    /// loopback addresses in v1 QoS responses, one of "client_source",
+    /// "configured" or "dynamic"
+    pub probe_response_ip_source: IpSource,
+    /// Individual addresses that are blocked from using the server
+    pub ip_blocklist: Vec<Ipv4Addr>,
+    /// CIDR address ranges that are blocked from using the server
+    pub ip_blocklist_cidr: Vec<String>,
+    /// Individual addresses that are allowed to access the admin endpoints
+    pub admin_allowlist: Vec<Ipv4Addr>,
+    /// CIDR address ranges that are allowed to access the admin endpoints
+    pub admin_allowlist_cidr: Vec<String>,
+    /// Maximum time in seconds after creation that a flow will accept probes
+    pub max_flow_lifetime_secs: u64,
+    /// Optional path to write the binary probe arrival log to
+    pub probe_log_path: Option<PathBuf>,
+    /// IP TTL for QoS response packets
+    pub udp_ttl: u8,
+    /// Optional path to capture QoS packets to in the pcap format
+    pub pcap_output: Option<PathBuf>,
+    /// Fire type returned when classification is disabled or not possible
+    pub firetype_default: u32,
+    /// Whether to classify the fire type by comparing the client internal
+    /// address against the addresses of its firewall probes
+    pub firetype_classify: bool,
+    /// Time in milliseconds the UDP servers wait for a message before
+    /// checking whether the server is shutting down
+    pub udp_poll_timeout_ms: u64,
+    /// Optional size in bytes to truncate QoS responses to for
+    /// simulating path MTU constraints when testing
+    pub response_mtu: Option<usize>,
+    /// Whether to include the X-QoS-Server header in HTTP responses
+    pub server_header: bool,
+    /// Value for the X-QoS-Server header, defaults to the system hostname
+    pub server_name: Option<String>,
+    /// Whether the firewall server should bind a socket per CPU using
+    /// SO_REUSEPORT to spread incoming messages across multiple tasks
+    pub firewall_udp_reuseport: bool,
+    /// Time in milliseconds a firewall session is kept after its firetype
+    /// request completes so that late probes can be logged
+    pub firewall_late_probe_grace_ms: u64,
+    /// Whether to log hex dumps of every received UDP packet at TRACE level
+    pub log_udp_packets: bool,
+    /// Trailing bytes of v1 QoS responses, the meaning of these
+    /// bytes is unknown so they default to zeros
+    pub qos_v1_trailer: [u8; 4],
+    /// Minimum allowed size in bytes of v2 probe payloads, at least 6 so
+    /// the payload can make room for the response fields
+    pub probe_size_min: usize,
+    /// Maximum allowed size in bytes of v2 probe payloads
+    pub probe_size_max: usize,
+    /// Headers removed from every HTTP response
+    pub strip_headers: Vec<String>,
+    /// Whether zero-length UDP datagrams are logged at debug level
+    pub log_empty_datagrams: bool,
+    /// How clients are identified for limits and metrics
+    pub client_identity_mode: ClientIdentityMode,
+    /// Whether the UDP servers finish in-flight messages before stopping,
+    /// new messages received while draining are dropped
+    pub shutdown_drain: bool,
+    /// Whether v1 QoS responses use the extended nanosecond timestamp layout
+    pub extended_timestamp: bool,
+    /// Optional multicast group for the QoS server to receive probes from
+    pub udp_multicast_group: Option<Ipv4Addr>,
+    /// Byte order used when parsing QoS requests, either "big" (standard),
+    /// "little" or "auto" to detect the order of each request. Responses
+    /// always use the standard big-endian framing
+    pub request_byte_order: RequestByteOrder,
+    /// Maintenance mode, when enabled HTTP QoS requests are responded to
+    /// with 503 Service Unavailable and UDP messages are dropped
+    pub maintenance_mode: bool,
+    /// Algorithm used when classifying fire types, one of "open",
+    /// "symmetric" or "rfc3489"
+    pub classifier: String,
+    /// Prefix placed in the upper 12 bits of session IDs to keep them unique
+    /// between multiple servers sharing the same backend
+    pub session_id_prefix: u32,
+    /// First session ID to allocate, allows reproducing a specific
+    /// session ID sequence
+    pub session_id_start: u32,
+    /// Whether to keep a log of recently allocated session IDs, available
+    /// from the debug endpoints
+    pub log_id_allocations: bool,
+    /// Maximum number of firewall probes queued for a firetype request
+    pub firewall_channel_capacity: usize,
+    /// Policy for firewall probes arriving while their queue is full, one of
+    /// "drop_newest", "drop_oldest" or "block_briefly"
+    pub firewall_channel_policy: BackpressurePolicy,
+    /// Maximum time in milliseconds a firewall probe waits for room in its
+    /// queue when using the "block_briefly" policy
+    pub firewall_channel_block_ms: u64,
+    /// Whether to check on startup that the QoS server can be reached
+    /// through the advertised address and port
+    pub udp_reachability_check: bool,
+    /// Addresses of this server advertised to clients as firewall interfaces,
+    /// the self address is used when empty. The firewall server listens on
+    /// every address so each interface uses the same port
+    pub firewall_interface_ips: Vec<Ipv4Addr>,
+    /// Maximum size in bytes of the HTTP request headers, requests with
+    /// larger headers are rejected. Must be at least 8192
+    pub http_max_header_size: usize,
+    /// Whether to log to the local syslog daemon instead of the
+    /// log file and stdout
+    pub use_syslog: bool,
+    /// Syslog facility used when logging to syslog
+    pub syslog_facility: String,
+    /// Maximum number of concurrent latency flows a single client can have,
+    /// clients are identified by their address using the client identity mode
+    pub max_client_flows: Option<u32>,
+    /// Whether latency probes must be signed using the session token given
+    /// to the client, probes without a valid signature are rejected
+    pub probe_signing: bool,
+    /// Shared key used for creating session tokens when probe signing is enabled
+    pub probe_signing_key: String,
+    /// Whether to estimate the upstream bits per second in v2 QoS responses
+    /// from the send timestamps of the probe payload records instead of
+    /// responding with the fixed value
+    pub estimate_ubps: bool,
+    /// Whether firewall probes are acknowledged by sending the
+    /// ACK payload back to the address the probe came from
+    pub firewall_probe_ack: bool,
+    /// Payload sent when acknowledging firewall probes, at most 16 bytes
+    pub firewall_ack_payload: Vec<u8>,
+    /// Geographic region label of this server, reported by the version
+    /// and health endpoints and included in flow summaries
+    pub region: Option<String>,
+    /// Whether to include the region label in an x-qos-region
+    /// header on all HTTP responses
+    pub region_header: bool,
+    /// Handling of latency probes reusing an already received probe number,
+    /// one of "respond", "ignore" or "anomaly"
+    pub duplicate_probe_policy: DuplicateProbePolicy,
+    /// Maximum number of HTTP requests handled at the same time, requests
+    /// beyond the limit are responded to with 503 Service Unavailable
+    pub http_concurrency_limit: usize,
+    /// Handling of latency probes arriving on a different UDP port than the
+    /// one their flow was created for, either "respond" or "ignore"
+    pub port_mismatch_policy: PortMismatchPolicy,
+    /// Number of probe numbers a latency probe can skip ahead of the next
+    /// expected probe number and still be accepted as a new probe, probes
+    /// further ahead are treated as retransmits
+    pub probe_reorder_tolerance: u32,
+    /// Whether v2 probes for unknown sessions are responded to with an
+    /// invalid request response so the client can fail fast instead of
+    /// the usual probe response
+    pub invalid_flow_response: bool,
+    /// Whether the QoS server receives UDP messages in batches using
+    /// recvmmsg, only supported on Linux
+    pub udp_batch_recv: bool,
+    /// Maximum number of distinct client protocol versions tracked by the
+    /// per-version request metrics, other versions are counted together
+    pub metrics_max_version_labels: usize,
+    /// User-Agent patterns of legacy clients that expect a different QoS
+    /// response, the first pattern contained in the User-Agent selects the
+    /// response variant and unmatched clients get the standard response
+    pub user_agent_variants: Vec<UserAgentVariant>,
+    /// Flags sent in the `u1` header field of v1 QoS responses in place of
+    /// the flags echoed from the request, the extended timestamp flag is
+    /// still added when enabled
+    pub qos_response_flags: Option<u32>,
+    /// Minimum size of the payload echoed in v2 QoS responses, smaller
+    /// payloads are zero padded up to this size. Zero disables padding
+    pub response_payload_floor: usize,
+    /// Maximum number of attempts at generating a session secret that
+    /// doesn't collide with an existing session before giving up
+    pub secret_collision_retry_limit: u32,
+    /// Number of worker threads of a dedicated runtime for the UDP QoS and
+    /// firewall servers, by default they share the runtime of the HTTP
+    /// server. A dedicated runtime keeps HTTP load from delaying probe
+    /// responses at the cost of the extra threads sitting idle when there
+    /// are few probes, and threads that can't be shared with HTTP when it's
+    /// busy. Firetype requests wait on firewall probes across both runtimes
+    pub udp_runtime_threads: Option<usize>,
+    /// Whether v2 QoS responses include the server receive timestamp
+    /// appended after the payload, see [crate::udp::RECEIVE_TIMESTAMP_FLAG]
+    pub v2_receive_timestamp: bool,
+    /// XML body of the 500 Internal Server Error response sent when
+    /// an HTTP handler panics
+    pub http_fallback_xml: String,
+    /// Path to a local GeoIP database of `cidr,region` lines used to annotate
+    /// firewall sessions with the approximate region of the client, see
+    /// [crate::geoip]. Sessions have no region when unset or unreadable
+    pub geoip_database: Option<PathBuf>,
+    /// Maximum number of bytes of firewall probes buffered across all sessions,
+    /// probes beyond the limit are dropped. Unlimited when unset
+    pub max_buffered_bytes: Option<usize>,
+    /// Maximum size in bytes of a firewall probe packet, probes are 8 bytes
+    /// but may be padded by clients. Larger packets are discarded as suspicious
+    pub firewall_probe_max_size: usize,
+    /// Address given in v1 QoS responses to clients connecting from the same
+    /// public address as the server, one of "echo" or "self_address"
+    pub shared_public_ip_policy: SharedPublicIpPolicy,
+    /// Whether packets sent from the UDP sockets have the don't fragment bit
+    /// set for path MTU discovery, responses too large for the path are then
+    /// dropped and logged rather than fragmented. Only supported on Linux
+    pub udp_no_fragment: bool,
+    /// Time in milliseconds after startup during which the UDP servers hold
+    /// received messages rather than processing them, giving the public
+    /// address time to resolve. Readiness is reported by the ready endpoint
+    pub warmup_delay_ms: u64,
+    /// Sustained number of QoS probes per second accepted from each client
+    /// address, probes beyond the limit are dropped. Unlimited when unset
+    pub udp_rate_limit_rps: Option<u32>,
+    /// Number of QoS probes a client can send in a burst above the sustained
+    /// rate limit, defaults to three times `udp_rate_limit_rps`
+    pub qos_probe_burst_size: Option<u32>,
+    /// URL that a JSON notification is posted to whenever a latency or
+    /// firewall flow completes, notifications are disabled when unset
+    pub completion_webhook: Option<String>,
+    /// Maximum number of firewall probes read for each firewall session,
+    /// further probes from clients that over-send are left unread
+    pub firewall_session_max_probes: u32,
 }
 
 impl Default for Config {
@@ -17,15 +264,523 @@ impl Default for Config {
             udp_port_1: 17500,
             udp_port_2: 17501,
             self_address: Ipv4Addr::new(127, 0, 0, 1),
+            artificial_delay_ms: 0,
+            artificial_delay_jitter_ms: 0,
+            debug_endpoints: false,
+            http_compression: false,
+            admin_endpoints: false,
+            session_ttl_secs: 60,
+            // Only looked up by default in debug builds
+            probe_response_ip_source: if cfg!(debug_assertions) {
+                IpSource::Dynamic
+            } else {
+                IpSource::Configured
+            },
+            ip_blocklist: Vec::new(),
+            ip_blocklist_cidr: Vec::new(),
+            admin_allowlist: vec![Ipv4Addr::LOCALHOST],
+            admin_allowlist_cidr: Vec::new(),
+            max_flow_lifetime_secs: 30,
+            probe_log_path: None,
+            udp_ttl: 64,
+            pcap_output: None,
+            firetype_default: 2,
+            firetype_classify: false,
+            udp_poll_timeout_ms: 100,
+            response_mtu: None,
+            server_header: false,
+            server_name: None,
+            firewall_udp_reuseport: false,
+            firewall_late_probe_grace_ms: 2000,
+            log_udp_packets: false,
+            qos_v1_trailer: [0; 4],
+            probe_size_min: 6,
+            probe_size_max: 1000,
+            strip_headers: vec!["server".to_string(), "x-powered-by".to_string()],
+            log_empty_datagrams: true,
+            client_identity_mode: ClientIdentityMode::default(),
+            shutdown_drain: true,
+            extended_timestamp: false,
+            udp_multicast_group: None,
+            request_byte_order: RequestByteOrder::default(),
+            maintenance_mode: false,
+            classifier: DEFAULT_CLASSIFIER.to_string(),
+            session_id_prefix: 0,
+            session_id_start: MIN_SESSION_ID,
+            log_id_allocations: false,
+            firewall_channel_capacity: 16,
+            firewall_channel_policy: BackpressurePolicy::default(),
+            firewall_channel_block_ms: 50,
+            udp_reachability_check: false,
+            firewall_interface_ips: Vec::new(),
+            http_max_header_size: 8192,
+            use_syslog: false,
+            syslog_facility: "daemon".to_string(),
+            max_client_flows: None,
+            probe_signing: false,
+            probe_signing_key: String::new(),
+            estimate_ubps: false,
+            firewall_probe_ack: false,
+            firewall_ack_payload: vec![0xAC, 0xAC, 0x00, 0x01],
+            region: None,
+            region_header: false,
+            duplicate_probe_policy: DuplicateProbePolicy::default(),
+            http_concurrency_limit: 1000,
+            port_mismatch_policy: PortMismatchPolicy::default(),
+            probe_reorder_tolerance: 3,
+            invalid_flow_response: false,
+            udp_batch_recv: false,
+            metrics_max_version_labels: DEFAULT_MAX_VERSION_LABELS,
+            user_agent_variants: Vec::new(),
+            qos_response_flags: None,
+            response_payload_floor: 0,
+            secret_collision_retry_limit: 100,
+            udp_runtime_threads: None,
+            v2_receive_timestamp: false,
+            http_fallback_xml: "<error>internal server error</error>".to_string(),
+            geoip_database: None,
+            max_buffered_bytes: None,
+            firewall_probe_max_size: 64,
+            shared_public_ip_policy: SharedPublicIpPolicy::Echo,
+            udp_no_fragment: false,
+            warmup_delay_ms: 0,
+            udp_rate_limit_rps: None,
+            qos_probe_burst_size: None,
+            completion_webhook: None,
+            firewall_session_max_probes: 5,
         }
     }
 }
 
-pub async fn load_config() -> Config {
-    let file = Path::new("config.json");
-    if !file.exists() {
-        return Config::default();
+impl Config {
+    /// Computes the delay to apply before sending a QoS response, this is
+    /// the artificial delay plus a random jitter in the range of
+    /// 0..=artificial_delay_jitter_ms
+    pub fn response_delay(&self) -> Duration {
+        let jitter = if self.artificial_delay_jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=self.artificial_delay_jitter_ms)
+        } else {
+            0
+        };
+
+        Duration::from_millis(self.artificial_delay_ms + jitter)
+    }
+
+    /// Capacity of the probe rate limiter buckets, defaulting to three
+    /// seconds worth of probes at the sustained rate
+    pub fn probe_burst_size(&self) -> u32 {
+        self.qos_probe_burst_size
+            .unwrap_or_else(|| self.udp_rate_limit_rps.unwrap_or(0).saturating_mul(3))
+    }
+
+    /// Addresses advertised to clients as firewall interfaces
+    pub fn firewall_interfaces(&self) -> Vec<Ipv4Addr> {
+        if self.firewall_interface_ips.is_empty() {
+            vec![self.self_address]
+        } else {
+            self.firewall_interface_ips.clone()
+        }
+    }
+
+    /// Checks the config for any invalid values, providing every
+    /// problem found rather than stopping at the first
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        let ports = [
+            ("HTTP", self.http_port),
+            ("QoS UDP", self.udp_port_1),
+            ("firewall UDP", self.udp_port_2),
+        ];
+
+        for (index, (name, port)) in ports.iter().enumerate() {
+            if *port < MIN_PORT {
+                errors.push(ConfigError::PrivilegedPort(name, *port));
+            }
+
+            // Only reported against the first server using the port
+            if let Some((other, _)) = ports[index + 1..]
+                .iter()
+                .find(|(_, other_port)| other_port == port)
+            {
+                errors.push(ConfigError::DuplicatePort(*port, name, other));
+            }
+        }
+
+        if self.self_address.is_unspecified() {
+            errors.push(ConfigError::UnspecifiedSelfAddress);
+        }
+
+        if self.probe_size_min < V2_RESPONSE_FIELDS_SIZE {
+            errors.push(ConfigError::ProbeSizeMinTooSmall(self.probe_size_min));
+        }
+
+        if self.firewall_ack_payload.len() > MAX_FIREWALL_ACK_PAYLOAD {
+            errors.push(ConfigError::FirewallAckPayloadTooLarge(
+                self.firewall_ack_payload.len(),
+            ));
+        }
+
+        if self.secret_collision_retry_limit == 0 {
+            errors.push(ConfigError::ZeroSecretRetryLimit);
+        }
+
+        if self.session_id_prefix > MAX_SESSION_ID_PREFIX {
+            errors.push(ConfigError::SessionIdPrefixTooLarge(self.session_id_prefix));
+        }
+
+        if !(MIN_SESSION_ID..(1 << SESSION_ID_BITS)).contains(&self.session_id_start) {
+            errors.push(ConfigError::SessionIdStartOutOfRange(self.session_id_start));
+        }
+
+        if self.udp_poll_timeout_ms == 0 {
+            errors.push(ConfigError::ZeroUdpPollTimeout);
+        }
+
+        if self.udp_runtime_threads == Some(0) {
+            errors.push(ConfigError::ZeroUdpRuntimeThreads);
+        }
+
+        if self.udp_rate_limit_rps == Some(0) || self.qos_probe_burst_size == Some(0) {
+            errors.push(ConfigError::ZeroRateLimit);
+        }
+
+        if self.firewall_probe_max_size < FIREWALL_MESSAGE_SIZE {
+            errors.push(ConfigError::FirewallProbeMaxSizeTooSmall(
+                self.firewall_probe_max_size,
+            ));
+        }
+
+        let cidr_lists = [
+            ("blocklist", &self.ip_blocklist_cidr),
+            ("admin allowlist", &self.admin_allowlist_cidr),
+        ];
+
+        for (name, ranges) in cidr_lists {
+            for range in ranges {
+                if let Err(err) = range.parse::<Ipv4Net>() {
+                    errors.push(ConfigError::InvalidCidr(name, range.clone(), err));
+                }
+            }
+        }
+
+        if self.firewall_session_max_probes == 0 {
+            errors.push(ConfigError::ZeroFirewallSessionMaxProbes);
+        }
+
+        if self.http_max_header_size < MIN_HTTP_MAX_HEADER_SIZE {
+            errors.push(ConfigError::HttpMaxHeaderSizeTooSmall(
+                self.http_max_header_size,
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Serializes the config as pretty printed JSON in the
+    /// same format that is read from the config file
+    pub fn to_json_pretty(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize config")
+    }
+
+    /// Serializes the config as pretty printed TOML in the
+    /// same format that is read from a TOML config file
+    pub fn to_toml_pretty(&self) -> String {
+        toml::to_string_pretty(self).expect("Failed to serialize config")
+    }
+
+    /// Resolves the name of this server, using the system hostname
+    /// when no name is configured
+    pub fn resolve_server_name(&self) -> String {
+        match &self.server_name {
+            Some(value) => value.clone(),
+            None => hostname::get()
+                .map(|value| value.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+}
+
+/// Maximum size of the firewall ACK payload, kept small so the ACKs
+/// can't be used to amplify spoofed traffic
+pub const MAX_FIREWALL_ACK_PAYLOAD: usize = 16;
+
+/// Lowest port the servers can be configured to use, lower ports
+/// are privileged and most deployments don't run as root
+pub const MIN_PORT: u16 = 1024;
+
+/// Errors for invalid config values
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// A server port is in the privileged range, binding it requires root
+    #[error("{0} port {1} is below {MIN_PORT}, privileged ports can't be bound without root")]
+    PrivilegedPort(&'static str, u16),
+    /// Two servers are configured to use the same port
+    #[error("port {0} is used by both the {1} and {2} servers")]
+    DuplicatePort(u16, &'static str, &'static str),
+    /// Probes could be too small to make room for the v2 response fields
+    #[error(
+        "minimum probe size is {0} bytes but must be at least {V2_RESPONSE_FIELDS_SIZE} bytes"
+    )]
+    ProbeSizeMinTooSmall(usize),
+    /// The self address can't be given to clients to connect to
+    #[error("self address must not be unspecified (0.0.0.0)")]
+    UnspecifiedSelfAddress,
+    /// The firewall ACK payload is larger than the maximum size
+    #[error(
+        "firewall ACK payload is {0} bytes but must be at most {MAX_FIREWALL_ACK_PAYLOAD} bytes"
+    )]
+    FirewallAckPayloadTooLarge(usize),
+    /// Secret generation is never attempted
+    #[error("secret collision retry limit must be at least 1")]
+    ZeroSecretRetryLimit,
+    /// Hyper rejects buffers smaller than its initial read buffer
+    #[error(
+        "HTTP max header size is {0} bytes but must be at least {MIN_HTTP_MAX_HEADER_SIZE} bytes"
+    )]
+    HttpMaxHeaderSizeTooSmall(usize),
+    /// The UDP receive loops would spin without ever waiting for packets
+    #[error("UDP poll timeout must be at least 1ms")]
+    ZeroUdpPollTimeout,
+    /// The dedicated UDP runtime has no threads to run on
+    #[error("UDP runtime threads must be at least 1")]
+    ZeroUdpRuntimeThreads,
+    /// Firewall probes would always be discarded as oversized
+    #[error(
+        "firewall probe max size is {0} bytes but must be at least {FIREWALL_MESSAGE_SIZE} bytes"
+    )]
+    FirewallProbeMaxSizeTooSmall(usize),
+    /// The rate limiter would drop every probe
+    #[error("UDP rate limit and probe burst size must be at least 1")]
+    ZeroRateLimit,
+    /// Firetype requests would complete without reading any probes
+    #[error("firewall session max probes must be at least 1")]
+    ZeroFirewallSessionMaxProbes,
+    /// The session ID prefix doesn't fit in the upper bits of session IDs
+    #[error("session ID prefix {0} is larger than the maximum of {MAX_SESSION_ID_PREFIX}")]
+    SessionIdPrefixTooLarge(u32),
+    /// The first session ID is reserved or outside the session ID sequence
+    #[error(
+        "session ID start {0} must be at least {MIN_SESSION_ID} and below {}",
+        1u32 << SESSION_ID_BITS
+    )]
+    SessionIdStartOutOfRange(u32),
+    /// A CIDR range in an address list couldn't be parsed
+    #[error("invalid {0} CIDR {1}: {2}")]
+    InvalidCidr(&'static str, String, AddrParseError),
+}
+
+/// Loads the config from the defaults, the config file, the environment
+/// variables and then the command line `overrides`, with later sources
+/// taking precedence. The config file is the one at `path` when given,
+/// which must exist, otherwise config.toml or config.json in the working
+/// directory if present
+pub async fn load_config(
+    path: Option<&Path>,
+    overrides: &[String],
+) -> Result<Config, ConfigSourceError> {
+    let file = match path {
+        Some(path) => FileSource::required(path),
+        None => FileSource::new(find_config_file()),
+    };
+
+    ConfigLoader::default()
+        .source(DefaultsSource)
+        .source(file)
+        .source(EnvSource::new(ENV_PREFIX))
+        .source(CliSource::new(overrides))
+        .load()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, path::Path, time::Duration};
+
+    use super::{
+        Config, ConfigError, ConfigLoader, FileSource, MAX_SESSION_ID_PREFIX, SESSION_ID_BITS,
+    };
+
+    /// Response delays with jitter must stay within the configured range
+    /// and actually vary between responses
+    #[test]
+    fn response_delay_within_jitter_range() {
+        let config = Config {
+            artificial_delay_ms: 50,
+            artificial_delay_jitter_ms: 20,
+            ..Default::default()
+        };
+
+        let min = Duration::from_millis(50);
+        let max = Duration::from_millis(70);
+        let mut seen = HashSet::new();
+
+        for _ in 0..10_000 {
+            let delay = config.response_delay();
+            assert!(
+                (min..=max).contains(&delay),
+                "delay {:?} outside of {:?}..={:?}",
+                delay,
+                min,
+                max
+            );
+            seen.insert(delay);
+        }
+
+        // 10000 uniform samples over 21 values will hit every value
+        assert_eq!(seen.len(), 21);
+    }
+
+    /// Without jitter the delay is exactly the artificial delay
+    #[test]
+    fn response_delay_without_jitter() {
+        let config = Config {
+            artificial_delay_ms: 30,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            assert_eq!(config.response_delay(), Duration::from_millis(30));
+        }
+    }
+
+    /// Invalid CIDR ranges are reported as config errors instead of
+    /// panicking when the address lists are built
+    #[test]
+    fn invalid_cidr_fails_validation() {
+        let config = Config {
+            ip_blocklist_cidr: vec!["10.0.0.0/8".to_string(), "10.0.0.0/33".to_string()],
+            admin_allowlist_cidr: vec!["not a range".to_string()],
+            ..Default::default()
+        };
+
+        let errors = config.validate().unwrap_err();
+        let invalid: Vec<_> = errors
+            .iter()
+            .filter_map(|err| match err {
+                ConfigError::InvalidCidr(name, range, _) => Some((*name, range.as_str())),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            invalid,
+            [
+                ("blocklist", "10.0.0.0/33"),
+                ("admin allowlist", "not a range")
+            ]
+        );
+    }
+
+    /// A zero poll timeout would make the UDP receive loops spin
+    #[test]
+    fn zero_udp_poll_timeout_fails_validation() {
+        let config = Config {
+            udp_poll_timeout_ms: 0,
+            ..Default::default()
+        };
+
+        let errors = config.validate().unwrap_err();
+        assert!(errors
+            .iter()
+            .any(|err| matches!(err, ConfigError::ZeroUdpPollTimeout)));
+    }
+
+    /// Probes smaller than the v2 response fields can't be echoed back
+    #[test]
+    fn probe_size_min_too_small_fails_validation() {
+        let config = Config {
+            probe_size_min: 5,
+            ..Default::default()
+        };
+
+        let errors = config.validate().unwrap_err();
+        assert!(errors
+            .iter()
+            .any(|err| matches!(err, ConfigError::ProbeSizeMinTooSmall(5))));
+    }
+
+    /// Header sizes below the hyper minimum are rejected rather than raised
+    #[test]
+    fn http_max_header_size_too_small_fails_validation() {
+        let config = Config {
+            http_max_header_size: 4096,
+            ..Default::default()
+        };
+
+        let errors = config.validate().unwrap_err();
+        assert!(errors
+            .iter()
+            .any(|err| matches!(err, ConfigError::HttpMaxHeaderSizeTooSmall(4096))));
+    }
+
+    /// Loads the config file at `path` on its own, without the
+    /// defaults or environment variables
+    fn load_file(path: &Path) -> Config {
+        ConfigLoader::default()
+            .source(FileSource::new(path))
+            .load()
+            .unwrap()
+    }
+
+    /// The printed default config must load back into the same config
+    /// in both of the supported file formats
+    #[test]
+    fn default_config_round_trips() {
+        let expected = serde_json::to_value(Config::default()).unwrap();
+        let dir = std::env::temp_dir().join(format!("qos-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let json_path = dir.join("config.json");
+        std::fs::write(&json_path, Config::default().to_json_pretty()).unwrap();
+        let from_json = load_file(&json_path);
+
+        let toml_path = dir.join("config.toml");
+        std::fs::write(&toml_path, Config::default().to_toml_pretty()).unwrap();
+        let from_toml = load_file(&toml_path);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(serde_json::to_value(from_json).unwrap(), expected);
+        assert_eq!(serde_json::to_value(from_toml).unwrap(), expected);
+    }
+
+    /// Session ID prefixes that don't fit above the ID sequence are rejected
+    #[test]
+    fn session_id_prefix_too_large_fails_validation() {
+        let config = Config {
+            session_id_prefix: MAX_SESSION_ID_PREFIX + 1,
+            ..Default::default()
+        };
+
+        let errors = config.validate().unwrap_err();
+        assert!(errors
+            .iter()
+            .any(|err| matches!(err, ConfigError::SessionIdPrefixTooLarge(_))));
+
+        let config = Config {
+            session_id_prefix: MAX_SESSION_ID_PREFIX,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    /// Session ID starts outside of the allocatable IDs are rejected
+    #[test]
+    fn session_id_start_out_of_range_fails_validation() {
+        for start in [0, 1, 1 << SESSION_ID_BITS] {
+            let config = Config {
+                session_id_start: start,
+                ..Default::default()
+            };
+
+            let errors = config.validate().unwrap_err();
+            assert!(errors
+                .iter()
+                .any(|err| matches!(err, ConfigError::SessionIdStartOutOfRange(_))));
+        }
     }
-    let bytes = tokio::fs::read(file).await.expect("Failed to read config");
-    serde_json::from_slice(&bytes).expect("Failed to parse config")
 }
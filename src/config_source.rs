@@ -0,0 +1,453 @@
+//! Sources the server config is loaded from.
+//!
+//! Each [ConfigSource] provides a JSON object of config values, the
+//! [ConfigLoader] merges the objects of its sources in order so that
+//! values from later sources take precedence over earlier ones. Only
+//! top level fields are merged, a nested value from a later source
+//! replaces the whole value rather than being merged into it
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::config::Config;
+
+/// Name of the JSON config file
+pub const CONFIG_FILE_NAME: &str = "config.json";
+
+/// Name of the TOML config file, preferred over the JSON config file
+pub const TOML_CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Prefix for environment variables that set config values
+pub const ENV_PREFIX: &str = "QOS_";
+
+/// Object of config values provided by a source
+pub type ConfigValues = Map<String, Value>;
+
+/// Source of config values
+pub trait ConfigSource {
+    /// Name of the source used in error messages
+    fn name(&self) -> String;
+
+    /// Loads the config values from the source
+    fn load(&self) -> Result<ConfigValues, ConfigSourceError>;
+}
+
+/// Errors that can occur while loading the config
+#[derive(Debug, Error)]
+pub enum ConfigSourceError {
+    /// The source couldn't be read
+    #[error("failed to read {0}: {1}")]
+    Read(String, io::Error),
+    /// A required config file doesn't exist
+    #[error("config file {0} doesn't exist")]
+    Missing(String),
+    /// The source contents aren't valid JSON
+    #[error("failed to parse {0}: {1}")]
+    Parse(String, serde_json::Error),
+    /// The source contents aren't valid TOML
+    #[error("failed to parse {0}: {1}")]
+    ParseToml(String, toml::de::Error),
+    /// The source contents aren't a JSON object
+    #[error("{0} must contain a JSON object")]
+    NotObject(String),
+    /// A command line override isn't in the `field=value` form
+    #[error("invalid config override {0}, expected field=value")]
+    InvalidOverride(String),
+    /// The merged config values aren't a valid config
+    #[error("invalid config: {0}")]
+    Invalid(serde_json::Error),
+}
+
+/// Source providing the default config values
+pub struct DefaultsSource;
+
+impl ConfigSource for DefaultsSource {
+    fn name(&self) -> String {
+        "defaults".to_string()
+    }
+
+    fn load(&self) -> Result<ConfigValues, ConfigSourceError> {
+        match serde_json::to_value(Config::default()) {
+            Ok(Value::Object(values)) => Ok(values),
+            Ok(_) => Err(ConfigSourceError::NotObject(self.name())),
+            Err(err) => Err(ConfigSourceError::Parse(self.name(), err)),
+        }
+    }
+}
+
+/// Source loading config values from a JSON file, or a TOML file when the
+/// path has a `.toml` extension, a missing file provides no values unless
+/// the file is required
+pub struct FileSource {
+    /// Path to the config file
+    path: PathBuf,
+    /// Whether a missing file is an error
+    required: bool,
+}
+
+impl FileSource {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            required: false,
+        }
+    }
+
+    /// Creates a source for a file that must exist, such as
+    /// a config file given on the command line
+    pub fn required(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            required: true,
+        }
+    }
+}
+
+impl ConfigSource for FileSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn load(&self) -> Result<ConfigValues, ConfigSourceError> {
+        if !self.path.exists() {
+            if self.required {
+                return Err(ConfigSourceError::Missing(self.name()));
+            }
+            return Ok(ConfigValues::new());
+        }
+
+        let bytes =
+            std::fs::read(&self.path).map_err(|err| ConfigSourceError::Read(self.name(), err))?;
+
+        let value = if self.path.extension().is_some_and(|ext| ext == "toml") {
+            let table = toml::from_str::<toml::Table>(&String::from_utf8_lossy(&bytes))
+                .map_err(|err| ConfigSourceError::ParseToml(self.name(), err))?;
+            serde_json::to_value(table)
+        } else {
+            serde_json::from_slice(&bytes)
+        };
+
+        match value {
+            Ok(Value::Object(values)) => Ok(values),
+            Ok(_) => Err(ConfigSourceError::NotObject(self.name())),
+            Err(err) => Err(ConfigSourceError::Parse(self.name(), err)),
+        }
+    }
+}
+
+/// Finds the config file to load in the working directory, preferring
+/// the TOML config file over the JSON config file when both exist
+pub fn find_config_file() -> PathBuf {
+    find_config_file_in(Path::new(""))
+}
+
+/// Finds the config file to load in the `dir` like [find_config_file]
+fn find_config_file_in(dir: &Path) -> PathBuf {
+    let toml = dir.join(TOML_CONFIG_FILE_NAME);
+    let json = dir.join(CONFIG_FILE_NAME);
+
+    if !toml.exists() {
+        return json;
+    }
+
+    if json.exists() {
+        warn!(
+            "Both {} and {} exist, using {}",
+            TOML_CONFIG_FILE_NAME, CONFIG_FILE_NAME, TOML_CONFIG_FILE_NAME
+        );
+    }
+
+    toml
+}
+
+/// Converts the `raw` string value for the config `field` into a JSON value.
+/// The value is kept as a string when the field accepts strings so that
+/// string fields can hold number or boolean like values, otherwise it's
+/// parsed as JSON falling back to a string when it isn't valid JSON
+fn coerce_value(field: &str, raw: &str) -> Value {
+    let value = Value::String(raw.to_string());
+
+    let mut values = ConfigValues::new();
+    values.insert(field.to_string(), value.clone());
+
+    // Every other field uses its default so only this field can fail
+    if serde_json::from_value::<Config>(Value::Object(values)).is_ok() {
+        return value;
+    }
+
+    serde_json::from_str(raw).unwrap_or(value)
+}
+
+/// Source loading config values from environment variables starting with
+/// the prefix, the rest of the variable name lowercased is the field name
+/// (i.e. QOS_HTTP_PORT sets http_port). Values are coerced to the type of
+/// their field, see [coerce_value]
+pub struct EnvSource {
+    /// Prefix of the environment variables
+    prefix: String,
+}
+
+impl EnvSource {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl ConfigSource for EnvSource {
+    fn name(&self) -> String {
+        format!("{}* environment variables", self.prefix)
+    }
+
+    fn load(&self) -> Result<ConfigValues, ConfigSourceError> {
+        let values = std::env::vars()
+            .filter_map(|(key, value)| {
+                let field = key.strip_prefix(&self.prefix)?.to_lowercase();
+                let value = coerce_value(&field, &value);
+                Some((field, value))
+            })
+            .collect();
+        Ok(values)
+    }
+}
+
+/// Source loading config values from `field=value` overrides given on the
+/// command line, values are coerced to the type of their field like the
+/// environment variables
+pub struct CliSource {
+    /// Overrides in the `field=value` form
+    overrides: Vec<String>,
+}
+
+impl CliSource {
+    pub fn new(overrides: &[String]) -> Self {
+        Self {
+            overrides: overrides.to_vec(),
+        }
+    }
+}
+
+impl ConfigSource for CliSource {
+    fn name(&self) -> String {
+        "command line overrides".to_string()
+    }
+
+    fn load(&self) -> Result<ConfigValues, ConfigSourceError> {
+        self.overrides
+            .iter()
+            .map(|value| {
+                let (field, raw) = value
+                    .split_once('=')
+                    .ok_or_else(|| ConfigSourceError::InvalidOverride(value.clone()))?;
+                Ok((field.to_string(), coerce_value(field, raw)))
+            })
+            .collect()
+    }
+}
+
+/// Loads the config by merging the values of multiple sources
+/// in order of increasing precedence
+#[derive(Default)]
+pub struct ConfigLoader {
+    /// Sources in order of increasing precedence
+    sources: Vec<Box<dyn ConfigSource>>,
+}
+
+impl ConfigLoader {
+    /// Adds a source taking precedence over all the current sources
+    pub fn source(mut self, source: impl ConfigSource + 'static) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Loads and merges the values from all the sources into a config
+    pub fn load(&self) -> Result<Config, ConfigSourceError> {
+        let mut merged = ConfigValues::new();
+        for source in &self.sources {
+            merged.extend(source.load()?);
+        }
+        serde_json::from_value(Value::Object(merged)).map_err(ConfigSourceError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        net::Ipv4Addr,
+        path::{Path, PathBuf},
+    };
+
+    use log::Level;
+
+    use super::{
+        find_config_file_in, CliSource, ConfigLoader, DefaultsSource, EnvSource, FileSource,
+        CONFIG_FILE_NAME, ENV_PREFIX, TOML_CONFIG_FILE_NAME,
+    };
+    use crate::logging::capture;
+
+    /// Creates an empty temporary directory unique to the `name`
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qos-{}-{}", name, std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Finds the config file in the `dir` and loads the HTTP port from it
+    fn discovered_http_port(dir: &Path) -> u16 {
+        ConfigLoader::default()
+            .source(DefaultsSource)
+            .source(FileSource::new(find_config_file_in(dir)))
+            .load()
+            .unwrap()
+            .http_port
+    }
+
+    /// Whichever config file is present is discovered and loaded, the TOML
+    /// file is preferred with a warning when both are present
+    #[test]
+    fn config_file_discovery() {
+        let toml = "http_port = 1111\n";
+        let json = r#"{"http_port": 2222}"#;
+
+        let dir = temp_dir("discovery-toml");
+        fs::write(dir.join(TOML_CONFIG_FILE_NAME), toml).unwrap();
+        assert_eq!(discovered_http_port(&dir), 1111);
+
+        let dir = temp_dir("discovery-json");
+        fs::write(dir.join(CONFIG_FILE_NAME), json).unwrap();
+        assert_eq!(discovered_http_port(&dir), 2222);
+
+        let dir = temp_dir("discovery-both");
+        fs::write(dir.join(TOML_CONFIG_FILE_NAME), toml).unwrap();
+        fs::write(dir.join(CONFIG_FILE_NAME), json).unwrap();
+        capture::start();
+        assert_eq!(discovered_http_port(&dir), 1111);
+        assert!(capture::take()
+            .iter()
+            .any(|(level, message)| *level == Level::Warn && message.contains("Both")));
+
+        // Missing files are optional when discovered
+        let dir = temp_dir("discovery-none");
+        assert_eq!(
+            discovered_http_port(&dir),
+            crate::config::Config::default().http_port
+        );
+    }
+
+    /// Environment variable set for the lifetime of the guard, so variables
+    /// using the real prefix don't leak into other tests
+    struct EnvVarGuard(String);
+
+    impl EnvVarGuard {
+        fn set(name: String, value: &str) -> Self {
+            std::env::set_var(&name, value);
+            Self(name)
+        }
+    }
+
+    impl Drop for EnvVarGuard {
+        fn drop(&mut self) {
+            std::env::remove_var(&self.0);
+        }
+    }
+
+    /// Loads the config from the defaults and the real environment prefix
+    fn load_env() -> crate::config::Config {
+        ConfigLoader::default()
+            .source(DefaultsSource)
+            .source(EnvSource::new(ENV_PREFIX))
+            .load()
+            .unwrap()
+    }
+
+    /// Addresses from the environment are parsed into the address field
+    #[test]
+    fn self_address_from_env() {
+        let _var = EnvVarGuard::set(format!("{ENV_PREFIX}SELF_ADDRESS"), "1.2.3.4");
+        assert_eq!(load_env().self_address, Ipv4Addr::new(1, 2, 3, 4));
+    }
+
+    /// Numbers from the environment are parsed into number fields
+    #[test]
+    fn udp_port_from_env() {
+        let _var = EnvVarGuard::set(format!("{ENV_PREFIX}UDP_PORT_1"), "17777");
+        assert_eq!(load_env().udp_port_1, 17777);
+    }
+
+    /// Number and boolean like values are kept as strings for string
+    /// fields while other fields still parse them as JSON
+    #[test]
+    fn env_values_coerced_to_field_types() {
+        let prefix = "QOS_TEST_COERCE_";
+        std::env::set_var(format!("{prefix}PROBE_SIGNING_KEY"), "123456");
+        std::env::set_var(format!("{prefix}REGION"), "1");
+        std::env::set_var(format!("{prefix}SERVER_NAME"), "true");
+        std::env::set_var(format!("{prefix}HTTP_PORT"), "8080");
+        std::env::set_var(format!("{prefix}PROBE_SIGNING"), "true");
+        std::env::set_var(format!("{prefix}IP_BLOCKLIST"), r#"["10.0.0.1"]"#);
+
+        let config = ConfigLoader::default()
+            .source(DefaultsSource)
+            .source(EnvSource::new(prefix))
+            .load()
+            .unwrap();
+
+        assert_eq!(config.probe_signing_key, "123456");
+        assert_eq!(config.region.as_deref(), Some("1"));
+        assert_eq!(config.server_name.as_deref(), Some("true"));
+        assert_eq!(config.http_port, 8080);
+        assert!(config.probe_signing);
+        assert_eq!(config.ip_blocklist, [Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    /// Values from the config file are overridden by the environment
+    /// variables, which are overridden by the command line
+    #[test]
+    fn sources_take_precedence_in_order() {
+        let path =
+            std::env::temp_dir().join(format!("qos-precedence-test-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"http_port": 1000, "udp_port_1": 2000, "udp_port_2": 3000}"#,
+        )
+        .unwrap();
+
+        let prefix = "QOS_TEST_PRECEDENCE_";
+        std::env::set_var(format!("{prefix}UDP_PORT_1"), "2001");
+        std::env::set_var(format!("{prefix}UDP_PORT_2"), "3001");
+
+        let config = ConfigLoader::default()
+            .source(DefaultsSource)
+            .source(FileSource::new(&path))
+            .source(EnvSource::new(prefix))
+            .source(CliSource::new(&["udp_port_2=3002".to_string()]))
+            .load()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.http_port, 1000);
+        assert_eq!(config.udp_port_1, 2001);
+        assert_eq!(config.udp_port_2, 3002);
+        // Fields no source sets keep their defaults
+        assert_eq!(config.udp_poll_timeout_ms, 100);
+    }
+
+    /// Command line overrides must name the field they set
+    #[test]
+    fn cli_override_without_field_fails() {
+        let result = ConfigLoader::default()
+            .source(DefaultsSource)
+            .source(CliSource::new(&["8080".to_string()]))
+            .load();
+        assert!(result.is_err());
+    }
+}
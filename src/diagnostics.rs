@@ -0,0 +1,198 @@
+//! Startup diagnostics for common deployment mistakes.
+//!
+//! The UDP reachability check sends a v1 QoS probe to the advertised address
+//! and port of the server and waits for the response. A missing response
+//! usually means the UDP port isn't forwarded to the server, although
+//! routers without NAT hairpinning will also fail the check.
+//!
+//! [wait_for_port] is used by orchestration scripts through the
+//! `--wait-for-port` argument to wait until the server is up
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::{self, Instant},
+};
+
+use crate::{config::Config, udp::QosHeader};
+
+/// Delay before checking to give the QoS server time to start
+const CHECK_DELAY: Duration = Duration::from_secs(1);
+/// Time to wait for the probe response
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Delay before the first retry when waiting for a port, doubled after
+/// each failed attempt up to [WAIT_MAX_BACKOFF]
+const WAIT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+/// Maximum delay between attempts when waiting for a port
+const WAIT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Error returned when a port didn't start accepting connections in time
+#[derive(Debug, Error)]
+#[error("port {port} didn't accept connections within {timeout:?}: {last_error}")]
+pub struct WaitError {
+    /// Port that was waited on
+    pub port: u16,
+    /// Time that was waited for
+    pub timeout: Duration,
+    /// Error from the last connection attempt
+    pub last_error: io::Error,
+}
+
+/// Checks whether the QoS server can be reached through the advertised
+/// address and port, logging a warning when it can't
+pub async fn check_udp_reachability(config: Arc<Config>) {
+    time::sleep(CHECK_DELAY).await;
+
+    let target = SocketAddrV4::new(config.self_address, config.udp_port_1);
+    debug!("Checking UDP reachability of {}", target);
+
+    match send_probe(target, CHECK_TIMEOUT).await {
+        Ok(true) => info!("QoS server is reachable on {}", target),
+        Ok(false) => warn!(
+            "No response from the QoS server on {}, check that UDP port {} is forwarded to this server",
+            target, config.udp_port_1
+        ),
+        Err(err) => warn!("Failed to check UDP reachability of {}: {}", target, err),
+    }
+}
+
+/// Sends a v1 QoS probe to the `target` returning whether a response
+/// was received within the `timeout`
+async fn send_probe(target: SocketAddrV4, timeout: Duration) -> std::io::Result<bool> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+    let mut probe = BytesMut::new();
+    QosHeader {
+        u1: 0,
+        request_id: 1,
+        request_secret: 0,
+        probe_number: 0,
+    }
+    .write(&mut probe);
+    // Timestamp of the probe
+    probe.extend_from_slice(&[0; 4]);
+
+    socket.send_to(&probe, target).await?;
+
+    let mut buffer = [0u8; 64];
+    loop {
+        let (_, addr) = match time::timeout(timeout, socket.recv_from(&mut buffer)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(false),
+        };
+
+        // Ignore anything that didn't come from the server
+        if addr.port() == target.port() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Waits until the local TCP `port` accepts connections, retrying with
+/// exponential backoff until the `timeout` has elapsed
+pub async fn wait_for_port(port: u16, timeout: Duration) -> Result<(), WaitError> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = WAIT_INITIAL_BACKOFF;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let attempt = time::timeout(remaining, TcpStream::connect((Ipv4Addr::LOCALHOST, port)));
+
+        let last_error = match attempt.await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(err)) => err,
+            Err(_) => io::ErrorKind::TimedOut.into(),
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(WaitError {
+                port,
+                timeout,
+                last_error,
+            });
+        }
+
+        time::sleep(backoff.min(remaining)).await;
+        backoff = (backoff * 2).min(WAIT_MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::send_probe;
+    use crate::udp::{ByteOrder, QosHeader, QOS_HEADER_SIZE};
+
+    /// Time to wait for the mock echo in the tests
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Binds a mock QoS server on a loopback port
+    async fn mock_server() -> (UdpSocket, SocketAddrV4) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = match socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        (socket, addr)
+    }
+
+    /// A server echoing the probe back is detected as reachable
+    #[tokio::test]
+    async fn reachable_with_echo() {
+        let (server, target) = mock_server().await;
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            let (length, addr) = server.recv_from(&mut buffer).await.unwrap();
+            server.send_to(&buffer[..length], addr).await.unwrap();
+            buffer[..length].to_vec()
+        });
+
+        assert!(send_probe(target, TIMEOUT).await.unwrap());
+
+        // The probe is a v1 address query
+        let probe = echo.await.unwrap();
+        assert_eq!(probe.len(), QOS_HEADER_SIZE + 4);
+        let header = QosHeader::from_slice(&probe, ByteOrder::Big).unwrap();
+        assert!(header.is_address_query());
+    }
+
+    /// A server that never responds is detected as unreachable
+    #[tokio::test]
+    async fn unreachable_without_echo() {
+        let (_server, target) = mock_server().await;
+        assert!(!send_probe(target, TIMEOUT).await.unwrap());
+    }
+
+    /// Responses from anywhere other than the server port are ignored
+    #[tokio::test]
+    async fn response_from_other_port_ignored() {
+        let (server, target) = mock_server().await;
+        let other = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            let (length, addr) = server.recv_from(&mut buffer).await.unwrap();
+            other.send_to(&buffer[..length], addr).await.unwrap();
+            // Keep the server bound until the probe times out
+            tokio::time::sleep(TIMEOUT * 2).await;
+        });
+
+        assert!(!send_probe(target, TIMEOUT).await.unwrap());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::http::{HeaderName, Request, Response};
+use log::error;
+use tower::{Layer, Service};
+
+/// Layer that strips a set of headers from every HTTP response so
+/// that implementation details aren't exposed to clients
+#[derive(Clone)]
+pub struct EgressFilterLayer {
+    /// The headers to remove
+    headers: Arc<[HeaderName]>,
+}
+
+impl EgressFilterLayer {
+    /// Creates a new layer stripping the provided `headers`, any
+    /// invalid header names are logged and ignored
+    pub fn new(headers: &[String]) -> Self {
+        let headers = headers
+            .iter()
+            .filter_map(|value| match HeaderName::try_from(value.as_str()) {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    error!("Invalid header name in strip_headers: {}", value);
+                    None
+                }
+            })
+            .collect();
+
+        Self { headers }
+    }
+}
+
+impl<S> Layer<S> for EgressFilterLayer {
+    type Service = EgressFilter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EgressFilter {
+            inner,
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+/// Service created by [EgressFilterLayer]
+#[derive(Clone)]
+pub struct EgressFilter<S> {
+    /// The wrapped service
+    inner: S,
+    /// The headers to remove
+    headers: Arc<[HeaderName]>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for EgressFilter<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let future = self.inner.call(req);
+        let headers = self.headers.clone();
+
+        Box::pin(async move {
+            let mut response = future.await?;
+            let response_headers = response.headers_mut();
+            for header in headers.iter() {
+                response_headers.remove(header);
+            }
+            Ok(response)
+        })
+    }
+}
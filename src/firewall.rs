@@ -1,32 +1,311 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
+    time::Duration,
 };
 
 use bytes::{Buf, BytesMut};
-use log::{debug, error, info};
-use tokio::net::UdpSocket;
+use log::{debug, error, info, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time};
 
-use crate::{config::Config, service::QService};
+use crate::{
+    blocklist::BlocklistChecker,
+    config::Config,
+    metrics::{self, QosTimer},
+    probe_channel::SendError,
+    service::QService,
+    shutdown,
+    swap::PortSwapDetector,
+    udp::{apply_no_fragment, QOS_HEADER_SIZE},
+    warmup,
+};
 
-pub async fn start_server(service: Arc<QService>, config: Arc<Config>) {
-    // Socket for handling connections
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.udp_port_2))
-        .await
-        .unwrap();
-    info!("Starting FireWall server on 0.0.0.0:{}", config.udp_port_2);
+/// Size of a firewall message (request id and secret)
+pub const FIREWALL_MESSAGE_SIZE: usize = 8;
+
+/// Detector for QoS messages being sent to the firewall server
+static PORT_SWAP_DETECTOR: PortSwapDetector = PortSwapDetector::new();
+
+/// Runs the firewall server until shutdown, fails if the sockets couldn't be bound
+pub async fn start_server(
+    service: Arc<QService>,
+    config: Arc<Config>,
+    blocklist: Arc<BlocklistChecker>,
+) -> std::io::Result<()> {
+    // With SO_REUSEPORT multiple sockets are bound to the same port and the
+    // kernel distributes incoming messages between them
+    let workers = if config.firewall_udp_reuseport && cfg!(unix) {
+        std::thread::available_parallelism()
+            .map(|value| value.get())
+            .unwrap_or(1)
+    } else {
+        if config.firewall_udp_reuseport {
+            warn!("SO_REUSEPORT is not supported on this platform, using a single firewall socket");
+        }
+        1
+    };
+
+    info!(
+        "Starting FireWall server on 0.0.0.0:{} ({} sockets)",
+        config.udp_port_2, workers
+    );
+
+    // Every socket is bound up front so a bind failure stops the server
+    // before any messages are received
+    let sockets = (0..workers)
+        .map(|_| bind_socket(config.udp_port_2, workers > 1))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    // Firewall messages are never responded to so each socket can
+    // be given its own receive-only task
+    let tasks: Vec<_> = sockets
+        .into_iter()
+        .map(|socket| {
+            if config.udp_no_fragment {
+                apply_no_fragment(&socket);
+            }
+            tokio::spawn(receive(
+                service.clone(),
+                config.clone(),
+                blocklist.clone(),
+                socket,
+            ))
+        })
+        .collect();
+
+    for task in tasks {
+        _ = task.await;
+    }
+
+    Ok(())
+}
+
+/// Binds a firewall socket on the provided `port` optionally
+/// allowing the port to be shared through SO_REUSEPORT
+fn bind_socket(port: u16, reuse_port: bool) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Receives firewall messages from the provided `socket` until
+/// the server is shutting down
+async fn receive(
+    service: Arc<QService>,
+    config: Arc<Config>,
+    blocklist: Arc<BlocklistChecker>,
+    socket: UdpSocket,
+) {
+    // Shared with the handlers for acknowledging probes
     let socket = Arc::new(socket);
 
     // Buffer for the packet header
     let mut buffer = [0u8; 65536 /* UDP allocated buffer size */];
 
+    // Time to wait for a message before checking for shutdown
+    let poll_timeout = Duration::from_millis(config.udp_poll_timeout_ms);
+
     loop {
+        let shutting_down = shutdown::is_shutting_down();
+
+        // Stop once shutting down and any in-flight handlers have finished
+        if shutting_down && (!config.shutdown_drain || shutdown::in_flight() == 0) {
+            info!("Stopping FireWall server");
+            break;
+        }
+
         // Read bytes from the socket
-        let (length, addr) = socket.recv_from(&mut buffer).await.unwrap();
+        let (length, addr) = match time::timeout(poll_timeout, socket.recv_from(&mut buffer)).await
+        {
+            Ok(Ok(value)) => value,
+            // Errors are transient so the server keeps receiving
+            Ok(Err(err)) => {
+                error!("Failed to receive firewall message: {}", err);
+                continue;
+            }
+            // Timed out, check for shutdown and try again
+            Err(_) => continue,
+        };
+
+        if config.log_udp_packets {
+            trace!(
+                "UDP packet from {}: {}",
+                addr,
+                hex::encode(&buffer[..length])
+            );
+        }
+
+        // Drop new messages while draining in-flight handlers
+        if shutting_down {
+            metrics::SHUTDOWN_DROPPED.inc();
+            continue;
+        }
+
+        // Ignore messages from blocked addresses
+        if blocklist.is_blocked(addr.ip()) {
+            debug!("Ignoring Firewall message from blocked address: {}", addr);
+            continue;
+        }
 
         // Copy the request bytes from the buffer
         let buffer: BytesMut = BytesMut::from(&buffer[..length]);
-        tokio::spawn(handle(service.clone(), socket.clone(), addr, buffer));
+        tokio::spawn(shutdown::tracked(handle(
+            service.clone(),
+            config.clone(),
+            socket.clone(),
+            addr,
+            buffer,
+        )));
+    }
+}
+
+/// Fire type for clients that aren't behind a NAT
+pub const FIRE_TYPE_OPEN: u32 = 0;
+/// Fire type for clients behind a NAT that maps all destinations
+/// through the same external port
+pub const FIRE_TYPE_MODERATE: u32 = 1;
+/// Fire type for clients behind a NAT that allocates sequential
+/// external ports for each destination
+pub const FIRE_TYPE_STRICT_SEQUENTIAL: u32 = 2;
+/// Fire type for clients behind a NAT that allocates unpredictable
+/// external ports for each destination
+pub const FIRE_TYPE_STRICT: u32 = 3;
+
+/// Kind of address reported by a client as its internal address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InternalAddressKind {
+    /// RFC1918 private or RFC6598 shared (CGNAT) address
+    Private,
+    /// Loopback address
+    Loopback,
+    /// Link local address
+    LinkLocal,
+    /// Unspecified address (0.0.0.0)
+    Unspecified,
+    /// Publicly routable address
+    Public,
+}
+
+impl InternalAddressKind {
+    /// Determines the kind of the provided address
+    pub fn of(addr: Ipv4Addr) -> Self {
+        let octets = addr.octets();
+        if addr.is_unspecified() {
+            Self::Unspecified
+        } else if addr.is_loopback() {
+            Self::Loopback
+        } else if addr.is_link_local() {
+            Self::LinkLocal
+        } else if addr.is_private() || (octets[0] == 100 && (octets[1] & 0xC0) == 64) {
+            Self::Private
+        } else {
+            Self::Public
+        }
+    }
+
+    /// Whether this is a plausible address for a client to report
+    /// as its internal address
+    pub fn is_plausible(&self) -> bool {
+        !matches!(self, Self::Unspecified | Self::Public)
+    }
+}
+
+/// Algorithm for classifying the NAT type of a client by comparing its
+/// reported `internal` address against the external addresses observed
+/// from its firewall `probes`. Callers must provide at least one probe
+pub trait FirewallClassifier: Send + Sync {
+    fn classify(&self, internal: SocketAddrV4, probes: &[SocketAddr]) -> u32;
+}
+
+/// Name of the classifier used when the configured one is unknown
+pub const DEFAULT_CLASSIFIER: &str = "rfc3489";
+
+/// Creates the classifier with the provided `name`
+pub fn classifier_from_name(name: &str) -> Option<Box<dyn FirewallClassifier>> {
+    let classifier: Box<dyn FirewallClassifier> = match name {
+        "open" => Box::new(OpenClassifier),
+        "symmetric" => Box::new(SymmetricClassifier),
+        "rfc3489" => Box::new(RFC3489Classifier),
+        _ => return None,
+    };
+    Some(classifier)
+}
+
+/// Whether any of the `probes` came from the `internal` address
+/// meaning there's no translation between the client and server
+fn is_untranslated(internal: SocketAddrV4, probes: &[SocketAddr]) -> bool {
+    probes.contains(&SocketAddr::V4(internal))
+}
+
+/// Whether all the `probes` used the same external port
+fn is_same_mapping(probes: &[SocketAddr]) -> bool {
+    probes
+        .windows(2)
+        .all(|pair| pair[0].port() == pair[1].port())
+}
+
+/// Classifier reporting every client as open, for deployments where
+/// clients are known to be directly reachable
+pub struct OpenClassifier;
+
+impl FirewallClassifier for OpenClassifier {
+    fn classify(&self, _internal: SocketAddrV4, _probes: &[SocketAddr]) -> u32 {
+        FIRE_TYPE_OPEN
+    }
+}
+
+/// Classifier only distinguishing between cone NATs that reuse the same
+/// mapping and symmetric NATs that don't
+pub struct SymmetricClassifier;
+
+impl FirewallClassifier for SymmetricClassifier {
+    fn classify(&self, internal: SocketAddrV4, probes: &[SocketAddr]) -> u32 {
+        if is_untranslated(internal, probes) {
+            FIRE_TYPE_OPEN
+        } else if is_same_mapping(probes) {
+            FIRE_TYPE_MODERATE
+        } else {
+            FIRE_TYPE_STRICT
+        }
+    }
+}
+
+/// Classifier based on the RFC 3489 NAT types which additionally detects
+/// symmetric NATs that allocate their external ports sequentially
+pub struct RFC3489Classifier;
+
+impl FirewallClassifier for RFC3489Classifier {
+    fn classify(&self, internal: SocketAddrV4, probes: &[SocketAddr]) -> u32 {
+        // Client reported its real address so there's no translation
+        if is_untranslated(internal, probes) {
+            return FIRE_TYPE_OPEN;
+        }
+
+        // Same external mapping used for every destination
+        if is_same_mapping(probes) {
+            return FIRE_TYPE_MODERATE;
+        }
+
+        // Differing mappings that increase predictably
+        let sequential = probes
+            .windows(2)
+            .all(|pair| pair[1].port().wrapping_sub(pair[0].port()) <= 1);
+
+        if sequential {
+            FIRE_TYPE_STRICT_SEQUENTIAL
+        } else {
+            FIRE_TYPE_STRICT
+        }
     }
 }
 
@@ -57,13 +336,58 @@ impl FirewallRequest {
 
 async fn handle(
     service: Arc<QService>,
-    // We don't use the socket for responding
-    _socket: Arc<UdpSocket>,
+    config: Arc<Config>,
+    socket: Arc<UdpSocket>,
     addr: SocketAddr,
     mut buffer: BytesMut,
 ) {
+    // Messages received while warming up are held until it completes
+    if !warmup::is_ready() {
+        warmup::wait_ready().await;
+    }
+
+    let _timer = QosTimer::new("firewall");
+
+    // Empty datagrams are common from scanners so they're handled quietly
+    if buffer.is_empty() {
+        metrics::EMPTY_DATAGRAMS.inc();
+        if config.log_empty_datagrams {
+            debug!("Ignoring empty firewall datagram from {}", addr);
+        }
+        return;
+    }
+
+    if config.maintenance_mode {
+        debug!("Dropping firewall message from {} during maintenance", addr);
+        return;
+    }
+
+    // QoS messages are at least a header and start with a small u1 value
+    // whereas firewall messages start with the request ID
+    if buffer.len() >= QOS_HEADER_SIZE && buffer[..3] == [0, 0, 0] {
+        if PORT_SWAP_DETECTOR.record_mismatch() {
+            warn!(
+                "Firewall server on port {} keeps receiving QoS shaped messages, \
+                 the udp_port_1 and udp_port_2 config values may be swapped",
+                config.udp_port_2
+            );
+        }
+    } else if buffer.len() == FIREWALL_MESSAGE_SIZE {
+        PORT_SWAP_DETECTOR.record_match();
+    }
+
+    // Probes are only ever a few bytes, anything larger isn't a real client
+    if buffer.len() > config.firewall_probe_max_size {
+        warn!(
+            "Discarding suspicious oversized firewall packet of {} bytes from {}",
+            buffer.len(),
+            addr
+        );
+        return;
+    }
+
     // Ignore messages that are too short
-    if buffer.len() < 8 {
+    if buffer.len() < FIREWALL_MESSAGE_SIZE {
         error!(
             "Client didn't send a firewall message long enough to be a message: {:?}",
             buffer.as_ref()
@@ -73,12 +397,130 @@ async fn handle(
 
     let message = FirewallRequest::from_buffer(&mut buffer);
 
-    let rx = service
+    let tx = match service
         .get_firewall_tx(message.request_id, message.request_secret)
         .await
-        .expect("Missing request data for request");
+    {
+        Some(value) => value,
+        None => {
+            debug!(
+                "Firewall message for unknown session: {:?} ADDR: {}",
+                message, addr
+            );
+            return;
+        }
+    };
 
     debug!("Firewall Query: MSG: {:?}  ADDR: {}", message, addr);
 
-    _ = rx.send(addr);
+    // Only probes for known sessions are acknowledged
+    if config.firewall_probe_ack {
+        if let Err(err) = socket.send_to(&config.firewall_ack_payload, addr).await {
+            warn!(
+                "Failed to acknowledge firewall probe from {}: {}",
+                addr, err
+            );
+        }
+    }
+
+    match tx.send(addr).await {
+        Ok(_) => {}
+        // The receiver is dropped once the firetype request has completed
+        Err(SendError::Closed) => info!(
+            "Late firewall probe after firetype completed: MSG: {:?} ADDR: {}",
+            message, addr
+        ),
+        Err(SendError::Full) => debug!(
+            "Dropped firewall probe for full channel: MSG: {:?} ADDR: {}",
+            message, addr
+        ),
+        Err(SendError::BufferLimit) => debug!(
+            "Dropped firewall probe over the buffered bytes limit: MSG: {:?} ADDR: {}",
+            message, addr
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    use super::{
+        classifier_from_name, InternalAddressKind, FIRE_TYPE_MODERATE, FIRE_TYPE_OPEN,
+        FIRE_TYPE_STRICT, FIRE_TYPE_STRICT_SEQUENTIAL,
+    };
+
+    /// Reported internal addresses are classified by their range
+    #[test]
+    fn internal_address_kinds() {
+        let cases = [
+            ([192, 168, 1, 20], InternalAddressKind::Private, true),
+            ([10, 0, 0, 5], InternalAddressKind::Private, true),
+            ([172, 16, 4, 1], InternalAddressKind::Private, true),
+            ([100, 64, 0, 1], InternalAddressKind::Private, true),
+            ([100, 128, 0, 1], InternalAddressKind::Public, false),
+            ([127, 0, 0, 1], InternalAddressKind::Loopback, true),
+            ([169, 254, 10, 1], InternalAddressKind::LinkLocal, true),
+            ([0, 0, 0, 0], InternalAddressKind::Unspecified, false),
+            ([203, 0, 113, 7], InternalAddressKind::Public, false),
+        ];
+
+        for (octets, kind, plausible) in cases {
+            let addr = Ipv4Addr::from(octets);
+            assert_eq!(InternalAddressKind::of(addr), kind, "{}", addr);
+            assert_eq!(kind.is_plausible(), plausible, "{:?}", kind);
+        }
+    }
+
+    /// The client's internal address is compared against the external
+    /// addresses of its probes for each classifier
+    #[test]
+    fn classify_internal_against_external() {
+        let internal = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 3659);
+        let external = |port: u16| SocketAddr::from(([203, 0, 113, 7], port));
+
+        // Public client reporting the address its probes came from
+        let direct = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 3659);
+        let untranslated = [SocketAddr::V4(direct), SocketAddr::V4(direct)];
+        // Same external mapping for both destinations
+        let same = [external(50000), external(50000)];
+        // Mapping increasing for each destination
+        let sequential = [external(50000), external(50001)];
+        // Unpredictable mapping for each destination
+        let random = [external(50000), external(61234)];
+
+        let cases = [
+            ("open", direct, &untranslated, FIRE_TYPE_OPEN),
+            ("open", internal, &random, FIRE_TYPE_OPEN),
+            ("symmetric", direct, &untranslated, FIRE_TYPE_OPEN),
+            ("symmetric", internal, &same, FIRE_TYPE_MODERATE),
+            ("symmetric", internal, &sequential, FIRE_TYPE_STRICT),
+            ("symmetric", internal, &random, FIRE_TYPE_STRICT),
+            ("rfc3489", direct, &untranslated, FIRE_TYPE_OPEN),
+            ("rfc3489", internal, &same, FIRE_TYPE_MODERATE),
+            (
+                "rfc3489",
+                internal,
+                &sequential,
+                FIRE_TYPE_STRICT_SEQUENTIAL,
+            ),
+            ("rfc3489", internal, &random, FIRE_TYPE_STRICT),
+            // Same address but a translated port isn't untranslated
+            ("rfc3489", direct, &same, FIRE_TYPE_MODERATE),
+        ];
+
+        for (name, internal, probes, fire_type) in cases {
+            let classifier = classifier_from_name(name).unwrap();
+            assert_eq!(
+                classifier.classify(internal, probes),
+                fire_type,
+                "{} {} {:?}",
+                name,
+                internal,
+                probes
+            );
+        }
+
+        assert!(classifier_from_name("unknown").is_none());
+    }
 }
@@ -0,0 +1,104 @@
+//! Approximate client regions from a local GeoIP database.
+//!
+//! Regions are purely informational annotations on firewall sessions. The
+//! database is a text file of `cidr,region` lines where the most specific
+//! range containing an address determines its region, blank lines and lines
+//! starting with `#` are ignored. When no database is configured or it
+//! fails to load sessions are simply left without a region
+
+use std::{cmp::Reverse, io, net::Ipv4Addr, path::Path};
+
+use ipnet::Ipv4Net;
+use log::{info, warn};
+use thiserror::Error;
+
+/// Lookup of the approximate region of an address
+pub trait GeoLookup: Send + Sync {
+    /// Looks up the region of the provided `addr`
+    fn region(&self, addr: Ipv4Addr) -> Option<String>;
+}
+
+/// Errors that can occur while loading a GeoIP database
+#[derive(Debug, Error)]
+pub enum GeoDatabaseError {
+    /// The database file couldn't be read
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A line of the database is invalid
+    #[error("invalid entry on line {0}: {1}")]
+    InvalidEntry(usize, String),
+}
+
+/// GeoIP database of CIDR ranges and their regions
+pub struct CidrGeoDatabase {
+    /// Ranges sorted from most to least specific
+    ranges: Vec<(Ipv4Net, String)>,
+}
+
+impl CidrGeoDatabase {
+    /// Loads the database from the file at the provided `path`
+    pub fn load(path: &Path) -> Result<Self, GeoDatabaseError> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// Parses the database from its text `contents`
+    pub fn parse(contents: &str) -> Result<Self, GeoDatabaseError> {
+        let mut ranges = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || GeoDatabaseError::InvalidEntry(index + 1, line.to_string());
+
+            let (range, region) = line.split_once(',').ok_or_else(invalid)?;
+            let range = range
+                .trim()
+                .parse::<Ipv4Net>()
+                .map_err(|_| invalid())?
+                .trunc();
+            ranges.push((range, region.trim().to_string()));
+        }
+
+        ranges.sort_by_key(|(range, _)| Reverse(range.prefix_len()));
+
+        Ok(Self { ranges })
+    }
+}
+
+impl GeoLookup for CidrGeoDatabase {
+    fn region(&self, addr: Ipv4Addr) -> Option<String> {
+        self.ranges
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, region)| region.clone())
+    }
+}
+
+/// Loads the GeoIP database at the provided `path` if one is configured,
+/// logging a warning and continuing without regions if it fails to load
+pub fn load(path: Option<&Path>) -> Option<Box<dyn GeoLookup>> {
+    let path = path?;
+
+    match CidrGeoDatabase::load(path) {
+        Ok(value) => {
+            info!(
+                "Loaded {} GeoIP ranges from {}",
+                value.ranges.len(),
+                path.display()
+            );
+            Some(Box::new(value))
+        }
+        Err(err) => {
+            warn!(
+                "Failed to load GeoIP database {}, continuing without regions: {}",
+                path.display(),
+                err
+            );
+            None
+        }
+    }
+}
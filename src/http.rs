@@ -1,40 +1,72 @@
 use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
     future::Future,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
-use axum::{extract::Query, routing::get, Extension, Router, Server};
+use axum::{
+    body::StreamBody,
+    error_handling::HandleErrorLayer,
+    extract::{ConnectInfo, Path, Query, State},
+    headers::UserAgent,
+    http::{header, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    BoxError, Extension, Json, Router, Server, TypedHeader,
+};
 use axum_xml_up::Xml;
-use log::{debug, error, info};
+use hyper::server::{conn::AddrIncoming, Builder};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use tokio::signal;
-use tower_http::trace::{DefaultMakeSpan, TraceLayer};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::error::Overloaded, ServiceBuilder};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::CompressionLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultMakeSpan, TraceLayer},
+};
 
-use crate::{config::Config, service::QService};
+use crate::{
+    blocklist::{AddressMatcher, BlocklistChecker},
+    config::Config,
+    egress::EgressFilterLayer,
+    firewall::{classifier_from_name, FirewallClassifier, InternalAddressKind, DEFAULT_CLASSIFIER},
+    metrics,
+    service::{
+        CreateSessionError, IdAllocation, ProbeSessionState, QFirewallInfo, QRequestData, QService,
+        CSV_HEADER,
+    },
+    session_event_log::{self, ProbeArrivalRecord},
+    signing,
+    validate::{invalid_session_response, QosQuery, SessionParamsLayer},
+    warmup,
+    webhook::{self, FlowCompletion},
+};
 
-pub async fn start_server(service: Arc<QService>, config: Arc<Config>) {
+pub async fn start_server(
+    service: Arc<QService>,
+    config: Arc<Config>,
+    blocklist: Arc<BlocklistChecker>,
+) {
     // Create the server socket address while the port is still available
     let addr: SocketAddr = (Ipv4Addr::UNSPECIFIED, config.http_port).into();
 
-    let router = Router::new()
-        .nest(
-            "/qos",
-            Router::new()
-                .route("/qos", get(qos))
-                .route("/firewall", get(firewall))
-                .route("/firetype", get(firetype)),
-        )
-        .layer(Extension(service))
-        .layer(Extension(config))
-        .layer(
-            TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().include_headers(true)),
-        );
+    let router = router(service, config.clone(), blocklist);
 
     info!("Starting HTTP server on {}", addr);
 
-    if let Err(err) = Server::bind(&addr)
+    if let Err(err) = bind(&addr, &config)
         .serve(router.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(async move {
             _ = signal::ctrl_c().await;
@@ -45,6 +77,228 @@ pub async fn start_server(service: Arc<QService>, config: Arc<Config>) {
     }
 }
 
+/// Binds the HTTP server to the `addr`. Requests with headers larger than
+/// the configured max header size are responded to with 431 Request Header
+/// Fields Too Large
+fn bind(addr: &SocketAddr, config: &Config) -> Builder<AddrIncoming> {
+    Server::bind(addr).http1_max_buf_size(config.http_max_header_size)
+}
+
+/// Creates the router for the HTTP server with the routes
+/// and layers enabled by the `config`
+fn router(service: Arc<QService>, config: Arc<Config>, blocklist: Arc<BlocklistChecker>) -> Router {
+    // Routes only accessible from the admin allowlist
+    let mut admin_router = Router::new();
+
+    if config.debug_endpoints {
+        admin_router = admin_router
+            .route(
+                "/debug/flow/:id/:secret",
+                get(debug_flow).delete(debug_remove_flow),
+            )
+            .route(
+                "/debug/firewall-probes/:id/:secret",
+                get(debug_firewall_probes),
+            )
+            .route("/debug/probe-log/:id", get(debug_probe_log))
+            .route("/debug/id-allocations", get(debug_id_allocations))
+            .route("/debug/metrics/reset", post(reset_metrics))
+            .route("/debug/sessions/batch", post(debug_batch_sessions));
+    }
+
+    if config.admin_endpoints {
+        admin_router = admin_router
+            .route("/metrics", get(get_metrics))
+            .route("/sessions", get(export_sessions_json))
+            .route("/sessions/export.csv", get(export_sessions_csv))
+            .route("/sessions/influxdb", post(export_sessions_influxdb))
+            .route("/sessions/expired", get(expired_firewall_sessions))
+            .route("/sessions/:id/:secret/events", get(session_events));
+    }
+
+    // Route layers can only be added once there are routes to apply them to
+    if config.debug_endpoints || config.admin_endpoints {
+        admin_router = admin_router.route_layer(middleware::from_fn_with_state(
+            Arc::new(RequireAdminIp::new(&config)),
+            require_admin_ip,
+        ));
+    }
+
+    let mut qos_router = Router::new()
+        .route("/qos", get(qos))
+        .route("/firewall", get(firewall))
+        .route("/firetype", get(firetype).route_layer(SessionParamsLayer))
+        .route("/latency-report/:id/:secret", get(latency_report))
+        .route("/version", get(version))
+        .route("/health", get(health))
+        .route("/ready", get(ready));
+
+    // Admin routes are still served during maintenance
+    if config.maintenance_mode {
+        qos_router = qos_router.route_layer(middleware::from_fn(maintenance));
+    }
+
+    let qos_router = qos_router.merge(admin_router);
+
+    let classifier: Arc<dyn FirewallClassifier> = match classifier_from_name(&config.classifier) {
+        Some(value) => value.into(),
+        None => {
+            error!(
+                "Unknown firewall classifier {}, using {}",
+                config.classifier, DEFAULT_CLASSIFIER
+            );
+            classifier_from_name(DEFAULT_CLASSIFIER)
+                .expect("Default classifier missing")
+                .into()
+        }
+    };
+
+    let mut router = Router::new()
+        .nest("/qos", qos_router)
+        .layer(Extension(classifier))
+        .layer(middleware::from_fn_with_state(blocklist, block_ip))
+        .layer(Extension(service))
+        .layer(Extension(config.clone()))
+        .layer(
+            TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().include_headers(true)),
+        );
+
+    // Panics are isolated to the request that caused them
+    let fallback_xml = config.http_fallback_xml.clone();
+    router = router.layer(CatchPanicLayer::custom(move |_| {
+        panic_response(&fallback_xml)
+    }));
+
+    // Requests beyond the limit are shed rather than queued, the limit is
+    // shared as the layer is applied to each route individually
+    router = router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_overloaded))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(
+                config.http_concurrency_limit,
+            )),
+    );
+
+    if !config.strip_headers.is_empty() {
+        router = router.layer(EgressFilterLayer::new(&config.strip_headers));
+    }
+
+    if config.http_compression {
+        router = router.layer(CompressionLayer::new());
+    }
+
+    if let (true, Some(region)) = (config.region_header, &config.region) {
+        match HeaderValue::from_str(region) {
+            Ok(value) => {
+                router = router.layer(SetResponseHeaderLayer::overriding(
+                    HeaderName::from_static("x-qos-region"),
+                    value,
+                ));
+            }
+            Err(_) => error!("Region is not a valid header value: {}", region),
+        }
+    }
+
+    if config.server_header {
+        let server_name = config.resolve_server_name();
+        match HeaderValue::from_str(&server_name) {
+            Ok(value) => {
+                router = router.layer(SetResponseHeaderLayer::overriding(
+                    HeaderName::from_static("x-qos-server"),
+                    value,
+                ));
+            }
+            Err(_) => error!("Server name is not a valid header value: {}", server_name),
+        }
+    }
+
+    router
+}
+
+/// Responds to requests whose handler panicked with the fallback
+/// XML, the panic itself is logged by the panic hook
+fn panic_response(fallback_xml: &str) -> Response {
+    error!("HTTP handler panicked, responding with an internal server error");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        [(header::CONTENT_TYPE, "application/xml")],
+        fallback_xml.to_string(),
+    )
+        .into_response()
+}
+
+/// Handles requests rejected by the concurrency limit
+async fn handle_overloaded(err: BoxError) -> StatusCode {
+    if err.is::<Overloaded>() {
+        debug!("Rejecting HTTP request, concurrency limit reached");
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        error!("Unhandled HTTP middleware error: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Smallest max header size accepted by hyper
+pub const MIN_HTTP_MAX_HEADER_SIZE: usize = 8192;
+
+/// Middleware rejecting requests from addresses on the blocklist
+async fn block_ip<B>(
+    State(blocklist): State<Arc<BlocklistChecker>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if blocklist.is_blocked(addr.ip()) {
+        debug!("Rejecting HTTP request from blocked address: {}", addr);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Middleware responding to all clients that the server is unavailable
+/// while maintenance mode is enabled
+async fn maintenance<B>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    _req: Request<B>,
+    _next: Next<B>,
+) -> Response {
+    debug!("Rejecting HTTP request from {} during maintenance", addr);
+    StatusCode::SERVICE_UNAVAILABLE.into_response()
+}
+
+/// State for the admin middleware, restricts access to the
+/// addresses within the configured admin allowlist
+pub struct RequireAdminIp {
+    /// The allowed addresses
+    allowed: AddressMatcher,
+}
+
+impl RequireAdminIp {
+    /// Creates the admin allowlist from the provided `config`
+    pub fn new(config: &Config) -> Self {
+        Self {
+            allowed: AddressMatcher::new(&config.admin_allowlist, &config.admin_allowlist_cidr),
+        }
+    }
+}
+
+/// Middleware rejecting admin requests from addresses outside the allowlist
+async fn require_admin_ip<B>(
+    State(admin): State<Arc<RequireAdminIp>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !admin.allowed.contains(addr.ip()) {
+        debug!("Rejecting admin request from address: {}", addr);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(req).await
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename = "qos")]
 pub struct QResponse {
@@ -60,6 +314,78 @@ pub struct QResponse {
     pub request_id: u32,
     #[serde(rename = "reqsecret")]
     pub request_secret: u32,
+    /// Session token for signing probes when probe signing is enabled
+    #[serde(rename = "token", skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Variant of the QoS response sent to a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseVariant {
+    /// The response including any elements added by server extensions
+    #[default]
+    Standard,
+    /// The [LegacyQResponse] shape for older clients, probes from these
+    /// clients can't be signed as the session token is left out
+    Legacy,
+}
+
+/// QoS response shape expected by legacy clients, these clients parse the
+/// address in its dotted form and reject unknown elements
+#[derive(Debug, Serialize)]
+#[serde(rename = "qos")]
+pub struct LegacyQResponse {
+    #[serde(rename = "numprobes")]
+    pub num_probes: u32,
+    #[serde(rename = "qosport")]
+    pub qos_port: u16,
+    #[serde(rename = "probesize")]
+    pub probe_size: u32,
+    /// Address of the QoS server in dotted form
+    #[serde(rename = "qosip")]
+    pub qos_ip: String,
+    #[serde(rename = "requestid")]
+    pub request_id: u32,
+    #[serde(rename = "reqsecret")]
+    pub request_secret: u32,
+}
+
+impl From<QResponse> for LegacyQResponse {
+    fn from(value: QResponse) -> Self {
+        Self {
+            num_probes: value.num_probes,
+            qos_port: value.qos_port,
+            probe_size: value.probe_size,
+            qos_ip: Ipv4Addr::from(value.qos_ip).to_string(),
+            request_id: value.request_id,
+            request_secret: value.request_secret,
+        }
+    }
+}
+
+/// Response variant selected for clients with a matching User-Agent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserAgentVariant {
+    /// Case-insensitive pattern contained in the User-Agent
+    pub pattern: String,
+    /// Variant of the response to send
+    pub variant: ResponseVariant,
+}
+
+/// Selects the response variant for the provided `user_agent`
+fn response_variant(config: &Config, user_agent: Option<&str>) -> ResponseVariant {
+    let Some(user_agent) = user_agent else {
+        return ResponseVariant::Standard;
+    };
+    let user_agent = user_agent.to_lowercase();
+
+    config
+        .user_agent_variants
+        .iter()
+        .find(|value| user_agent.contains(&value.pattern.to_lowercase()))
+        .map(|value| value.variant)
+        .unwrap_or_default()
 }
 
 #[derive(Debug, Deserialize)]
@@ -82,66 +408,127 @@ pub const LATENCY_PROBE_COUNT: u32 = 5;
 pub const LATENCY_PROBE_SIZE: u32 = 60;
 
 pub async fn qos(
-    Query(query): Query<QQuery>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    QosQuery(query): QosQuery<QQuery>,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Extension(service): Extension<Arc<QService>>,
     Extension(config): Extension<Arc<Config>>,
-) -> Xml<QResponse> {
+) -> Result<Response, StatusCode> {
+    metrics::REQUESTS_BY_VERSION.inc(query.version);
+
     let qos_ip = u32::from_be_bytes(config.self_address.octets());
     let qos_port = config.udp_port_1;
 
-    let response_fut: Pin<Box<dyn Future<Output = QResponse> + Send>> = match query.qtyp {
+    type ResponseFuture = Pin<Box<dyn Future<Output = Result<QResponse, StatusCode>> + Send>>;
+
+    let q_type = query.qtyp;
+    let response_fut: ResponseFuture = match query.qtyp {
         QOS_TYPE_ADDRESS => Box::pin(qos_address(qos_ip, qos_port)),
-        QOS_TYPE_LATENCY => Box::pin(qos_latency(service, query, qos_ip, qos_port)),
+        QOS_TYPE_LATENCY => Box::pin(qos_latency(service, query, client_addr, qos_ip, qos_port)),
         _ => Box::pin(qos_unknown(query)),
     };
 
-    let response = response_fut.await;
-    Xml(response)
+    let mut response = response_fut.await?;
+
+    // Latency probes must be signed using the session token
+    if config.probe_signing && q_type == QOS_TYPE_LATENCY {
+        let token = signing::session_token(
+            config.probe_signing_key.as_bytes(),
+            response.request_id,
+            response.request_secret,
+        );
+        response.token = Some(hex::encode(token));
+    }
+
+    let user_agent = user_agent.as_ref().map(|value| value.as_str());
+    if response_variant(&config, user_agent) == ResponseVariant::Legacy {
+        debug!("Sending legacy QoS response to {:?}", user_agent);
+        return Ok(Xml(LegacyQResponse::from(response)).into_response());
+    }
+
+    Ok(Xml(response).into_response())
 }
 
-async fn qos_address(qos_ip: u32, qos_port: u16) -> QResponse {
-    QResponse {
+async fn qos_address(qos_ip: u32, qos_port: u16) -> Result<QResponse, StatusCode> {
+    Ok(QResponse {
         num_probes: 0,
         qos_port,
         probe_size: 0,
         qos_ip,
         request_id: 1,
         request_secret: 0,
-    }
+        token: None,
+    })
 }
 
 async fn qos_latency(
     service: Arc<QService>,
     query: QQuery,
+    client_addr: SocketAddr,
     qos_ip: u32,
     qos_port: u16,
-) -> QResponse {
+) -> Result<QResponse, StatusCode> {
     let (request_id, request_secret) = service
-        .create_request_data(query.qtyp, query.port, query.version)
-        .await;
+        .create_request_data(
+            query.qtyp,
+            LATENCY_PROBE_COUNT,
+            query.port,
+            query.version,
+            client_addr,
+        )
+        .await
+        .map_err(create_session_error)?;
 
     debug!("QResponse: {} {}", request_id, request_secret);
 
-    QResponse {
+    Ok(QResponse {
         num_probes: LATENCY_PROBE_COUNT,
         qos_port,
         probe_size: LATENCY_PROBE_SIZE,
         qos_ip,
         request_id,
         request_secret,
-    }
+        token: None,
+    })
 }
 
-async fn qos_unknown(query: QQuery) -> QResponse {
+async fn qos_unknown(query: QQuery) -> Result<QResponse, StatusCode> {
     debug!("Unknown qos type query: {:?}", query);
 
-    QResponse {
+    Ok(QResponse {
         num_probes: 0,
         qos_port: 0,
         probe_size: 0,
         qos_ip: 0,
         request_id: 0,
         request_secret: 0,
+        token: None,
+    })
+}
+
+/// Logs the reason a session couldn't be created and
+/// provides the status code to respond with
+fn create_session_error(err: CreateSessionError) -> StatusCode {
+    match err {
+        CreateSessionError::IdsExhausted => {
+            error!("All session IDs have been allocated, unable to create new sessions");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        CreateSessionError::SecretsExhausted => {
+            error!("Unable to generate a unique session secret within the retry limit");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        CreateSessionError::ClientLimit(owner) => {
+            warn!(
+                "Rejecting new flow from client {} that has too many concurrent flows",
+                owner
+            );
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        CreateSessionError::Store(err) => {
+            error!("Unable to store new session: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
     }
 }
 
@@ -177,25 +564,47 @@ pub struct QFirewallQuery {
 }
 
 pub async fn firewall(
-    Query(query): Query<QFirewallQuery>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    QosQuery(query): QosQuery<QFirewallQuery>,
     Extension(service): Extension<Arc<QService>>,
     Extension(config): Extension<Arc<Config>>,
-) -> Xml<QFirewall> {
+) -> Result<Xml<QFirewall>, StatusCode> {
     debug!("Firewall query: {:?}", query);
+    metrics::REQUESTS_BY_VERSION.inc(query.version);
+
+    let (request_id, request_secret) = service
+        .create_firewall_data(client_addr)
+        .await
+        .map_err(create_session_error)?;
 
-    let (request_id, request_secret) = service.create_firewall_data().await;
+    let mut interfaces = config.firewall_interfaces();
+
+    // Only provide as many interfaces as the client can handle
+    let max_interfaces = query.number_interfaces.max(1) as usize;
+    if interfaces.len() > max_interfaces {
+        debug!(
+            "Clamping firewall interfaces from {} to {} for client {}",
+            interfaces.len(),
+            max_interfaces,
+            client_addr
+        );
+        interfaces.truncate(max_interfaces);
+    }
 
-    Xml(QFirewall {
+    Ok(Xml(QFirewall {
+        num_interfaces: interfaces.len() as u32,
         ips: QFirewallIps {
-            ip: vec![u32::from_be_bytes(config.self_address.octets())],
+            ip: interfaces
+                .iter()
+                .map(|addr| u32::from_be_bytes(addr.octets()))
+                .collect(),
         },
-        num_interfaces: 1,
         ports: QFirewallPorts {
-            ports: vec![config.udp_port_2],
+            ports: vec![config.udp_port_2; interfaces.len()],
         },
         request_id,
         request_secret,
-    })
+    }))
 }
 
 #[derive(Debug, Serialize)]
@@ -220,21 +629,71 @@ pub struct QFireTypeQuery {
 }
 
 pub async fn firetype(
-    Query(query): Query<QFireTypeQuery>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    QosQuery(query): QosQuery<QFireTypeQuery>,
     Extension(service): Extension<Arc<QService>>,
-) -> Xml<QFireType> {
+    Extension(config): Extension<Arc<Config>>,
+    Extension(classifier): Extension<Arc<dyn FirewallClassifier>>,
+) -> Result<Xml<QFireType>, Response> {
     debug!("Firetype query: {:?}", query);
+    metrics::REQUESTS_BY_VERSION.inc(query.version);
 
     let internal_ip = Ipv4Addr::from(query.internal_ip as u32);
     let internal = SocketAddrV4::new(internal_ip, query.internal_port);
     debug!("Fire type internal: {}", internal);
-    let mut rx = service
+
+    let internal_kind = InternalAddressKind::of(internal_ip);
+    if !internal_kind.is_plausible() {
+        warn!(
+            "Client reported an implausible internal address {} ({:?}) for firewall session {} {}",
+            internal, internal_kind, query.request_id, query.request_secret
+        );
+    }
+
+    // A completed session has no probes left that could be waited on, such
+    // as a firewall session whose firetype request already finished and is
+    // only kept around for logging late probes
+    match service
+        .probe_session_state(query.request_id, query.request_secret)
+        .await
+    {
+        ProbeSessionState::NotFound => {}
+        ProbeSessionState::Active {
+            probes_received,
+            age_ms,
+        } => debug!(
+            "Firetype request for active session {} {} ({} probes after {}ms)",
+            query.request_id, query.request_secret, probes_received, age_ms
+        ),
+        ProbeSessionState::Completed {
+            total_probes,
+            duration_ms,
+        } => {
+            debug!(
+                "Firetype request for completed session {} {} ({} probes in {}ms)",
+                query.request_id, query.request_secret, total_probes, duration_ms
+            );
+            return Err(invalid_session_response());
+        }
+    }
+
+    let mut rx = match service
         .take_firewall_rx(query.request_id, query.request_secret)
         .await
-        .expect("Missing firewall rx");
+    {
+        Some(value) => value,
+        None => {
+            debug!(
+                "Firetype request for unknown or already consumed firewall session: {} {}",
+                query.request_id, query.request_secret
+            );
+            return Err(invalid_session_response());
+        }
+    };
     debug!("Firetype got rx handle, waiting for connections..");
 
-    let mut addrs: Vec<SocketAddr> = Vec::with_capacity(5);
+    let max_probes = config.firewall_session_max_probes as usize;
+    let mut addrs: Vec<SocketAddr> = Vec::with_capacity(max_probes);
 
     loop {
         let addr = match rx.recv().await {
@@ -244,11 +703,1150 @@ pub async fn firetype(
         addrs.push(addr);
         debug!("Firetype got connection: {}", addr);
 
-        if addrs.len() >= 5 {
+        // Stop reading once enough probes arrived, any extras are left in the channel
+        if addrs.len() >= max_probes {
             break;
         }
     }
     debug!("Firetype connections complete: {:?}", addrs);
 
-    Xml(QFireType { fire_type: 2 })
+    let (request_id, request_secret) = (query.request_id, query.request_secret);
+    service
+        .complete_firewall(request_id, request_secret, addrs.len() as u32)
+        .await;
+
+    // Keep the session around for a short while so late probes can be logged
+    let grace = Duration::from_millis(config.firewall_late_probe_grace_ms);
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        service
+            .remove_firewall_data(request_id, request_secret)
+            .await;
+    });
+
+    let mut fire_type = config.firetype_default;
+
+    if config.firetype_classify {
+        if addrs.is_empty() {
+            debug!("No firewall probes to classify, using default fire type");
+        } else {
+            fire_type = classifier.classify(internal, &addrs);
+            debug!("Classified fire type for {}: {}", internal, fire_type);
+        }
+    }
+
+    webhook::notify(FlowCompletion::firewall(request_id, client_addr, fire_type));
+
+    Ok(Xml(QFireType { fire_type }))
+}
+
+/// Stored data for a single flow, returned by the debug flow endpoint
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QFlowInfo {
+    /// Latency flow created by a qos request
+    Request(Box<QRequestData>),
+    /// Firewall flow created by a firewall request
+    Firewall(QFirewallInfo),
+}
+
+/// Response for the version endpoint
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    /// Version of the server
+    pub version: &'static str,
+    /// Region label of the server
+    pub region: Option<String>,
+}
+
+/// Provides the version and region of the server
+pub async fn version(Extension(config): Extension<Arc<Config>>) -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        region: config.region.clone(),
+    })
+}
+
+/// Response for the health endpoint
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    /// Health status of the server
+    pub status: &'static str,
+    /// Region label of the server
+    pub region: Option<String>,
+}
+
+/// Health check endpoint, responds while the server is running
+pub async fn health(Extension(config): Extension<Arc<Config>>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        region: config.region.clone(),
+    })
+}
+
+/// Readiness endpoint, responds with 503 Service Unavailable
+/// until the servers have finished warming up
+pub async fn ready() -> StatusCode {
+    if warmup::is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Maximum width of the bars in latency report histograms
+const LATENCY_REPORT_BAR_WIDTH: usize = 40;
+
+/// Provides a plain text histogram of the times between the probes of a
+/// completed latency session
+pub async fn latency_report(
+    Path((id, secret)): Path<(u32, u32)>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Result<Response, StatusCode> {
+    let data = service
+        .get_request_data(id, secret)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // Gaps aren't meaningful until every probe has arrived
+    if data.completed_at.is_none() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let report = latency_histogram(&data.inter_probe_gaps());
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        report,
+    )
+        .into_response())
+}
+
+/// Renders the provided `gaps` as a histogram with a bucket for each
+/// millisecond, bars are scaled relative to the largest bucket
+fn latency_histogram(gaps: &[Duration]) -> String {
+    let mut buckets: BTreeMap<u128, usize> = BTreeMap::new();
+    for gap in gaps {
+        *buckets.entry(gap.as_millis()).or_default() += 1;
+    }
+
+    let largest = buckets.values().copied().max().unwrap_or(1);
+
+    let mut out = String::new();
+    for (millis, count) in buckets {
+        let width = (count * LATENCY_REPORT_BAR_WIDTH + largest / 2) / largest;
+        _ = writeln!(out, "{}ms: {} {}", millis, "█".repeat(width.max(1)), count);
+    }
+    out
+}
+
+/// Debug endpoint resetting the metrics counters
+pub async fn reset_metrics() -> StatusCode {
+    metrics::reset_counters();
+    info!("Metrics counters have been reset");
+    StatusCode::NO_CONTENT
+}
+
+/// Maximum number of sessions that can be created in a single batch
+const MAX_BATCH_SESSIONS: usize = 100_000;
+
+#[derive(Deserialize)]
+pub struct BatchSessionsQuery {
+    /// Number of sessions to create
+    pub count: usize,
+    /// QoS type of the created sessions
+    #[serde(default)]
+    pub qtyp: u32,
+}
+
+#[derive(Serialize)]
+pub struct BatchSession {
+    pub id: u32,
+    pub secret: u32,
+}
+
+/// Debug endpoint creating a batch of latency sessions for load testing
+pub async fn debug_batch_sessions(
+    Query(query): Query<BatchSessionsQuery>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Result<Json<Vec<BatchSession>>, StatusCode> {
+    if query.count > MAX_BATCH_SESSIONS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sessions = service
+        .batch_create_sessions(query.count, query.qtyp)
+        .await
+        .map_err(create_session_error)?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|(id, secret)| BatchSession { id, secret })
+            .collect(),
+    ))
+}
+
+/// Debug endpoint providing the session ID allocation log
+pub async fn debug_id_allocations(
+    Extension(service): Extension<Arc<QService>>,
+) -> Result<Json<Vec<IdAllocation>>, StatusCode> {
+    service
+        .id_allocations()
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Debug endpoint for looking up the stored data for a specific flow
+pub async fn debug_flow(
+    Path((id, secret)): Path<(u32, u32)>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Result<Json<QFlowInfo>, StatusCode> {
+    if let Some(data) = service.get_request_data(id, secret).await {
+        return Ok(Json(QFlowInfo::Request(Box::new(data))));
+    }
+
+    if let Some(data) = service.get_firewall_info(id, secret).await {
+        return Ok(Json(QFlowInfo::Firewall(data)));
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// Debug endpoint removing a latency session
+pub async fn debug_remove_flow(
+    Path((id, secret)): Path<(u32, u32)>,
+    Extension(service): Extension<Arc<QService>>,
+) -> StatusCode {
+    if service.remove_request_data(id, secret).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Debug endpoint providing the probes received by a firewall
+/// session without consuming them
+pub async fn debug_firewall_probes(
+    Path((id, secret)): Path<(u32, u32)>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Json<Vec<SocketAddr>> {
+    Json(service.drain_channel(id, secret).await)
+}
+
+/// Debug endpoint replaying the probe arrival log for a session
+pub async fn debug_probe_log(
+    Path(id): Path<u32>,
+) -> Result<Json<Vec<ProbeArrivalRecord>>, StatusCode> {
+    let path = session_event_log::path().ok_or(StatusCode::NOT_FOUND)?;
+
+    let records = tokio::task::spawn_blocking(move || {
+        session_event_log::replay(path, id).map(|records| records.collect::<Vec<_>>())
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|err| {
+        error!("Failed to replay probe arrival log: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(records))
+}
+
+/// Admin endpoint streaming the events of a latency session as
+/// server sent events with JSON encoded event data
+pub async fn session_events(
+    Path((id, secret)): Path<(u32, u32)>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let rx = service
+        .watch_session(id, secret)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let stream = BroadcastStream::new(rx).filter_map(|event| {
+        // Lagged receivers skip the missed events
+        let event = event.ok()?;
+        Event::default().json_data(event).ok().map(Ok)
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Admin endpoint exposing the server metrics in the Prometheus format
+pub async fn get_metrics() -> String {
+    metrics::render()
+}
+
+/// Admin endpoint exporting all the latency sessions as a JSON array
+pub async fn export_sessions_json(
+    Extension(service): Extension<Arc<QService>>,
+) -> Json<serde_json::Value> {
+    Json(service.export_sessions_json().await)
+}
+
+/// Admin endpoint streaming all the latency sessions as CSV rows
+pub async fn export_sessions_csv(Extension(service): Extension<Arc<QService>>) -> Response {
+    let sessions = service.list_sessions().await;
+
+    // Rows are serialized from the snapshot as the body is streamed
+    let rows = std::iter::once(CSV_HEADER.to_string())
+        .chain(sessions.into_iter().map(|session| session.to_csv_row()))
+        .map(|row| Ok::<_, Infallible>(row + "\n"));
+
+    (
+        [(header::CONTENT_TYPE, "text/csv")],
+        StreamBody::new(tokio_stream::iter(rows)),
+    )
+        .into_response()
+}
+
+/// Default measurement for sessions exported to InfluxDB
+const DEFAULT_INFLUXDB_MEASUREMENT: &str = "qos_session";
+
+#[derive(Debug, Deserialize)]
+pub struct InfluxDbExportQuery {
+    /// Measurement to write the sessions to
+    pub measurement: Option<String>,
+}
+
+/// Admin endpoint streaming all the completed latency
+/// sessions in the InfluxDB line protocol
+pub async fn export_sessions_influxdb(
+    Query(query): Query<InfluxDbExportQuery>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Response {
+    let sessions = service.list_sessions().await;
+    let measurement = query
+        .measurement
+        .unwrap_or_else(|| DEFAULT_INFLUXDB_MEASUREMENT.to_string());
+
+    // Lines are serialized from the snapshot as the body is streamed
+    let lines = sessions
+        .into_iter()
+        .filter(|session| session.completed_at.is_some())
+        .map(move |session| Ok::<_, Infallible>(session.to_influxdb_line(&measurement) + "\n"));
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        StreamBody::new(tokio_stream::iter(lines)),
+    )
+        .into_response()
+}
+
+/// Default age in seconds after which firewall sessions are considered expired
+const DEFAULT_EXPIRED_TTL_SECS: u64 = 30;
+
+#[derive(Debug, Deserialize)]
+pub struct ExpiredSessionsQuery {
+    /// Age in seconds after which a session is considered expired
+    pub ttl: Option<u64>,
+}
+
+/// Admin endpoint listing the firewall sessions that have been waiting
+/// longer than the provided TTL for their firewall probes
+pub async fn expired_firewall_sessions(
+    Query(query): Query<ExpiredSessionsQuery>,
+    Extension(service): Extension<Arc<QService>>,
+) -> Json<Vec<(u32, u32)>> {
+    let ttl = Duration::from_secs(query.ttl.unwrap_or(DEFAULT_EXPIRED_TTL_SECS));
+    Json(service.list_expired_firewall_sessions(ttl).await)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        async_trait,
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{header, Request, StatusCode},
+        Router,
+    };
+    use log::Level;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time,
+    };
+    use tower::ServiceExt;
+
+    use super::{bind, router, ResponseVariant, UserAgentVariant};
+    use crate::{
+        blocklist::BlocklistChecker,
+        config::Config,
+        geoip::GeoLookup,
+        logging::capture,
+        metrics,
+        probe_channel::SendError,
+        service::{ProbeSessionState, QRequestData, QService, CSV_HEADER},
+        session_store::{
+            CreateSession, InMemorySessionStore, SessionKey, SessionStore, StoreError,
+        },
+        warmup,
+    };
+
+    /// Address the test requests are sent from
+    fn client() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 3659))
+    }
+
+    /// Creates the service and router for the `config`
+    fn test_router(config: Config) -> (Arc<QService>, Router) {
+        store_router(config, Box::new(InMemorySessionStore::default()))
+    }
+
+    /// Creates the service storing sessions in the `store` and the
+    /// router for the `config`
+    fn store_router(config: Config, store: Box<dyn SessionStore>) -> (Arc<QService>, Router) {
+        let service = Arc::new(QService::new(&config, store));
+        let router = router(
+            service.clone(),
+            Arc::new(config),
+            Arc::new(BlocklistChecker::default()),
+        )
+        .layer(MockConnectInfo(client()));
+        (service, router)
+    }
+
+    /// Sends the `request` returning the response status and body
+    async fn send(router: &Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Sends a GET request for the `uri`
+    async fn get(router: &Router, uri: &str) -> (StatusCode, String) {
+        send(router, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    /// Creates a latency session expecting `num_probes` probes
+    async fn latency_session(service: &QService, num_probes: u32) -> (u32, u32) {
+        service
+            .create_request_data(1, num_probes, 3659, 2, client())
+            .await
+            .unwrap()
+    }
+
+    /// Records a probe of each of the `sizes` for the session, spaced out
+    /// so the probes span some time
+    async fn record_probes(service: &QService, (id, secret): (u32, u32), sizes: &[usize]) {
+        let port = Config::default().udp_port_1;
+        for (probe_number, size) in sizes.iter().enumerate() {
+            if probe_number > 0 {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+            service
+                .record_probe(
+                    id,
+                    secret,
+                    probe_number as u32,
+                    *size,
+                    port,
+                    Duration::from_secs(60),
+                )
+                .await;
+        }
+    }
+
+    /// Creates a firewall session whose secret passes the session
+    /// parameter validation, which rejects a zero secret
+    async fn firewall_session(service: &QService) -> (u32, u32) {
+        loop {
+            let (id, secret) = service.create_firewall_data(client()).await.unwrap();
+            if secret != 0 {
+                return (id, secret);
+            }
+        }
+    }
+
+    /// Firetype completes the firewall session it waited on, so repeating
+    /// the request is rejected while the session is kept for late probes
+    #[tokio::test]
+    async fn firetype_completes_firewall_session() {
+        let (service, router) = test_router(Config {
+            firewall_session_max_probes: 1,
+            firewall_late_probe_grace_ms: 60_000,
+            ..Default::default()
+        });
+
+        let (id, secret) = firewall_session(&service).await;
+        let tx = service.get_firewall_tx(id, secret).await.unwrap();
+        tx.send(client()).await.unwrap();
+
+        let uri = format!(
+            "/qos/firetype?vers=2&rqid={}&rqsc={}&inip=2130706433&inpt=3659",
+            id, secret
+        );
+        let (status, body) = get(&router, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<firetype>"), "unexpected body {}", body);
+
+        assert!(matches!(
+            service.probe_session_state(id, secret).await,
+            ProbeSessionState::Completed {
+                total_probes: 1,
+                ..
+            }
+        ));
+
+        let (status, _) = get(&router, &uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    /// The configured region is reported by the health and version endpoints
+    #[tokio::test]
+    async fn region_reported_by_health_and_version() {
+        let (_, router) = test_router(Config {
+            region: Some("eu-west".to_string()),
+            ..Default::default()
+        });
+
+        for uri in ["/qos/health", "/qos/version"] {
+            let (status, body) = get(&router, uri).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["region"], "eu-west", "{} region", uri);
+        }
+    }
+
+    /// Clients with a User-Agent matching a legacy pattern get the legacy
+    /// response shape while other clients get the standard response
+    #[tokio::test]
+    async fn user_agent_selects_response_variant() {
+        let (_, router) = test_router(Config {
+            user_agent_variants: vec![UserAgentVariant {
+                pattern: "ME3-Legacy".to_string(),
+                variant: ResponseVariant::Legacy,
+            }],
+            ..Default::default()
+        });
+
+        let request = |user_agent: &str| {
+            Request::get("/qos/qos?prpt=3659&vers=1&qtyp=1")
+                .header(header::USER_AGENT, user_agent)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, body) = send(&router, request("Blaze/3.15 (me3-legacy)")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.contains("<qosip>127.0.0.1</qosip>"),
+            "unexpected legacy body {}",
+            body
+        );
+
+        let (status, body) = send(&router, request("Blaze/3.15 (me3)")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.contains("<qosip>2130706433</qosip>"),
+            "unexpected standard body {}",
+            body
+        );
+    }
+
+    /// Exported rows hold the bandwidth computed from the recorded probes,
+    /// left empty for sessions without enough probes to compute it
+    #[tokio::test]
+    async fn csv_export_rows() {
+        let (service, router) = test_router(Config {
+            admin_endpoints: true,
+            ..Default::default()
+        });
+
+        let probed = latency_session(&service, 5).await;
+        record_probes(&service, probed, &[100, 100]).await;
+        let unprobed = latency_session(&service, 5).await;
+
+        let (status, body) = get(&router, "/qos/sessions/export.csv").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut lines = body.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), 2);
+        let row = |id: u32| {
+            rows.iter()
+                .find(|row| row[0] == id.to_string())
+                .unwrap_or_else(|| panic!("missing row for session {}", id))
+        };
+
+        let probed_row = row(probed.0);
+        assert_eq!(probed_row.len(), CSV_HEADER.split(',').count());
+        assert_eq!(probed_row[1], probed.1.to_string());
+        assert_eq!(
+            probed_row[3], "",
+            "incomplete session has a completion time"
+        );
+        assert_eq!(probed_row[4], "2");
+        assert_eq!(probed_row[6], "3659");
+        assert_eq!(probed_row[7], "2");
+
+        // 800 bits over at least 20ms
+        let ubps: u32 = probed_row[5].parse().unwrap();
+        assert!(ubps > 0 && ubps <= 40_000, "unexpected ubps {}", ubps);
+        let data = service.get_request_data(probed.0, probed.1).await.unwrap();
+        assert_eq!(Some(ubps), data.computed_ubps());
+
+        assert_eq!(row(unprobed.0)[5], "");
+    }
+
+    /// Exported lines hold the bandwidth computed from the recorded probes
+    /// and leave the field out for sessions without enough probes for it
+    #[tokio::test]
+    async fn influxdb_export_lines() {
+        let (service, router) = test_router(Config {
+            admin_endpoints: true,
+            ..Default::default()
+        });
+
+        let probed = latency_session(&service, 2).await;
+        record_probes(&service, probed, &[100, 100]).await;
+        let single = latency_session(&service, 1).await;
+        record_probes(&service, single, &[100]).await;
+        // Incomplete sessions aren't exported
+        latency_session(&service, 5).await;
+
+        let request = Request::post("/qos/sessions/influxdb?measurement=qos_test")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.lines().count(), 2);
+
+        let line = |id: u32| {
+            let prefix = format!("qos_test,session_id={},version=2 ", id);
+            body.lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .unwrap_or_else(|| panic!("missing line for session {}", id))
+        };
+
+        let fields = line(probed.0).split(' ').next().unwrap();
+        let ubps: u32 = fields
+            .split(',')
+            .find_map(|field| field.strip_prefix("ubps="))
+            .expect("ubps field")
+            .parse()
+            .unwrap();
+        let data = service.get_request_data(probed.0, probed.1).await.unwrap();
+        assert_eq!(Some(ubps), data.computed_ubps());
+        assert!(fields.contains("probe_count=2"));
+
+        assert!(line(single.0).starts_with("probe_count=1,"));
+    }
+
+    /// The ready endpoint reports unavailable until warmup completes
+    #[tokio::test]
+    async fn ready_after_warmup() {
+        let _guard = warmup::TEST_LOCK.write().await;
+        warmup::reset();
+
+        let (_, router) = test_router(Config::default());
+        let (status, _) = get(&router, "/qos/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        warmup::run(Arc::new(Config::default())).await;
+        let (status, _) = get(&router, "/qos/ready").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Requests with headers over the max header size are rejected with 431
+    /// while requests within it are served
+    #[tokio::test]
+    async fn oversized_headers_rejected() {
+        let config = Config::default();
+        let max_header_size = config.http_max_header_size;
+        let server = bind(&SocketAddr::from(([127, 0, 0, 1], 0)), &config);
+        let (_, router) = test_router(config);
+        let server = server.serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let status = |header_size: usize| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /qos/health HTTP/1.1\r\nHost: localhost\r\nX-Padding: {}\r\nConnection: close\r\n\r\n",
+                "a".repeat(header_size)
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            let response = String::from_utf8_lossy(&response).into_owned();
+            response.lines().next().unwrap_or_default().to_string()
+        };
+
+        assert_eq!(status(64).await, "HTTP/1.1 200 OK");
+        assert_eq!(
+            status(max_header_size * 2).await,
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+    }
+
+    /// The debug flow endpoint provides known latency and firewall flows
+    /// and responds with 404 for unknown flows
+    #[tokio::test]
+    async fn debug_flow_lookup() {
+        let (service, router) = test_router(Config {
+            debug_endpoints: true,
+            ..Default::default()
+        });
+
+        let (id, secret) = latency_session(&service, 5).await;
+        let (status, body) = get(&router, &format!("/qos/debug/flow/{}/{}", id, secret)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["type"], "request");
+        assert_eq!(body["id"], id);
+        assert_eq!(body["num_probes"], 5);
+
+        let (id, secret) = firewall_session(&service).await;
+        let (status, body) = get(&router, &format!("/qos/debug/flow/{}/{}", id, secret)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["type"], "firewall");
+
+        let (status, _) = get(&router, &format!("/qos/debug/flow/{}/{}", id, secret + 1)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    /// Responses are gzip compressed for clients accepting gzip and left
+    /// uncompressed for clients that don't
+    #[tokio::test]
+    async fn responses_compressed_when_accepted() {
+        let (_, router) = test_router(Config {
+            http_compression: true,
+            ..Default::default()
+        });
+
+        let request = |accept_encoding: Option<&str>| {
+            let mut request = Request::get("/qos/version");
+            if let Some(value) = accept_encoding {
+                request = request.header(header::ACCEPT_ENCODING, value);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = router.clone().oneshot(request(Some("gzip"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..2], &[0x1f, 0x8b], "body isn't gzip");
+
+        let response = router.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["version"].is_string());
+    }
+
+    /// The server name is included in the X-QoS-Server header of every
+    /// response when enabled and left out otherwise
+    #[tokio::test]
+    async fn server_header_included_when_enabled() {
+        let (_, router) = test_router(Config {
+            server_header: true,
+            server_name: Some("qos-eu-1".to_string()),
+            ..Default::default()
+        });
+
+        for uri in ["/qos/version", "/qos/health", "/unknown"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.headers()["x-qos-server"], "qos-eu-1", "{}", uri);
+        }
+
+        let (_, router) = test_router(Config::default());
+        let request = Request::get("/qos/version").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert!(!response.headers().contains_key("x-qos-server"));
+    }
+
+    /// Late probes still reach the firewall session during the grace period
+    /// after firetype completes, once it ends the session is removed
+    #[tokio::test]
+    async fn late_firewall_probe_grace_period() {
+        let (service, router) = test_router(Config {
+            firewall_session_max_probes: 1,
+            firewall_late_probe_grace_ms: 300,
+            ..Default::default()
+        });
+
+        let (id, secret) = firewall_session(&service).await;
+        let tx = service.get_firewall_tx(id, secret).await.unwrap();
+        tx.send(client()).await.unwrap();
+
+        let uri = format!(
+            "/qos/firetype?vers=2&rqid={}&rqsc={}&inip=2130706433&inpt=3659",
+            id, secret
+        );
+        let (status, _) = get(&router, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Within the grace period the late probe finds the session
+        let tx = service
+            .get_firewall_tx(id, secret)
+            .await
+            .expect("session removed during grace period");
+        assert!(matches!(tx.send(client()).await, Err(SendError::Closed)));
+
+        // Outside the grace period the session is gone
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(service.get_firewall_tx(id, secret).await.is_none());
+    }
+
+    /// Public and unspecified internal addresses reported to firetype are
+    /// warned about while private addresses aren't
+    #[tokio::test]
+    async fn implausible_internal_address_warned() {
+        let (service, router) = test_router(Config {
+            firewall_session_max_probes: 1,
+            ..Default::default()
+        });
+
+        // 203.0.113.7, 0.0.0.0 and 192.168.1.20 as signed integers
+        let cases = [(-889163513, true), (0, true), (-1062731500, false)];
+
+        for (internal_ip, warned) in cases {
+            let (id, secret) = firewall_session(&service).await;
+            let tx = service.get_firewall_tx(id, secret).await.unwrap();
+            tx.send(client()).await.unwrap();
+
+            let uri = format!(
+                "/qos/firetype?vers=2&rqid={}&rqsc={}&inip={}&inpt=3659",
+                id, secret, internal_ip
+            );
+            capture::start();
+            let (status, _) = get(&router, &uri).await;
+            let records = capture::take();
+            assert_eq!(status, StatusCode::OK);
+
+            assert_eq!(
+                records.iter().any(|(level, message)| *level == Level::Warn
+                    && message.contains("implausible internal address")),
+                warned,
+                "{} {:?}",
+                internal_ip,
+                records
+            );
+        }
+    }
+
+    /// Maintenance mode rejects QoS requests as unavailable while the
+    /// admin endpoints are still served
+    #[tokio::test]
+    async fn maintenance_mode_rejects_qos_requests() {
+        let (_, router) = test_router(Config {
+            maintenance_mode: true,
+            admin_endpoints: true,
+            ..Default::default()
+        });
+
+        for uri in [
+            "/qos/qos?prpt=3659&vers=1&qtyp=1",
+            "/qos/firewall?vers=1&nint=1",
+            "/qos/version",
+            "/qos/health",
+        ] {
+            let (status, _) = get(&router, uri).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+        }
+
+        let (status, _) = get(&router, "/qos/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, router) = test_router(Config::default());
+        let (status, _) = get(&router, "/qos/version").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Clients asking for fewer interfaces than configured are only given
+    /// as many interfaces as they asked for
+    #[tokio::test]
+    async fn firewall_interfaces_clamped_to_nint() {
+        let (_, router) = test_router(Config {
+            firewall_interface_ips: vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()],
+            ..Default::default()
+        });
+
+        // 10.0.0.1 and 10.0.0.2 as integers
+        let (first, second) = ("<ip>167772161</ip>", "<ip>167772162</ip>");
+
+        let (status, body) = get(&router, "/qos/firewall?vers=1&nint=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.contains("<numinterfaces>1</numinterfaces>"),
+            "{}",
+            body
+        );
+        assert!(body.contains(first) && !body.contains(second), "{}", body);
+        // The port list element wraps an element for each port
+        assert_eq!(body.matches("<ports>").count(), 2, "{}", body);
+
+        let (status, body) = get(&router, "/qos/firewall?vers=1&nint=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.contains("<numinterfaces>2</numinterfaces>"),
+            "{}",
+            body
+        );
+        assert!(body.contains(first) && body.contains(second), "{}", body);
+    }
+
+    /// Latency flows beyond the per-client limit are rejected with 429
+    /// until one of the client's flows is removed
+    #[tokio::test]
+    async fn client_flow_limit_rejected() {
+        let (service, router) = test_router(Config {
+            max_client_flows: Some(2),
+            ..Default::default()
+        });
+        let uri = "/qos/qos?prpt=3659&vers=2&qtyp=2";
+
+        for _ in 0..2 {
+            let (status, body) = get(&router, uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+
+        capture::start();
+        let (status, _) = get(&router, uri).await;
+        let records = capture::take();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(records.iter().any(|(level, message)| *level == Level::Warn
+            && message.contains("too many concurrent flows")));
+
+        // Address queries don't create flows so aren't limited
+        let (status, _) = get(&router, "/qos/qos?prpt=3659&vers=2&qtyp=1").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let session = service.list_sessions().await.remove(0);
+        assert!(
+            service
+                .remove_request_data(session.id, session.secret)
+                .await
+        );
+        let (status, _) = get(&router, uri).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Resetting the metrics zeroes the counters while the gauges keep
+    /// tracking the current state
+    #[tokio::test]
+    async fn metrics_reset_zeroes_counters() {
+        let _metrics = metrics::TEST_LOCK.write().await;
+        let (_, router) = test_router(Config {
+            debug_endpoints: true,
+            admin_endpoints: true,
+            ..Default::default()
+        });
+
+        /// Removes the gauge offset even if the test fails
+        struct RemoveOffset;
+
+        impl Drop for RemoveOffset {
+            fn drop(&mut self) {
+                metrics::QOS_ACTIVE_SESSIONS.sub(GAUGE_OFFSET);
+            }
+        }
+
+        // Offset large enough to stand out from sessions of other tests
+        const GAUGE_OFFSET: i64 = 1_000_000;
+        metrics::QOS_ACTIVE_SESSIONS.add(GAUGE_OFFSET);
+        let _offset = RemoveOffset;
+
+        metrics::EMPTY_DATAGRAMS.inc();
+        metrics::DUPLICATE_PROBES.add(3);
+        metrics::SHUTDOWN_DROPPED.inc();
+        let (status, _) = get(&router, "/qos/firewall?vers=7777&nint=1").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let version_line = "qos_requests_by_version_total{vers=\"7777\"} 1";
+        let (_, body) = get(&router, "/qos/metrics").await;
+        assert!(body.contains(version_line), "{}", body);
+
+        let request = Request::post("/qos/debug/metrics/reset")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&router, request).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        assert_eq!(metrics::EMPTY_DATAGRAMS.get(), 0);
+        assert_eq!(metrics::DUPLICATE_PROBES.get(), 0);
+        assert_eq!(metrics::SHUTDOWN_DROPPED.get(), 0);
+        let (_, body) = get(&router, "/qos/metrics").await;
+        assert!(!body.contains("vers=\"7777\""), "{}", body);
+
+        assert!(metrics::QOS_ACTIVE_SESSIONS.get() > GAUGE_OFFSET / 2);
+    }
+
+    /// Requests to the QoS endpoints are counted by their version
+    #[tokio::test]
+    async fn requests_counted_by_version() {
+        let _metrics = metrics::TEST_LOCK.read().await;
+        let (_, router) = test_router(Config::default());
+
+        // Versions that no other test uses so the counts are exact
+        let (v1, v2) = (9001, 9002);
+        for uri in [
+            format!("/qos/qos?prpt=3659&vers={}&qtyp=1", v1),
+            format!("/qos/qos?prpt=3659&vers={}&qtyp=1", v2),
+            format!("/qos/firewall?vers={}&nint=1", v2),
+        ] {
+            let (status, _) = get(&router, &uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+
+        assert_eq!(metrics::REQUESTS_BY_VERSION.get(v1), 1);
+        assert_eq!(metrics::REQUESTS_BY_VERSION.get(v2), 2);
+    }
+
+    /// In-memory store that can be made to panic while holding its lock
+    #[derive(Default)]
+    struct PanickingStore {
+        inner: InMemorySessionStore,
+        /// Whether the next insert panics
+        panic_next: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl SessionStore for PanickingStore {
+        async fn insert(&self, key: SessionKey, data: QRequestData) -> Result<(), StoreError> {
+            if self.panic_next.swap(false, Ordering::SeqCst) {
+                // Panics from within the inner store while it's locked
+                let mut create = |_: &dyn Fn(u32) -> bool| -> Option<QRequestData> {
+                    panic!("Simulated panic while storing a session")
+                };
+                _ = self.inner.insert_many(1, &mut create).await;
+            }
+            self.inner.insert(key, data).await
+        }
+
+        async fn insert_many(
+            &self,
+            count: usize,
+            create: &mut CreateSession<'_>,
+        ) -> Result<Vec<SessionKey>, StoreError> {
+            self.inner.insert_many(count, create).await
+        }
+
+        async fn get(&self, key: SessionKey) -> Option<QRequestData> {
+            self.inner.get(key).await
+        }
+
+        async fn remove(&self, key: SessionKey) -> bool {
+            self.inner.remove(key).await
+        }
+
+        async fn contains(&self, key: SessionKey) -> bool {
+            self.inner.contains(key).await
+        }
+
+        async fn list(&self) -> Vec<QRequestData> {
+            self.inner.list().await
+        }
+
+        async fn count_where(
+            &self,
+            predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+        ) -> usize {
+            self.inner.count_where(predicate).await
+        }
+
+        async fn update(
+            &self,
+            key: SessionKey,
+            update: &mut (dyn for<'a> FnMut(&'a mut QRequestData) + Send),
+        ) -> bool {
+            self.inner.update(key, update).await
+        }
+
+        async fn remove_where(
+            &self,
+            predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+        ) -> Vec<QRequestData> {
+            self.inner.remove_where(predicate).await
+        }
+    }
+
+    /// A handler panicking while the session store is locked responds with
+    /// a 500 and the fallback XML while later requests are still served
+    #[tokio::test]
+    async fn handler_panic_isolated() {
+        let store = PanickingStore::default();
+        let panic_next = store.panic_next.clone();
+        let fallback_xml = Config::default().http_fallback_xml;
+        let (service, router) = store_router(Config::default(), Box::new(store));
+        let uri = "/qos/qos?prpt=3659&vers=2&qtyp=2";
+
+        panic_next.store(true, Ordering::SeqCst);
+        let (status, body) = get(&router, uri).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body, fallback_xml);
+
+        for _ in 0..3 {
+            let (status, body) = get(&router, uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+        assert_eq!(service.list_sessions().await.len(), 3);
+    }
+
+    /// GeoIP lookup placing every loopback client in the same region
+    struct StubGeoLookup;
+
+    impl GeoLookup for StubGeoLookup {
+        fn region(&self, addr: Ipv4Addr) -> Option<String> {
+            addr.is_loopback().then(|| "eu-west".to_string())
+        }
+    }
+
+    /// Firewall sessions are annotated with the region of the client from
+    /// the GeoIP lookup and left without one when there's no database
+    #[tokio::test]
+    async fn firewall_session_region_annotation() {
+        let config = Config {
+            debug_endpoints: true,
+            ..Default::default()
+        };
+        let service = Arc::new(
+            QService::new(&config, Box::new(InMemorySessionStore::default()))
+                .with_geo_lookup(Box::new(StubGeoLookup)),
+        );
+        let router = router(
+            service.clone(),
+            Arc::new(config),
+            Arc::new(BlocklistChecker::default()),
+        )
+        .layer(MockConnectInfo(client()));
+
+        let (id, secret) = firewall_session(&service).await;
+        let (status, body) = get(&router, &format!("/qos/debug/flow/{}/{}", id, secret)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["type"], "firewall");
+        assert_eq!(body["client_region"], "eu-west");
+
+        let (service, router) = test_router(Config {
+            debug_endpoints: true,
+            ..Default::default()
+        });
+        let (id, secret) = firewall_session(&service).await;
+        let (status, body) = get(&router, &format!("/qos/debug/flow/{}/{}", id, secret)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["client_region"].is_null());
+    }
 }
@@ -0,0 +1,109 @@
+//! Derivation of the identity used to group requests from the same
+//! client. Each mode trades off accuracy against how easily it can be
+//! evaded:
+//!
+//! * [ClientIdentityMode::Ip] groups by source address. Simple and hard to
+//!   evade but every client sharing a public address (CGNAT, shared homes)
+//!   is treated as the same client.
+//! * [ClientIdentityMode::IpPort] groups by source address and port. This
+//!   separates clients behind the same NAT but a client can obtain a new
+//!   identity by opening a new socket, and HTTP and UDP traffic from one
+//!   client use different ports so won't share an identity.
+//! * [ClientIdentityMode::Session] uses a hash of the session ID and secret.
+//!   Every flow is its own client so nothing is shared between flows, this
+//!   makes it useless for limiting flow creation (requests that don't have
+//!   a session yet fall back to the source address)
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Mode used for deriving client identities
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentityMode {
+    /// Identify clients by their source address
+    #[default]
+    Ip,
+    /// Identify clients by their source address and port
+    IpPort,
+    /// Identify clients by a hash of their session ID and secret
+    Session,
+}
+
+/// Derives the identity of a client using the provided `mode` from its
+/// source `addr` and `session` ID and secret if one has been created
+pub fn client_identity(
+    mode: ClientIdentityMode,
+    addr: SocketAddr,
+    session: Option<(u32, u32)>,
+) -> String {
+    match (mode, session) {
+        (ClientIdentityMode::Ip, _) | (ClientIdentityMode::Session, None) => addr.ip().to_string(),
+        (ClientIdentityMode::IpPort, _) => addr.to_string(),
+        (ClientIdentityMode::Session, Some(session)) => {
+            let mut hasher = DefaultHasher::new();
+            session.hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{client_identity, ClientIdentityMode};
+
+    /// Each mode derives the expected key from sample clients
+    #[test]
+    fn identity_keys_for_each_mode() {
+        let addr = SocketAddr::from(([203, 0, 113, 7], 3659));
+        let other_port = SocketAddr::from(([203, 0, 113, 7], 40000));
+
+        assert_eq!(
+            client_identity(ClientIdentityMode::Ip, addr, Some((1, 2))),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_identity(ClientIdentityMode::Ip, other_port, None),
+            "203.0.113.7"
+        );
+
+        assert_eq!(
+            client_identity(ClientIdentityMode::IpPort, addr, Some((1, 2))),
+            "203.0.113.7:3659"
+        );
+        assert_eq!(
+            client_identity(ClientIdentityMode::IpPort, other_port, None),
+            "203.0.113.7:40000"
+        );
+
+        // Sessions are keyed by their ID and secret regardless of address
+        let session = client_identity(ClientIdentityMode::Session, addr, Some((1, 2)));
+        assert_eq!(session.len(), 16);
+        assert!(session.chars().all(|value| value.is_ascii_hexdigit()));
+        assert_eq!(
+            client_identity(ClientIdentityMode::Session, other_port, Some((1, 2))),
+            session
+        );
+        assert_ne!(
+            client_identity(ClientIdentityMode::Session, addr, Some((1, 3))),
+            session
+        );
+        assert_ne!(
+            client_identity(ClientIdentityMode::Session, addr, Some((2, 2))),
+            session
+        );
+
+        // Without a session the address is used instead
+        assert_eq!(
+            client_identity(ClientIdentityMode::Session, addr, None),
+            "203.0.113.7"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Quality of service server for Pocket Relay
+
+pub mod args;
+pub mod blocklist;
+pub mod buffer_limit;
+pub mod config;
+pub mod config_source;
+pub mod diagnostics;
+pub mod egress;
+pub mod firewall;
+pub mod geoip;
+pub mod http;
+pub mod identity;
+pub mod logging;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
+pub mod pcap;
+pub mod probe_channel;
+pub mod rate_limit;
+pub mod service;
+pub mod session_event_log;
+pub mod session_store;
+pub mod shutdown;
+pub mod signing;
+pub mod swap;
+pub mod udp;
+pub mod udp_runtime;
+pub mod validate;
+pub mod warmup;
+pub mod webhook;
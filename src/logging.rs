@@ -1,52 +1,532 @@
-use log::LevelFilter;
+use std::{fmt::Debug, net::Ipv4Addr, path::Path, str::FromStr, sync::Mutex};
+
+use log::{error, info, warn, Level, LevelFilter, Record};
 use log4rs::{
-    append::{console::ConsoleAppender, file::FileAppender},
+    append::{console::ConsoleAppender, file::FileAppender, Append},
     config::{Appender, Logger, Root},
-    encode::pattern::PatternEncoder,
-    init_config, Config,
+    encode::{pattern::PatternEncoder, writer::simple::SimpleWriter, Encode},
+    init_config, Config, Handle,
 };
+use syslog::{Facility, Formatter3164, LoggerBackend};
+
+use crate::{config::Config as ServerConfig, udp::IpSource};
 
 /// The pattern to use when logging
 const LOGGING_PATTERN: &str = "[{d} {h({l})} {M}] {m}{n}";
 
+/// The pattern to use when logging to syslog
+const SYSLOG_PATTERN: &str = "[{M}] {m}";
+
 /// Log file name
 pub const LOG_FILE_NAME: &str = "server.log";
 
+/// The logging level used for the server, trace is included so
+/// that packet dumps can be enabled through the config
+pub const LOGGING_LEVEL: LevelFilter = LevelFilter::Trace;
+
 /// Setup function for setting up the Log4rs logging configuring it
 /// for all the different modules and and setting up file and stdout logging
-pub fn setup() {
-    let logging_level = LevelFilter::Debug;
+pub fn setup() -> Handle {
+    let appenders = create_appenders(Path::new(LOG_FILE_NAME));
+    let handle = init_config(create_config(appenders)).expect("Unable to initialize logger");
+
+    // Include panics in logging
+    log_panics::init();
+
+    handle
+}
+
+/// Replaces the file and stdout logging with logging to the local syslog
+/// daemon, syslog messages include their own timestamps so only the
+/// module and message are included
+pub fn use_syslog(handle: &Handle, config: &ServerConfig) {
+    let facility = match Facility::from_str(&config.syslog_facility) {
+        Ok(value) => value,
+        Err(_) => {
+            warn!(
+                "Unknown syslog facility {}, using daemon",
+                config.syslog_facility
+            );
+            Facility::LOG_DAEMON
+        }
+    };
+
+    let formatter = Formatter3164 {
+        facility,
+        hostname: None,
+        process: env!("CARGO_PKG_NAME").to_string(),
+        pid: std::process::id(),
+    };
+
+    let logger = match syslog::unix(formatter) {
+        Ok(value) => value,
+        Err(err) => {
+            error!(
+                "Failed to connect to syslog, keeping existing logging: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let appender = Box::new(SyslogAppender {
+        logger: Mutex::new(logger),
+        encoder: Box::new(PatternEncoder::new(SYSLOG_PATTERN)),
+    });
+
+    handle.set_config(create_config(vec![("syslog", appender)]));
+}
 
-    // Create logging appenders
+/// Creates the stdout appender and the file appender writing to the
+/// log file at `path`
+fn create_appenders(path: &Path) -> Vec<(&'static str, Box<dyn Append>)> {
     let pattern = Box::new(PatternEncoder::new(LOGGING_PATTERN));
     let console = Box::new(ConsoleAppender::builder().encoder(pattern.clone()).build());
-    let file = Box::new(
-        FileAppender::builder()
-            .encoder(pattern)
-            .build(LOG_FILE_NAME)
-            .expect("Unable to create logging file appender"),
-    );
 
-    const APPENDERS: [&str; 2] = ["stdout", "file"];
+    let mut appenders: Vec<(&str, Box<dyn Append>)> = vec![("stdout", console)];
+
+    // Fallback to only logging to stdout when the log file can't be created
+    match FileAppender::builder().encoder(pattern).build(path) {
+        Ok(value) => appenders.push(("file", Box::new(value))),
+        Err(err) => {
+            eprintln!(
+                "WARNING: Unable to create log file {}, logging to stdout only: {}",
+                path.display(),
+                err
+            );
+        }
+    };
+
+    appenders
+}
+
+/// Creates the logging config writing to all the provided `appenders`
+fn create_config(appenders: Vec<(&str, Box<dyn Append>)>) -> Config {
+    let names: Vec<&str> = appenders.iter().map(|(name, _)| *name).collect();
+
+    let mut builder = Config::builder();
+    for (name, appender) in appenders {
+        builder = builder.appender(Appender::builder().build(name, appender));
+    }
 
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", console))
-        .appender(Appender::builder().build("file", file))
+    builder
         .logger(
             Logger::builder()
-                .appenders(APPENDERS)
+                .appenders(names.clone())
                 .additive(false)
-                .build("pocket_relay_qos", logging_level),
+                .build("pocket_relay_qos", LOGGING_LEVEL),
         )
-        .build(
-            Root::builder()
-                .appenders(APPENDERS)
-                .build(LevelFilter::Debug),
-        )
-        .expect("Failed to create logging config");
+        .build(Root::builder().appenders(names).build(LevelFilter::Debug))
+        .expect("Failed to create logging config")
+}
 
-    init_config(config).expect("Unable to initialize logger");
+/// Log4rs appender writing to syslog with the severity
+/// matching the level of each record
+struct SyslogAppender {
+    /// Connection to the syslog daemon
+    logger: Mutex<syslog::Logger<LoggerBackend, Formatter3164>>,
+    /// Encoder for the message of each record
+    encoder: Box<dyn Encode>,
+}
 
-    // Include panics in logging
-    log_panics::init();
+impl Debug for SyslogAppender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyslogAppender").finish_non_exhaustive()
+    }
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut message = SimpleWriter(Vec::new());
+        self.encoder.encode(&mut message, record)?;
+        let message = String::from_utf8_lossy(&message.0);
+
+        let logger = &mut *self
+            .logger
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Syslog logger poisoned"))?;
+
+        let result = match record.level() {
+            Level::Error => logger.err(message),
+            Level::Warn => logger.warning(message),
+            Level::Info => logger.info(message),
+            Level::Debug | Level::Trace => logger.debug(message),
+        };
+        result.map_err(|err| anyhow::anyhow!("{}", err))
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs a summary of the effective server configuration, any
+/// secret values are redacted before being logged
+pub fn log_startup_banner(config: &ServerConfig) {
+    let enabled = |value: bool| if value { "enabled" } else { "disabled" }.to_string();
+
+    let public_address_source = match config.probe_response_ip_source {
+        IpSource::ClientSource => "client source",
+        IpSource::Configured => "configured (private clients)",
+        IpSource::Dynamic => "lookup (private clients)",
+    };
+
+    let lines: Vec<(&str, String)> = vec![
+        (
+            "Region",
+            config.region.clone().unwrap_or_else(|| "unset".to_string()),
+        ),
+        ("HTTP port", config.http_port.to_string()),
+        ("QoS UDP port", config.udp_port_1.to_string()),
+        ("Firewall UDP port", config.udp_port_2.to_string()),
+        ("Bind address", Ipv4Addr::UNSPECIFIED.to_string()),
+        ("UDP TTL", config.udp_ttl.to_string()),
+        ("UDP batch receive", enabled(config.udp_batch_recv)),
+        ("UDP no fragment", enabled(config.udp_no_fragment)),
+        ("Warmup delay", format!("{}ms", config.warmup_delay_ms)),
+        (
+            "Completion webhook",
+            config
+                .completion_webhook
+                .clone()
+                .unwrap_or_else(|| "disabled".to_string()),
+        ),
+        (
+            "UDP rate limit",
+            match config.udp_rate_limit_rps {
+                Some(rate) => format!("{}/s (burst {})", rate, config.probe_burst_size()),
+                None => "unlimited".to_string(),
+            },
+        ),
+        (
+            "UDP runtime",
+            match config.udp_runtime_threads {
+                Some(threads) => format!("dedicated ({} threads)", threads),
+                None => "shared".to_string(),
+            },
+        ),
+        (
+            "UDP multicast group",
+            match config.udp_multicast_group {
+                Some(group) => group.to_string(),
+                None => "disabled".to_string(),
+            },
+        ),
+        (
+            "Firewall SO_REUSEPORT",
+            enabled(config.firewall_udp_reuseport),
+        ),
+        ("Self address", config.self_address.to_string()),
+        ("Public address source", public_address_source.to_string()),
+        (
+            "Shared public address",
+            format!("{:?}", config.shared_public_ip_policy),
+        ),
+        (
+            "Artificial delay",
+            format!(
+                "{}ms (jitter {}ms)",
+                config.artificial_delay_ms, config.artificial_delay_jitter_ms
+            ),
+        ),
+        (
+            "Response MTU",
+            match config.response_mtu {
+                Some(mtu) => format!("{} bytes", mtu),
+                None => "unlimited".to_string(),
+            },
+        ),
+        (
+            "Response payload floor",
+            match config.response_payload_floor {
+                0 => "disabled".to_string(),
+                floor => format!("{} bytes", floor),
+            },
+        ),
+        (
+            "Probe payload size",
+            format!(
+                "{}..={} bytes",
+                config.probe_size_min, config.probe_size_max
+            ),
+        ),
+        (
+            "Client identity",
+            format!("{:?}", config.client_identity_mode),
+        ),
+        (
+            "Request byte order",
+            format!("{:?}", config.request_byte_order),
+        ),
+        (
+            "Probe reorder tolerance",
+            config.probe_reorder_tolerance.to_string(),
+        ),
+        (
+            "Port mismatch policy",
+            format!("{:?}", config.port_mismatch_policy),
+        ),
+        ("Session TTL", format!("{}s", config.session_ttl_secs)),
+        ("Session ID prefix", config.session_id_prefix.to_string()),
+        ("Session ID start", config.session_id_start.to_string()),
+        (
+            "Max flow lifetime",
+            format!("{}s", config.max_flow_lifetime_secs),
+        ),
+        (
+            "Fire type",
+            if config.firetype_classify {
+                format!(
+                    "classified by {} (default {})",
+                    config.classifier, config.firetype_default
+                )
+            } else {
+                format!("fixed {}", config.firetype_default)
+            },
+        ),
+        (
+            "Firewall channel",
+            format!(
+                "{} probes ({:?})",
+                config.firewall_channel_capacity, config.firewall_channel_policy
+            ),
+        ),
+        (
+            "Firewall probe max size",
+            format!("{} bytes", config.firewall_probe_max_size),
+        ),
+        (
+            "Firewall session max probes",
+            config.firewall_session_max_probes.to_string(),
+        ),
+        (
+            "Buffered bytes limit",
+            match config.max_buffered_bytes {
+                Some(limit) => format!("{} bytes", limit),
+                None => "unlimited".to_string(),
+            },
+        ),
+        (
+            "Firewall probe ACK",
+            if config.firewall_probe_ack {
+                hex::encode(&config.firewall_ack_payload)
+            } else {
+                "disabled".to_string()
+            },
+        ),
+        ("V2 receive timestamp", enabled(config.v2_receive_timestamp)),
+        (
+            "QoS response flags",
+            match config.qos_response_flags {
+                Some(flags) => format!("{:#06x}", flags),
+                None => "echoed".to_string(),
+            },
+        ),
+        ("HTTP compression", enabled(config.http_compression)),
+        (
+            "User agent variants",
+            config.user_agent_variants.len().to_string(),
+        ),
+        (
+            "Server header",
+            if config.server_header {
+                config.resolve_server_name()
+            } else {
+                "disabled".to_string()
+            },
+        ),
+        ("Probe signing", enabled(config.probe_signing)),
+        ("Maintenance mode", enabled(config.maintenance_mode)),
+        (
+            "Invalid flow response",
+            enabled(config.invalid_flow_response),
+        ),
+        (
+            "UDP reachability check",
+            enabled(config.udp_reachability_check),
+        ),
+        (
+            "Syslog",
+            if config.use_syslog {
+                config.syslog_facility.clone()
+            } else {
+                "disabled".to_string()
+            },
+        ),
+        ("Debug endpoints", enabled(config.debug_endpoints)),
+        ("Admin endpoints", enabled(config.admin_endpoints)),
+        (
+            "IP blocklist",
+            format!(
+                "{} addresses, {} ranges",
+                config.ip_blocklist.len(),
+                config.ip_blocklist_cidr.len()
+            ),
+        ),
+        (
+            "Admin allowlist",
+            format!(
+                "{} addresses, {} ranges",
+                config.admin_allowlist.len(),
+                config.admin_allowlist_cidr.len()
+            ),
+        ),
+        (
+            "Probe arrival log",
+            match &config.probe_log_path {
+                Some(path) => path.display().to_string(),
+                None => "disabled".to_string(),
+            },
+        ),
+        (
+            "Pcap capture",
+            match &config.pcap_output {
+                Some(path) => path.display().to_string(),
+                None => "disabled".to_string(),
+            },
+        ),
+        (
+            "GeoIP database",
+            match &config.geoip_database {
+                Some(path) => path.display().to_string(),
+                None => "disabled".to_string(),
+            },
+        ),
+        ("UDP packet dumps", enabled(config.log_udp_packets)),
+        ("Log level", LOGGING_LEVEL.to_string()),
+    ];
+
+    let mut banner = format!(
+        "Pocket Relay QoS v{} starting with configuration:",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    for (name, value) in lines {
+        banner.push_str("\n\t");
+        banner.push_str(name);
+        banner.push_str(": ");
+        banner.push_str(&value);
+    }
+
+    info!("{}", banner);
+}
+
+/// Logger capturing the messages logged by tests
+#[cfg(test)]
+pub mod capture {
+    use std::{cell::RefCell, sync::Once};
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    thread_local! {
+        /// Messages logged on this thread since capturing started
+        static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Ensures the logger is only installed once
+    static INIT: Once = Once::new();
+
+    /// Logger recording messages for the thread they were logged on
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS.with(|records| {
+                records
+                    .borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Starts capturing the messages logged on the current thread,
+    /// discarding any previously captured messages
+    pub fn start() {
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).expect("Logger already set");
+            log::set_max_level(LevelFilter::Trace);
+        });
+        RECORDS.with(|records| records.borrow_mut().clear());
+    }
+
+    /// Takes the messages captured on the current thread
+    pub fn take() -> Vec<(Level, String)> {
+        RECORDS.with(|records| records.take())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::Level;
+
+    use super::{capture, create_appenders, create_config, log_startup_banner};
+    use crate::config::Config;
+
+    /// An unwritable log file path falls back to only logging to stdout
+    /// rather than panicking, writable paths log to both
+    #[test]
+    fn unwritable_log_file_falls_back_to_stdout() {
+        let dir = std::env::temp_dir().join(format!("qos-logging-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // The parent of the log file is a file so it can't be created
+        let blocker = dir.join("blocker");
+        std::fs::write(&blocker, "").unwrap();
+        let appenders = create_appenders(&blocker.join("server.log"));
+        let names: Vec<&str> = appenders.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["stdout"]);
+        create_config(appenders);
+
+        let appenders = create_appenders(&dir.join("server.log"));
+        let names: Vec<&str> = appenders.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["stdout", "file"]);
+        create_config(appenders);
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// The banner is logged as a single message including the key
+    /// configuration fields without exposing the signing key
+    #[test]
+    fn startup_banner_contents() {
+        let config = Config {
+            http_port: 4242,
+            udp_port_1: 4343,
+            udp_port_2: 4444,
+            probe_signing: true,
+            probe_signing_key: "super-secret-signing-key".to_string(),
+            ..Default::default()
+        };
+
+        capture::start();
+        log_startup_banner(&config);
+        let records = capture::take();
+        assert_eq!(records.len(), 1);
+
+        let (level, banner) = &records[0];
+        assert_eq!(*level, Level::Info);
+        assert!(banner.starts_with(&format!("Pocket Relay QoS v{}", env!("CARGO_PKG_VERSION"))));
+        for line in [
+            "HTTP port: 4242",
+            "QoS UDP port: 4343",
+            "Firewall UDP port: 4444",
+            "Bind address: 0.0.0.0",
+            "Public address source: ",
+            "Probe signing: enabled",
+            "Maintenance mode: disabled",
+            "Log level: TRACE",
+        ] {
+            assert!(
+                banner.contains(&format!("\n\t{}", line)),
+                "missing {}",
+                line
+            );
+        }
+        assert!(!banner.contains("super-secret-signing-key"));
+    }
 }
@@ -1,25 +1,153 @@
-use config::load_config;
-use service::QService;
-use std::sync::Arc;
-
-mod config;
-mod firewall;
-mod http;
-mod logging;
-mod service;
-mod udp;
+use clap::Parser;
+use log::{error, warn};
+use pocket_relay_qos::{
+    args::{Args, ConfigFormat},
+    blocklist::BlocklistChecker,
+    buffer_limit,
+    config::{load_config, Config},
+    diagnostics, firewall, http, logging, metrics, pcap, rate_limit,
+    service::{self, QService},
+    session_event_log,
+    session_store::InMemorySessionStore,
+    shutdown, udp,
+    udp_runtime::UdpRuntime,
+    warmup, webhook,
+};
+use std::{future::Future, io, sync::Arc, time::Duration};
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
+    if args.print_default_config {
+        let config = Config::default();
+        match args.format {
+            ConfigFormat::Json => println!("{}", config.to_json_pretty()),
+            ConfigFormat::Toml => println!("{}", config.to_toml_pretty()),
+        }
+        return;
+    }
+
+    if let Some(port) = args.wait_for_port {
+        let timeout = Duration::from_secs(args.wait_timeout);
+        match diagnostics::wait_for_port(port, timeout).await {
+            Ok(()) => println!("Port {} is accepting connections", port),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     std::env::set_var("RUST_LOG", "trace");
 
-    logging::setup();
+    let log_handle = logging::setup();
+
+    let config = match load_config(args.config.as_deref(), &args.overrides).await {
+        Ok(value) => Arc::new(value),
+        Err(err) => {
+            error!("Failed to load config: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if config.use_syslog {
+        logging::use_syslog(&log_handle, &config);
+    }
+
+    logging::log_startup_banner(&config);
+
+    if let Err(errors) = config.validate() {
+        for err in errors {
+            error!("Invalid config: {}", err);
+        }
+        std::process::exit(1);
+    }
+
+    metrics::REQUESTS_BY_VERSION.set_max_labels(config.metrics_max_version_labels);
+    buffer_limit::set_max_buffered_bytes(config.max_buffered_bytes);
+    rate_limit::init(&config);
+    webhook::init(&config);
+
+    if let Some(path) = &config.probe_log_path {
+        if let Err(err) = session_event_log::init(path).await {
+            error!(
+                "Failed to open probe arrival log {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
 
-    let config = Arc::new(load_config().await);
+    if let Some(path) = &config.pcap_output {
+        match pcap::init(path).await {
+            Ok(_) => warn!("Capturing QoS packets to {}", path.display()),
+            Err(err) => error!("Failed to create pcap capture {}: {}", path.display(), err),
+        }
+    }
 
-    let service = Arc::new(QService::default());
+    if let Some(mtu) = config.response_mtu {
+        warn!(
+            "QoS responses will be truncated to {} bytes, this is only intended for testing",
+            mtu
+        );
+    }
+
+    if config.probe_signing && config.probe_signing_key.is_empty() {
+        warn!("Probe signing is enabled without a signing key, any client can sign probes");
+    }
+
+    if config.maintenance_mode {
+        warn!("Maintenance mode is enabled, QoS requests will not be served");
+    }
+
+    let service = Arc::new(QService::new(
+        &config,
+        Box::new(InMemorySessionStore::default()),
+    ));
+    let blocklist = Arc::new(BlocklistChecker::new(&config));
+
+    if config.udp_reachability_check {
+        tokio::spawn(diagnostics::check_udp_reachability(config.clone()));
+    }
+
+    tokio::spawn(shutdown::listen());
+    tokio::spawn(warmup::run(config.clone()));
+    tokio::spawn(service::start_pruning(service.clone(), config.clone()));
+    tokio::spawn(http::start_server(
+        service.clone(),
+        config.clone(),
+        blocklist.clone(),
+    ));
+
+    let firewall_server = run_udp_server(
+        "FireWall",
+        config.udp_port_2,
+        firewall::start_server(service.clone(), config.clone(), blocklist.clone()),
+    );
+    let qos_server = run_udp_server(
+        "QoS",
+        config.udp_port_1,
+        udp::start_server(service, config.clone(), blocklist),
+    );
+
+    let runtime = match UdpRuntime::new(config.udp_runtime_threads) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to create UDP runtime: {}", err);
+            return;
+        }
+    };
+
+    runtime.run(firewall_server, qos_server).await;
+}
 
-    tokio::spawn(http::start_server(service.clone(), config.clone()));
-    tokio::spawn(firewall::start_server(service.clone(), config.clone()));
-    udp::start_server(service, config).await;
+/// Runs the UDP `server`, exiting the process with a failure code if the
+/// server couldn't be started (i.e. its `port` is already in use)
+async fn run_udp_server(name: &str, port: u16, server: impl Future<Output = io::Result<()>>) {
+    if let Err(err) = server.await {
+        error!("Failed to start {} server on port {}: {}", name, port, err);
+        std::process::exit(1);
+    }
 }
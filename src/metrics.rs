@@ -0,0 +1,560 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Monotonically increasing counter metric
+pub struct Counter {
+    /// The current counter value
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self {
+            value: AtomicU64::new(0),
+        }
+    }
+
+    /// Increments the counter by one
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increments the counter by the provided `value`
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Gets the current counter value
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Resets the counter back to zero
+    pub fn reset(&self) {
+        self.value.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Gauge metric for values that can go up and down. The value is signed
+/// so that a negative value exposes mismatched increments and decrements
+pub struct Gauge {
+    /// The current gauge value
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self {
+            value: AtomicI64::new(0),
+        }
+    }
+
+    /// Increments the gauge by one
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrements the gauge by one
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge by the provided `value`
+    pub fn add(&self, value: i64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Decrements the gauge by the provided `value`
+    pub fn sub(&self, value: i64) {
+        self.value.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    /// Gets the current gauge value
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Default maximum number of distinct versions tracked by a [VersionCounter]
+pub const DEFAULT_MAX_VERSION_LABELS: usize = 16;
+
+/// Counter metric labeled by client protocol version. The number of distinct
+/// versions is bounded so that clients sending arbitrary versions can't grow
+/// the metrics without limit, versions beyond the limit are counted together
+pub struct VersionCounter {
+    /// Counts for each tracked version
+    values: Mutex<BTreeMap<u32, u64>>,
+    /// Count for versions that weren't tracked due to the limit
+    other: AtomicU64,
+    /// Maximum number of distinct versions to track
+    max_labels: AtomicUsize,
+}
+
+impl VersionCounter {
+    pub const fn new() -> Self {
+        Self {
+            values: Mutex::new(BTreeMap::new()),
+            other: AtomicU64::new(0),
+            max_labels: AtomicUsize::new(DEFAULT_MAX_VERSION_LABELS),
+        }
+    }
+
+    /// Sets the maximum number of distinct versions to track
+    pub fn set_max_labels(&self, max_labels: usize) {
+        self.max_labels.store(max_labels, Ordering::Relaxed);
+    }
+
+    /// Increments the counter for the provided `version`
+    pub fn inc(&self, version: u32) {
+        let values = &mut *self.values.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(value) = values.get_mut(&version) {
+            *value += 1;
+        } else if values.len() < self.max_labels.load(Ordering::Relaxed) {
+            values.insert(version, 1);
+        } else {
+            self.other.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Gets the count for the provided `version`, versions that aren't
+    /// tracked due to the limit are counted in [VersionCounter::get_other]
+    pub fn get(&self, version: u32) -> u64 {
+        let values = &*self.values.lock().unwrap_or_else(|err| err.into_inner());
+        values.get(&version).copied().unwrap_or_default()
+    }
+
+    /// Gets the count for versions that weren't tracked due to the limit
+    pub fn get_other(&self) -> u64 {
+        self.other.load(Ordering::Relaxed)
+    }
+
+    /// Resets the counter removing all the tracked versions
+    pub fn reset(&self) {
+        self.values
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+        self.other.store(0, Ordering::Relaxed);
+    }
+
+    /// Writes the counter in the Prometheus text exposition format
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {} {}", name, help);
+        _ = writeln!(out, "# TYPE {} counter", name);
+
+        let values = &*self.values.lock().unwrap_or_else(|err| err.into_inner());
+        for (version, value) in values {
+            _ = writeln!(out, "{}{{vers=\"{}\"}} {}", name, version, value);
+        }
+        _ = writeln!(
+            out,
+            "{}{{vers=\"other\"}} {}",
+            name,
+            self.other.load(Ordering::Relaxed)
+        );
+    }
+}
+
+impl Default for VersionCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Upper bounds in seconds of the [DurationHistogram] buckets
+const DURATION_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Observations recorded for a single operation of a [DurationHistogram]
+#[derive(Default)]
+struct DurationValues {
+    /// Number of observations within each bucket, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    /// Total number of observations
+    count: u64,
+    /// Sum of all the observed durations in seconds
+    sum: f64,
+}
+
+/// Histogram metric of durations labeled by the operation they measure
+pub struct DurationHistogram {
+    /// Observations for each operation
+    values: Mutex<BTreeMap<&'static str, DurationValues>>,
+}
+
+impl DurationHistogram {
+    pub const fn new() -> Self {
+        Self {
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a duration of `seconds` for the provided `operation`
+    pub fn observe(&self, operation: &'static str, seconds: f64) {
+        let values = &mut *self.values.lock().unwrap_or_else(|err| err.into_inner());
+        let value = values.entry(operation).or_default();
+
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            value.buckets[bucket] += 1;
+        }
+        value.count += 1;
+        value.sum += seconds;
+    }
+
+    /// Resets the histogram removing all the observations
+    pub fn reset(&self) {
+        self.values
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+    }
+
+    /// Writes the histogram in the Prometheus text exposition format
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {} {}", name, help);
+        _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let values = &*self.values.lock().unwrap_or_else(|err| err.into_inner());
+        for (operation, value) in values {
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(value.buckets) {
+                cumulative += count;
+                _ = writeln!(
+                    out,
+                    "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}",
+                    name, operation, bound, cumulative
+                );
+            }
+            _ = writeln!(
+                out,
+                "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}",
+                name, operation, value.count
+            );
+            _ = writeln!(
+                out,
+                "{}_sum{{operation=\"{}\"}} {}",
+                name, operation, value.sum
+            );
+            _ = writeln!(
+                out,
+                "{}_count{{operation=\"{}\"}} {}",
+                name, operation, value.count
+            );
+        }
+    }
+}
+
+impl Default for DurationHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Timer recording the time from its creation until it's dropped
+/// in [HANDLER_DURATION] under its operation
+pub struct QosTimer {
+    /// Time the timer was started
+    start: Instant,
+    /// Name of the operation being timed
+    operation: &'static str,
+}
+
+impl QosTimer {
+    /// Starts a timer for the provided `operation`
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            start: Instant::now(),
+            operation,
+        }
+    }
+}
+
+impl Drop for QosTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        HANDLER_DURATION.observe(self.operation, elapsed.as_secs_f64());
+    }
+}
+
+/// Number of probes dropped because their flow exceeded the maximum lifetime
+pub static EXPIRED_FLOW_PROBES: Counter = Counter::new();
+
+/// Number of latency flows that received all their probes
+pub static FLOWS_COMPLETE: Counter = Counter::new();
+/// Number of latency flows that were missing probes when cleaned up
+pub static FLOWS_INCOMPLETE: Counter = Counter::new();
+/// Total number of probes that never arrived across all latency flows
+pub static FLOW_PROBES_LOST: Counter = Counter::new();
+
+/// Number of zero-length UDP datagrams received
+pub static EMPTY_DATAGRAMS: Counter = Counter::new();
+
+/// Number of QoS probes received with an already received probe number
+pub static DUPLICATE_PROBES: Counter = Counter::new();
+
+/// Number of QoS probes received on a different port than their flow expected
+pub static PROBE_PORT_MISMATCHES: Counter = Counter::new();
+
+/// Number of QoS probes rejected for having an invalid signature
+pub static INVALID_PROBE_SIGNATURES: Counter = Counter::new();
+
+/// Number of QoS probes dropped for exceeding the client rate limit
+pub static RATE_LIMITED_PROBES: Counter = Counter::new();
+
+/// Number of UDP messages dropped while shutting down
+pub static SHUTDOWN_DROPPED: Counter = Counter::new();
+
+/// Number of firewall probes dropped because their channel was full
+pub static FIREWALL_CHANNEL_DROPPED_NEWEST: Counter = Counter::new();
+/// Number of queued firewall probes dropped to make room for newer probes
+pub static FIREWALL_CHANNEL_DROPPED_OLDEST: Counter = Counter::new();
+/// Number of firewall probes that waited for room in a full channel
+pub static FIREWALL_CHANNEL_BLOCKED: Counter = Counter::new();
+/// Number of firewall probes rejected for exceeding the buffered bytes limit
+pub static BUFFER_LIMIT_REJECTED: Counter = Counter::new();
+
+/// Number of QoS HTTP requests by the client protocol version
+pub static REQUESTS_BY_VERSION: VersionCounter = VersionCounter::new();
+
+/// Time taken to handle UDP messages by the handling operation
+pub static HANDLER_DURATION: DurationHistogram = DurationHistogram::new();
+
+/// Counters that are reset by [reset_counters], gauges aren't included
+/// as they track current state rather than events
+static RESETTABLE_COUNTERS: [&Counter; 14] = [
+    &EXPIRED_FLOW_PROBES,
+    &FLOWS_COMPLETE,
+    &FLOWS_INCOMPLETE,
+    &FLOW_PROBES_LOST,
+    &EMPTY_DATAGRAMS,
+    &DUPLICATE_PROBES,
+    &PROBE_PORT_MISMATCHES,
+    &INVALID_PROBE_SIGNATURES,
+    &RATE_LIMITED_PROBES,
+    &SHUTDOWN_DROPPED,
+    &FIREWALL_CHANNEL_DROPPED_NEWEST,
+    &FIREWALL_CHANNEL_DROPPED_OLDEST,
+    &FIREWALL_CHANNEL_BLOCKED,
+    &BUFFER_LIMIT_REJECTED,
+];
+
+/// Lock held by tests depending on the counter values, shared by tests
+/// comparing counters and exclusive to tests resetting them
+#[cfg(test)]
+pub static TEST_LOCK: tokio::sync::RwLock<()> = tokio::sync::RwLock::const_new(());
+
+/// Resets all the counters back to zero
+pub fn reset_counters() {
+    for counter in RESETTABLE_COUNTERS {
+        counter.reset();
+    }
+    REQUESTS_BY_VERSION.reset();
+    HANDLER_DURATION.reset();
+}
+
+/// Number of active QoS latency sessions
+pub static QOS_ACTIVE_SESSIONS: Gauge = Gauge::new();
+/// Number of active firewall sessions
+pub static FIREWALL_ACTIVE_SESSIONS: Gauge = Gauge::new();
+/// Number of bytes buffered across all firewall sessions
+pub static BUFFERED_BYTES: Gauge = Gauge::new();
+
+/// Writes a single metric in the Prometheus text exposition format
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: impl Display) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} {}", name, kind);
+    _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Renders all the metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        "qos_expired_flow_probes_total",
+        "Probes dropped because their flow exceeded the maximum lifetime",
+        "counter",
+        EXPIRED_FLOW_PROBES.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_flows_complete_total",
+        "Latency flows that received all their probes",
+        "counter",
+        FLOWS_COMPLETE.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_flows_incomplete_total",
+        "Latency flows that were missing probes when cleaned up",
+        "counter",
+        FLOWS_INCOMPLETE.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_flow_probes_lost_total",
+        "Probes that never arrived across all latency flows",
+        "counter",
+        FLOW_PROBES_LOST.get(),
+    );
+    write_metric(
+        &mut out,
+        "udp_empty_datagrams_total",
+        "Zero-length UDP datagrams received",
+        "counter",
+        EMPTY_DATAGRAMS.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_duplicate_probes_total",
+        "QoS probes received with an already received probe number",
+        "counter",
+        DUPLICATE_PROBES.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_probe_port_mismatches_total",
+        "QoS probes received on a different port than their flow expected",
+        "counter",
+        PROBE_PORT_MISMATCHES.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_invalid_probe_signatures_total",
+        "QoS probes rejected for having an invalid signature",
+        "counter",
+        INVALID_PROBE_SIGNATURES.get(),
+    );
+    write_metric(
+        &mut out,
+        "qos_rate_limited_probes_total",
+        "QoS probes dropped for exceeding the client rate limit",
+        "counter",
+        RATE_LIMITED_PROBES.get(),
+    );
+    write_metric(
+        &mut out,
+        "udp_shutdown_dropped_total",
+        "UDP messages dropped while shutting down",
+        "counter",
+        SHUTDOWN_DROPPED.get(),
+    );
+    write_metric(
+        &mut out,
+        "firewall_channel_dropped_newest_total",
+        "Firewall probes dropped because their channel was full",
+        "counter",
+        FIREWALL_CHANNEL_DROPPED_NEWEST.get(),
+    );
+    write_metric(
+        &mut out,
+        "firewall_channel_dropped_oldest_total",
+        "Queued firewall probes dropped to make room for newer probes",
+        "counter",
+        FIREWALL_CHANNEL_DROPPED_OLDEST.get(),
+    );
+    write_metric(
+        &mut out,
+        "firewall_channel_blocked_total",
+        "Firewall probes that waited for room in a full channel",
+        "counter",
+        FIREWALL_CHANNEL_BLOCKED.get(),
+    );
+    write_metric(
+        &mut out,
+        "buffer_limit_rejected_total",
+        "Firewall probes rejected for exceeding the buffered bytes limit",
+        "counter",
+        BUFFER_LIMIT_REJECTED.get(),
+    );
+    REQUESTS_BY_VERSION.write(
+        &mut out,
+        "qos_requests_by_version_total",
+        "QoS HTTP requests by client protocol version",
+    );
+    HANDLER_DURATION.write(
+        &mut out,
+        "udp_handler_duration_seconds",
+        "Time taken to handle UDP messages by operation",
+    );
+    write_metric(
+        &mut out,
+        "qos_active_sessions",
+        "Number of active QoS latency sessions",
+        "gauge",
+        QOS_ACTIVE_SESSIONS.get(),
+    );
+    write_metric(
+        &mut out,
+        "firewall_active_sessions",
+        "Number of active firewall sessions",
+        "gauge",
+        FIREWALL_ACTIVE_SESSIONS.get(),
+    );
+    write_metric(
+        &mut out,
+        "buffered_bytes",
+        "Number of bytes buffered across all firewall sessions",
+        "gauge",
+        BUFFERED_BYTES.get(),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::VersionCounter;
+
+    /// Requests are counted by their version until the label limit is
+    /// reached, after which new versions are counted together
+    #[test]
+    fn version_counts_bounded() {
+        let counter = VersionCounter::new();
+        counter.set_max_labels(2);
+
+        counter.inc(1);
+        counter.inc(2);
+        counter.inc(2);
+        assert_eq!(counter.get(1), 1);
+        assert_eq!(counter.get(2), 2);
+
+        // Versions beyond the limit share the other count
+        counter.inc(3);
+        counter.inc(4);
+        counter.inc(1);
+        assert_eq!(counter.get(3), 0);
+        assert_eq!(counter.get(4), 0);
+        assert_eq!(counter.get_other(), 2);
+        assert_eq!(counter.get(1), 2);
+
+        let mut out = String::new();
+        counter.write(&mut out, "requests", "Requests");
+        assert!(out.contains("requests{vers=\"1\"} 2\n"), "{}", out);
+        assert!(out.contains("requests{vers=\"2\"} 2\n"), "{}", out);
+        assert!(out.contains("requests{vers=\"other\"} 2\n"), "{}", out);
+
+        counter.reset();
+        assert_eq!(counter.get(1), 0);
+        assert_eq!(counter.get_other(), 0);
+    }
+}
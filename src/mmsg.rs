@@ -0,0 +1,124 @@
+//! Batch receiving of UDP messages using `recvmmsg` on Linux, receiving
+//! up to [BATCH_SIZE] messages with a single syscall instead of one
+//! syscall for each message
+
+use std::{
+    io::{self, IoSliceMut},
+    net::{SocketAddr, SocketAddrV4},
+    os::fd::AsRawFd,
+};
+
+use bytes::BytesMut;
+use nix::sys::socket::{recvmmsg, MsgFlags, MultiHeaders, SockaddrIn};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Maximum number of messages received in a single batch
+pub const BATCH_SIZE: usize = 64;
+
+/// Size of the buffer for each message in the batch
+const BUFFER_SIZE: usize = 65536 /* UDP allocated buffer size */;
+
+/// Receiver holding the buffers reused across batches
+pub struct BatchReceiver {
+    /// Buffers for the message contents, [BUFFER_SIZE] bytes for each message
+    buffers: Vec<u8>,
+}
+
+impl BatchReceiver {
+    pub fn new() -> Self {
+        Self {
+            buffers: vec![0; BATCH_SIZE * BUFFER_SIZE],
+        }
+    }
+
+    /// Waits for the `socket` to become readable then receives all the
+    /// available messages up to the batch size along with their senders
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<Vec<(BytesMut, SocketAddr)>> {
+        loop {
+            socket.readable().await?;
+
+            match socket.try_io(Interest::READABLE, || self.recv_batch(socket)) {
+                Ok(messages) => return Ok(messages),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Receives a batch of messages without blocking
+    fn recv_batch(&mut self, socket: &UdpSocket) -> io::Result<Vec<(BytesMut, SocketAddr)>> {
+        // Headers hold raw pointers so they aren't Send and can't be kept
+        // across the awaits in recv
+        let mut headers = MultiHeaders::<SockaddrIn>::preallocate(BATCH_SIZE, None);
+        let mut slices: Vec<[IoSliceMut; 1]> = self
+            .buffers
+            .chunks_exact_mut(BUFFER_SIZE)
+            .map(|buffer| [IoSliceMut::new(buffer)])
+            .collect();
+
+        // Lengths and senders are collected first as the results borrow the buffers
+        let received: Vec<(usize, Option<SockaddrIn>)> = recvmmsg(
+            socket.as_raw_fd(),
+            &mut headers,
+            slices.iter_mut(),
+            MsgFlags::MSG_DONTWAIT,
+            None,
+        )?
+        .map(|message| (message.bytes, message.address))
+        .collect();
+
+        let messages = received
+            .into_iter()
+            .zip(self.buffers.chunks_exact(BUFFER_SIZE))
+            .filter_map(|((length, addr), buffer)| {
+                let addr = SocketAddr::V4(SocketAddrV4::from(addr?));
+                Some((BytesMut::from(&buffer[..length]), addr))
+            })
+            .collect();
+
+        Ok(messages)
+    }
+}
+
+impl Default for BatchReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{net::UdpSocket, time};
+
+    use super::BatchReceiver;
+
+    /// Messages waiting on the socket are all received in a single batch
+    #[tokio::test]
+    async fn receives_batch_of_messages() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = socket.local_addr().unwrap();
+
+        let sent: [&[u8]; 3] = [b"first", b"second message", b"third"];
+        for message in sent {
+            sender.send_to(message, target).await.unwrap();
+        }
+
+        // Give loopback delivery time to queue every message
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut receiver = BatchReceiver::new();
+        let messages = time::timeout(Duration::from_secs(1), receiver.recv(&socket))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let received: Vec<&[u8]> = messages.iter().map(|(buffer, _)| buffer.as_ref()).collect();
+        assert_eq!(received, sent);
+        assert!(messages
+            .iter()
+            .all(|(_, addr)| *addr == sender.local_addr().unwrap()));
+    }
+}
@@ -0,0 +1,227 @@
+//! Writer for capturing the exchanged QoS packets in the pcap format
+//! so they can be inspected with tools like Wireshark. Packets are
+//! written with synthesized IPv4 and UDP headers
+
+use std::{
+    net::SocketAddrV4,
+    path::Path,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use log::error;
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+/// Magic number for microsecond precision pcap files
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// Link type for raw IP packets without a link layer header
+const LINKTYPE_RAW: u32 = 101;
+/// Maximum length of a captured packet
+const SNAP_LEN: u32 = 65535;
+/// IP protocol number for UDP
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// The pcap capture file if capturing is enabled
+static PCAP_FILE: OnceLock<Mutex<PcapWriter>> = OnceLock::new();
+
+/// Creates the pcap capture file at the provided `path` writing the
+/// pcap global header, replaces any existing file
+pub async fn init(path: &Path) -> std::io::Result<()> {
+    let writer = PcapWriter::create(path).await?;
+    _ = PCAP_FILE.set(Mutex::new(writer));
+    Ok(())
+}
+
+/// Checks whether pcap capturing is enabled
+pub fn is_enabled() -> bool {
+    PCAP_FILE.get().is_some()
+}
+
+/// Records a UDP packet sent from `src` to `dst` with the provided
+/// `payload`, does nothing if capturing is not enabled
+pub async fn record(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+    let writer = match PCAP_FILE.get() {
+        Some(value) => value,
+        None => return,
+    };
+
+    let writer = &mut *writer.lock().await;
+    if let Err(err) = writer.write(src, dst, payload).await {
+        error!("Failed to write to pcap capture: {}", err);
+    }
+}
+
+/// Writer for a pcap capture file
+struct PcapWriter {
+    /// The underlying capture file
+    file: File,
+}
+
+impl PcapWriter {
+    /// Creates the capture file at the provided `path` writing the
+    /// pcap global header, replaces any existing file
+    async fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = File::create(path).await?;
+
+        let mut header = BytesMut::with_capacity(24);
+        header.put_u32_le(PCAP_MAGIC);
+        // Format version 2.4
+        header.put_u16_le(2);
+        header.put_u16_le(4);
+        // Timezone offset and timestamp accuracy
+        header.put_i32_le(0);
+        header.put_u32_le(0);
+        header.put_u32_le(SNAP_LEN);
+        header.put_u32_le(LINKTYPE_RAW);
+        file.write_all(&header).await?;
+        file.flush().await?;
+
+        Ok(Self { file })
+    }
+
+    /// Writes a record for a UDP packet sent from `src` to `dst`
+    /// with the provided `payload`
+    async fn write(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let packet = udp_packet(src, dst, payload);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut out = BytesMut::with_capacity(16 + packet.len());
+        out.put_u32_le(time.as_secs() as u32);
+        out.put_u32_le(time.subsec_micros());
+        // Captured and original length
+        out.put_u32_le(packet.len() as u32);
+        out.put_u32_le(packet.len() as u32);
+        out.extend_from_slice(&packet);
+
+        self.file.write_all(&out).await
+    }
+}
+
+/// Synthesizes an IPv4 packet containing a UDP datagram
+fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> BytesMut {
+    let udp_length = 8 + payload.len();
+    let total_length = 20 + udp_length;
+
+    let mut out = BytesMut::with_capacity(total_length);
+
+    // IPv4 header
+    out.put_u8(0x45 /* Version 4, 5 word header */);
+    out.put_u8(0);
+    out.put_u16(total_length as u16);
+    // Identification, flags and fragment offset
+    out.put_u16(0);
+    out.put_u16(0);
+    out.put_u8(64 /* TTL */);
+    out.put_u8(IP_PROTOCOL_UDP);
+    // Checksum is filled in after the header is written
+    out.put_u16(0);
+    out.extend_from_slice(&src.ip().octets());
+    out.extend_from_slice(&dst.ip().octets());
+
+    let checksum = ipv4_checksum(&out[..20]);
+    out[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // UDP header, a zero checksum means no checksum for IPv4
+    out.put_u16(src.port());
+    out.put_u16(dst.port());
+    out.put_u16(udp_length as u16);
+    out.put_u16(0);
+
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Computes the ones complement checksum of an IPv4 header
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]) as u32)
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::{ipv4_checksum, PcapWriter, LINKTYPE_RAW, PCAP_MAGIC, SNAP_LEN};
+
+    fn u16_le(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_le(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Capture files start with the pcap global header followed by a
+    /// record for each packet containing the synthesized IPv4 and UDP headers
+    #[tokio::test]
+    async fn capture_file_layout() {
+        let path = std::env::temp_dir().join(format!("qos-capture-{}.pcap", std::process::id()));
+        let src = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 3659);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 2), 17499);
+        let payload = [1, 2, 3, 4, 5];
+
+        let mut writer = PcapWriter::create(&path).await.unwrap();
+        writer.write(src, dst, &payload).await.unwrap();
+        writer.file.flush().await.unwrap();
+        drop(writer);
+
+        let bytes = std::fs::read(&path).unwrap();
+        _ = std::fs::remove_file(&path);
+
+        // Global header
+        assert_eq!(u32_le(&bytes, 0), PCAP_MAGIC);
+        assert_eq!((u16_le(&bytes, 4), u16_le(&bytes, 6)), (2, 4));
+        assert_eq!(u32_le(&bytes, 8), 0);
+        assert_eq!(u32_le(&bytes, 12), 0);
+        assert_eq!(u32_le(&bytes, 16), SNAP_LEN);
+        assert_eq!(u32_le(&bytes, 20), LINKTYPE_RAW);
+
+        // Record header
+        let record = &bytes[24..];
+        let packet_length = 20 + 8 + payload.len();
+        assert!(u32_le(record, 0) > 0);
+        assert!(u32_le(record, 4) < 1_000_000);
+        assert_eq!(u32_le(record, 8) as usize, packet_length);
+        assert_eq!(u32_le(record, 12) as usize, packet_length);
+
+        // IPv4 header
+        let packet = &record[16..];
+        assert_eq!(packet.len(), packet_length);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(
+            u16::from_be_bytes([packet[2], packet[3]]) as usize,
+            packet_length
+        );
+        assert_eq!(packet[9], 17);
+        assert_eq!(&packet[12..16], &src.ip().octets());
+        assert_eq!(&packet[16..20], &dst.ip().octets());
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+
+        // UDP header and payload
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), src.port());
+        assert_eq!(u16::from_be_bytes([packet[22], packet[23]]), dst.port());
+        assert_eq!(
+            u16::from_be_bytes([packet[24], packet[25]]) as usize,
+            8 + payload.len()
+        );
+        assert_eq!(&packet[28..], &payload);
+    }
+}
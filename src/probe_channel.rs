@@ -0,0 +1,340 @@
+//! Bounded channel carrying the addresses of firewall probes from the
+//! firewall server to the firetype handler waiting on them.
+//!
+//! When the firetype handler falls behind the incoming probes the channel
+//! fills up and the configured [BackpressurePolicy] decides which probes
+//! are shed, each policy records when it was triggered in the metrics.
+//! Queued probes also count towards the global [buffer_limit]
+
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time};
+
+use crate::{buffer_limit, metrics};
+
+/// Number of bytes accounted for each queued probe
+pub const PROBE_BYTES: usize = size_of::<SocketAddr>();
+
+/// Policy applied when sending to a full channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// Drop the probe being sent
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued probe to make room
+    DropOldest,
+    /// Wait briefly for room before dropping the probe being sent
+    BlockBriefly,
+}
+
+/// Error returned when a probe could not be sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiver has been dropped
+    Closed,
+    /// The channel was full and the probe was dropped
+    Full,
+    /// The buffered bytes limit was reached and the probe was dropped
+    BufferLimit,
+}
+
+/// State shared between the sender and receiver
+struct Shared {
+    /// Queued probe addresses
+    queue: Mutex<VecDeque<SocketAddr>>,
+    /// Maximum number of queued probes
+    capacity: usize,
+    /// Policy to apply when the queue is full
+    policy: BackpressurePolicy,
+    /// Time to wait for room when using [BackpressurePolicy::BlockBriefly]
+    block_timeout: Duration,
+    /// Number of live senders
+    senders: AtomicUsize,
+    /// Whether the receiver has been dropped
+    closed: AtomicBool,
+    /// Notified when a probe is queued or the last sender is dropped
+    item_ready: Notify,
+    /// Notified when a probe is taken from the queue
+    space_ready: Notify,
+}
+
+impl Shared {
+    /// Locks the queue, a panic while the queue was locked can't leave
+    /// it in an invalid state so a poisoned lock is recovered rather than
+    /// failing every later send and receive
+    fn lock_queue(&self) -> MutexGuard<'_, VecDeque<SocketAddr>> {
+        self.queue.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Probes that were never received are no longer buffered
+        let queued = self.lock_queue().len();
+        buffer_limit::release(queued * PROBE_BYTES);
+    }
+}
+
+/// Creates a new channel holding at most `capacity` probes
+pub fn channel(
+    capacity: usize,
+    policy: BackpressurePolicy,
+    block_timeout: Duration,
+) -> (ProbeSender, ProbeReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        policy,
+        block_timeout,
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        item_ready: Notify::new(),
+        space_ready: Notify::new(),
+    });
+
+    (
+        ProbeSender {
+            shared: shared.clone(),
+        },
+        ProbeReceiver { shared },
+    )
+}
+
+/// Sending half of a probe channel
+pub struct ProbeSender {
+    shared: Arc<Shared>,
+}
+
+impl ProbeSender {
+    /// Sends the provided probe `addr` applying the backpressure
+    /// policy if the channel is full
+    pub async fn send(&self, addr: SocketAddr) -> Result<(), SendError> {
+        let shared = &*self.shared;
+
+        if self.try_push(addr)? {
+            return Ok(());
+        }
+
+        match shared.policy {
+            BackpressurePolicy::BlockBriefly => {
+                metrics::FIREWALL_CHANNEL_BLOCKED.inc();
+
+                // Wait for the receiver to make room, giving up after the timeout
+                let wait = async {
+                    loop {
+                        let space = shared.space_ready.notified();
+                        if self.try_push(addr)? {
+                            return Ok(());
+                        }
+                        space.await;
+                    }
+                };
+
+                match time::timeout(shared.block_timeout, wait).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.inc();
+                        Err(SendError::Full)
+                    }
+                }
+            }
+            BackpressurePolicy::DropOldest => {
+                {
+                    let queue = &mut *shared.lock_queue();
+                    queue.pop_front();
+                    queue.push_back(addr);
+                }
+
+                metrics::FIREWALL_CHANNEL_DROPPED_OLDEST.inc();
+                shared.item_ready.notify_one();
+                Ok(())
+            }
+            BackpressurePolicy::DropNewest => {
+                metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.inc();
+                Err(SendError::Full)
+            }
+        }
+    }
+
+    /// Queues the `addr` if there's room, returns whether it was queued
+    fn try_push(&self, addr: SocketAddr) -> Result<bool, SendError> {
+        let shared = &*self.shared;
+        if shared.closed.load(Ordering::Acquire) {
+            return Err(SendError::Closed);
+        }
+
+        {
+            let queue = &mut *shared.lock_queue();
+            if queue.len() >= shared.capacity {
+                return Ok(false);
+            }
+            if !buffer_limit::try_reserve(PROBE_BYTES) {
+                return Err(SendError::BufferLimit);
+            }
+            queue.push_back(addr);
+        }
+
+        shared.item_ready.notify_one();
+        Ok(true)
+    }
+}
+
+impl Clone for ProbeSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for ProbeSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Wake the receiver so it can observe the channel closing
+            self.shared.item_ready.notify_one();
+        }
+    }
+}
+
+/// Receiving half of a probe channel
+pub struct ProbeReceiver {
+    shared: Arc<Shared>,
+}
+
+impl ProbeReceiver {
+    /// Receives the next probe address, returns [None] once the
+    /// queue is empty and all the senders have been dropped
+    pub async fn recv(&mut self) -> Option<SocketAddr> {
+        let shared = &*self.shared;
+        loop {
+            let ready = shared.item_ready.notified();
+
+            let next = shared.lock_queue().pop_front();
+
+            if let Some(addr) = next {
+                buffer_limit::release(PROBE_BYTES);
+                shared.space_ready.notify_one();
+                return Some(addr);
+            }
+
+            if shared.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+
+            ready.await;
+        }
+    }
+}
+
+impl Drop for ProbeReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.space_ready.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{channel, BackpressurePolicy, ProbeReceiver, SendError};
+    use crate::metrics;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Receives everything left in the channel once the senders are dropped
+    async fn drain(mut rx: ProbeReceiver) -> Vec<u16> {
+        let mut ports = Vec::new();
+        while let Some(addr) = rx.recv().await {
+            ports.push(addr.port());
+        }
+        ports
+    }
+
+    /// Drop newest keeps the queued probes and rejects the new one
+    #[tokio::test]
+    async fn drop_newest_keeps_queued() {
+        let _metrics = metrics::TEST_LOCK.read().await;
+        let (tx, rx) = channel(2, BackpressurePolicy::DropNewest, Duration::ZERO);
+        tx.send(addr(1)).await.unwrap();
+        tx.send(addr(2)).await.unwrap();
+
+        let dropped = metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.get();
+        assert_eq!(tx.send(addr(3)).await, Err(SendError::Full));
+        assert!(metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.get() > dropped);
+
+        drop(tx);
+        assert_eq!(drain(rx).await, [1, 2]);
+    }
+
+    /// Drop oldest makes room for the new probe by dropping the oldest
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() {
+        let _metrics = metrics::TEST_LOCK.read().await;
+        let (tx, rx) = channel(2, BackpressurePolicy::DropOldest, Duration::ZERO);
+        tx.send(addr(1)).await.unwrap();
+        tx.send(addr(2)).await.unwrap();
+
+        let dropped = metrics::FIREWALL_CHANNEL_DROPPED_OLDEST.get();
+        tx.send(addr(3)).await.unwrap();
+        assert!(metrics::FIREWALL_CHANNEL_DROPPED_OLDEST.get() > dropped);
+
+        drop(tx);
+        assert_eq!(drain(rx).await, [2, 3]);
+    }
+
+    /// Block briefly waits for the receiver to make room, dropping the
+    /// new probe if no room is made before the timeout
+    #[tokio::test]
+    async fn block_briefly_waits_for_room() {
+        let _metrics = metrics::TEST_LOCK.read().await;
+        let (tx, mut rx) = channel(
+            2,
+            BackpressurePolicy::BlockBriefly,
+            Duration::from_millis(50),
+        );
+        tx.send(addr(1)).await.unwrap();
+        tx.send(addr(2)).await.unwrap();
+
+        let blocked = metrics::FIREWALL_CHANNEL_BLOCKED.get();
+        let dropped = metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.get();
+        assert_eq!(tx.send(addr(3)).await, Err(SendError::Full));
+        assert!(metrics::FIREWALL_CHANNEL_BLOCKED.get() > blocked);
+        assert!(metrics::FIREWALL_CHANNEL_DROPPED_NEWEST.get() > dropped);
+
+        // Room made while the sender is blocked lets the probe through
+        let blocked = metrics::FIREWALL_CHANNEL_BLOCKED.get();
+        let (sent, received) = tokio::join!(tx.send(addr(4)), async {
+            tokio::task::yield_now().await;
+            rx.recv().await
+        });
+        assert_eq!(sent, Ok(()));
+        assert_eq!(received, Some(addr(1)));
+        assert!(metrics::FIREWALL_CHANNEL_BLOCKED.get() > blocked);
+
+        drop(tx);
+        assert_eq!(drain(rx).await, [2, 4]);
+    }
+
+    /// Sending after the receiver is dropped fails as closed
+    #[tokio::test]
+    async fn send_after_receiver_dropped() {
+        let (tx, rx) = channel(2, BackpressurePolicy::DropNewest, Duration::ZERO);
+        drop(rx);
+        assert_eq!(tx.send(addr(1)).await, Err(SendError::Closed));
+    }
+}
@@ -0,0 +1,111 @@
+//! Per-client rate limiting of QoS probes.
+//!
+//! Each client address has a token bucket that refills at the sustained
+//! rate up to the burst size, a probe takes a token from the bucket of its
+//! sender and is dropped when the bucket is empty. Clients normally send
+//! all their probes in quick succession so the burst size allows a whole
+//! flow through at once while still limiting the sustained rate
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use log::info;
+
+use crate::{config::Config, metrics};
+
+/// Number of tracked clients after which idle clients are forgotten
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Rate limiter shared by the QoS server, unset when rate limiting is disabled
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Token bucket of a single client
+struct Bucket {
+    /// Number of tokens currently available
+    tokens: f64,
+    /// Time the tokens were last refilled
+    updated_at: Instant,
+}
+
+/// Token bucket rate limiter keyed by client address
+struct RateLimiter {
+    /// Buckets for each client
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    /// Tokens added to each bucket per second
+    rate: f64,
+    /// Maximum number of tokens in a bucket
+    capacity: f64,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket of `ip`, returns whether there was a
+    /// token available
+    fn try_acquire(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let buckets = &mut *self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        // Clients whose buckets have refilled are indistinguishable from new clients
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Number of tokens the `bucket` has once refilled up to `now`
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+}
+
+/// Sets up rate limiting from the `config`, does nothing if
+/// rate limiting is disabled
+pub fn init(config: &Config) {
+    let Some(rate) = config.udp_rate_limit_rps else {
+        return;
+    };
+    let capacity = config.probe_burst_size();
+
+    info!(
+        "Rate limiting QoS probes to {} per second with bursts of {}",
+        rate, capacity
+    );
+
+    _ = RATE_LIMITER.set(RateLimiter {
+        buckets: Mutex::new(HashMap::new()),
+        rate: rate as f64,
+        capacity: capacity as f64,
+    });
+}
+
+/// Checks whether a probe from `ip` is within the rate limit, always
+/// allowed when rate limiting is disabled
+pub fn allow(ip: IpAddr) -> bool {
+    let Some(limiter) = RATE_LIMITER.get() else {
+        return true;
+    };
+
+    let allowed = limiter.try_acquire(ip);
+    if !allowed {
+        metrics::RATE_LIMITED_PROBES.inc();
+    }
+    allowed
+}
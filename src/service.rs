@@ -1,109 +1,1351 @@
-use std::{collections::HashMap, net::SocketAddr, sync::atomic::AtomicU32};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use log::{debug, info, warn};
 use rand::{rngs::OsRng, RngCore};
-use tokio::sync::{mpsc, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::{broadcast, Mutex, RwLock};
+
+use crate::{
+    buffer_limit,
+    config::Config,
+    geoip::{self, GeoLookup},
+    http::LATENCY_PROBE_COUNT,
+    identity::{client_identity, ClientIdentityMode},
+    metrics,
+    probe_channel::{self, BackpressurePolicy, ProbeReceiver, ProbeSender, SendError, PROBE_BYTES},
+    session_store::{SessionStore, StoreError},
+    webhook::{self, FlowCompletion},
+};
 
 type RequestId = u32;
 type RequestSecret = u32;
 
-#[derive(Default)]
 pub struct QService {
-    pub m1: RwLock<HashMap<(RequestId, RequestSecret), QRequestData>>,
+    /// Storage for the latency sessions
+    sessions: Box<dyn SessionStore>,
     pub m2: RwLock<HashMap<(RequestId, RequestSecret), QFirewallData>>,
+    /// Number of session IDs allocated so far, the position in the
+    /// session ID sequence which wraps back around to its start
+    next_id: AtomicU64,
+    /// First session ID of the sequence
+    id_start: u32,
+    /// Prefix placed in the upper bits of allocated session IDs
+    id_prefix: u32,
+    /// Log of recently allocated session IDs when enabled
+    id_allocations: Option<Mutex<VecDeque<IdAllocation>>>,
+    /// Settings for the firewall probe channels
+    firewall_channel: FirewallChannelSettings,
+    /// How clients are identified in latency sessions
+    identity_mode: ClientIdentityMode,
+    /// Maximum number of concurrent latency flows for each client
+    max_client_flows: Option<u32>,
+    /// Region label of this server included in flow summaries
+    region: Option<String>,
+    /// How probes with an already received probe number are handled
+    duplicate_probe_policy: DuplicateProbePolicy,
+    /// UDP port latency probes are expected to arrive on
+    qos_port: u16,
+    /// How probes arriving on an unexpected port are handled
+    port_mismatch_policy: PortMismatchPolicy,
+    /// How far ahead of the next expected probe number a probe can be
+    probe_reorder_tolerance: u32,
+    /// Maximum number of attempts at generating a unique session secret
+    secret_collision_retry_limit: u32,
+    /// GeoIP database used to annotate firewall sessions with client regions
+    geo: Option<Box<dyn GeoLookup>>,
+}
+
+/// Handling of probes that reuse a probe number that has
+/// already been received within the same latency flow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateProbePolicy {
+    /// Record and respond to the probe as normal
+    #[default]
+    Respond,
+    /// Drop the probe without responding
+    Ignore,
+    /// Respond to the probe without recording it, logging it as an anomaly
+    Anomaly,
+}
+
+/// Handling of latency probes that arrive on a different
+/// UDP port than the one their flow was created for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortMismatchPolicy {
+    /// Record and respond to the probe as normal
+    #[default]
+    Respond,
+    /// Drop the probe without responding
+    Ignore,
+}
+
+/// Settings used when creating firewall probe channels
+struct FirewallChannelSettings {
+    capacity: usize,
+    policy: BackpressurePolicy,
+    block_timeout: Duration,
+}
+
+/// Number of low bits of a session ID allocated from the ID
+/// sequence, the remaining upper bits are used by the session ID prefix
+pub const SESSION_ID_BITS: u32 = 20;
+/// Largest allowed session ID prefix
+pub const MAX_SESSION_ID_PREFIX: u32 = u32::MAX >> SESSION_ID_BITS;
+/// First session ID of the sequence, lower IDs are reserved
+/// for the address QoS type
+pub const MIN_SESSION_ID: u32 = 2;
+/// Maximum number of entries kept in the ID allocation log
+const ID_ALLOCATION_LOG_CAPACITY: usize = 1024;
+
+/// Type of flow a session ID was allocated for
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowType {
+    Latency,
+    Firewall,
+}
+
+/// Entry in the ID allocation log
+#[derive(Debug, Clone, Serialize)]
+pub struct IdAllocation {
+    /// The allocated session ID
+    pub id: RequestId,
+    /// Type of flow the ID was allocated for
+    pub flow: FlowType,
+    /// Address of the client the ID was allocated for
+    pub client: SocketAddr,
+    /// Time the ID was allocated
+    pub allocated_at: SystemTime,
+}
+
+/// Capacity of the per session event broadcast channel
+const SESSION_EVENT_CAPACITY: usize = 16;
+
+/// Interval between runs of the expired session pruning task
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Background task that periodically removes latency and firewall
+/// sessions that have outlived the configured session TTL
+pub async fn start_pruning(service: Arc<QService>, config: Arc<Config>) {
+    let ttl = Duration::from_secs(config.session_ttl_secs);
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        service.prune_expired_requests(ttl).await;
+        service.prune_expired_firewalls(ttl).await;
+    }
 }
 
-static NEXT_ID: AtomicU32 = AtomicU32::new(2);
+/// Milliseconds from `start` to `end`, zero if `end` is before `start`
+fn millis_between(start: SystemTime, end: SystemTime) -> u64 {
+    end.duration_since(start)
+        .map(|value| value.as_millis() as u64)
+        .unwrap_or_default()
+}
 
 impl QService {
-    pub async fn _get_request_data(
+    /// Creates a new service using the session ID settings from the
+    /// provided `config` storing latency sessions in the `sessions` store
+    pub fn new(config: &Config, sessions: Box<dyn SessionStore>) -> Self {
+        Self {
+            sessions,
+            m2: Default::default(),
+            next_id: AtomicU64::new(0),
+            id_start: config.session_id_start,
+            id_prefix: config.session_id_prefix,
+            id_allocations: config
+                .log_id_allocations
+                .then(|| Mutex::new(VecDeque::new())),
+            firewall_channel: FirewallChannelSettings {
+                capacity: config.firewall_channel_capacity,
+                policy: config.firewall_channel_policy,
+                block_timeout: Duration::from_millis(config.firewall_channel_block_ms),
+            },
+            identity_mode: config.client_identity_mode,
+            max_client_flows: config.max_client_flows,
+            region: config.region.clone(),
+            duplicate_probe_policy: config.duplicate_probe_policy,
+            qos_port: config.udp_port_1,
+            port_mismatch_policy: config.port_mismatch_policy,
+            probe_reorder_tolerance: config.probe_reorder_tolerance,
+            secret_collision_retry_limit: config.secret_collision_retry_limit,
+            geo: geoip::load(config.geoip_database.as_deref()),
+        }
+    }
+
+    /// Replaces the GeoIP database loaded from the config with the `geo` lookup
+    pub fn with_geo_lookup(mut self, geo: Box<dyn GeoLookup>) -> Self {
+        self.geo = Some(geo);
+        self
+    }
+
+    /// Allocates the next session ID for a `flow` from the `client`. The
+    /// sequence wraps back around to its start skipping IDs that are still
+    /// in use, returns [None] when every ID under the prefix is in use
+    async fn next_id(&self, flow: FlowType, client: SocketAddr) -> Option<RequestId> {
+        let range = self.id_range();
+
+        let mut attempts = 0;
+        let id = loop {
+            if attempts == range {
+                return None;
+            }
+            attempts += 1;
+
+            let position = self.next_id.fetch_add(1, Ordering::AcqRel);
+            let id = self.id_at(position);
+
+            // IDs can only still be in use once the sequence has wrapped around
+            if position < range || !self.id_in_use(id).await {
+                break id;
+            }
+        };
+
+        self.log_allocation(id, flow, client).await;
+        Some(id)
+    }
+
+    /// Number of session IDs in the sequence before it wraps around
+    fn id_range(&self) -> u64 {
+        (1u64 << SESSION_ID_BITS) - self.id_start as u64
+    }
+
+    /// Session ID at the `position` in the session ID sequence
+    fn id_at(&self, position: u64) -> RequestId {
+        let id = self.id_start + (position % self.id_range()) as u32;
+        (self.id_prefix << SESSION_ID_BITS) | id
+    }
+
+    /// Records the allocation of the `id` when the allocation log is enabled
+    async fn log_allocation(&self, id: RequestId, flow: FlowType, client: SocketAddr) {
+        if let Some(log) = &self.id_allocations {
+            let log = &mut *log.lock().await;
+            if log.len() >= ID_ALLOCATION_LOG_CAPACITY {
+                log.pop_front();
+            }
+            log.push_back(IdAllocation {
+                id,
+                flow,
+                client,
+                allocated_at: SystemTime::now(),
+            });
+        }
+    }
+
+    /// Checks whether a latency or firewall session is using the `id`
+    async fn id_in_use(&self, id: RequestId) -> bool {
+        if self.sessions.count_where(&|data| data.id == id).await > 0 {
+            return true;
+        }
+
+        self.m2.read().await.keys().any(|(other, _)| *other == id)
+    }
+
+    /// Provides the logged session ID allocations in the order they were
+    /// allocated, returns [None] when the allocation log isn't enabled
+    pub async fn id_allocations(&self) -> Option<Vec<IdAllocation>> {
+        let log = &*self.id_allocations.as_ref()?.lock().await;
+        Some(log.iter().cloned().collect())
+    }
+
+    pub async fn get_request_data(
         &self,
         id: RequestId,
         secret: RequestSecret,
     ) -> Option<QRequestData> {
-        let m1 = &*self.m1.read().await;
-        m1.get(&(id, secret)).cloned()
+        self.sessions.get((id, secret)).await
+    }
+
+    /// Checks whether a latency session exists for the provided
+    /// `id` and `secret` without cloning its data
+    pub async fn session_exists(&self, id: RequestId, secret: RequestSecret) -> bool {
+        self.sessions.contains((id, secret)).await
     }
 
     pub async fn create_request_data(
         &self,
         q_type: u32,
+        num_probes: u32,
+        client_port: u16,
+        version: u32,
+        client_addr: SocketAddr,
+    ) -> Result<(RequestId, RequestSecret), CreateSessionError> {
+        let identity_mode = self.identity_mode;
 
+        // Flows are limited by the identity of the client itself rather than
+        // the per-session identity so that each session isn't unique
+        let owner = client_identity(identity_mode, client_addr, None);
+
+        if let Some(max_client_flows) = self.max_client_flows {
+            let flows = self
+                .sessions
+                .count_where(&|value| value.owner == owner)
+                .await;
+            if flows >= max_client_flows as usize {
+                return Err(CreateSessionError::ClientLimit(owner));
+            }
+        }
+
+        self.insert_request_data(q_type, num_probes, client_port, version, client_addr)
+            .await
+    }
+
+    /// Creates `n` latency sessions of the provided `q_type`, used for load
+    /// testing. The sessions are inserted together so no other session can
+    /// be created during the batch, and none are created if the session IDs
+    /// run out. The flow limit for each client doesn't apply
+    pub async fn batch_create_sessions(
+        &self,
+        n: usize,
+        q_type: u32,
+    ) -> Result<Vec<(RequestId, RequestSecret)>, CreateSessionError> {
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let range = self.id_range();
+
+        // Firewall sessions can't be created while their IDs are being avoided
+        let m2 = &*self.m2.read().await;
+        let firewall_ids: HashSet<RequestId> = m2.keys().map(|(id, _)| *id).collect();
+
+        let mut positions = None;
+        let mut rand = OsRng;
+
+        let mut create = |in_use: &dyn Fn(u32) -> bool| {
+            // IDs for the whole batch are reserved at once while the store
+            // is locked, so the batch IDs are contiguous until they wrap
+            let positions = positions.get_or_insert_with(|| {
+                let start = self.next_id.fetch_add(n as u64, Ordering::AcqRel);
+                start..start + n as u64
+            });
+
+            let mut attempts = 0;
+            let id = loop {
+                if attempts == range {
+                    return None;
+                }
+                attempts += 1;
+
+                let position = positions
+                    .next()
+                    .unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::AcqRel));
+                let id = self.id_at(position);
+
+                // IDs can only still be in use once the sequence has wrapped around
+                if position < range || !(in_use(id) || firewall_ids.contains(&id)) {
+                    break id;
+                }
+            };
+
+            // The ID is unused so any secret is unique
+            let secret = (rand.next_u32() as u16) as u32;
+            Some(self.new_request_data(id, secret, q_type, LATENCY_PROBE_COUNT, 0, 1, client_addr))
+        };
+
+        let sessions = match self.sessions.insert_many(n, &mut create).await {
+            Ok(sessions) => sessions,
+            Err(StoreError::Incomplete { .. }) => return Err(CreateSessionError::IdsExhausted),
+            Err(err) => return Err(CreateSessionError::Store(err)),
+        };
+
+        metrics::QOS_ACTIVE_SESSIONS.add(sessions.len() as i64);
+        for (id, _) in &sessions {
+            self.log_allocation(*id, FlowType::Latency, client_addr)
+                .await;
+        }
+
+        Ok(sessions)
+    }
+
+    /// Creates the data for a new latency session with the `id` and `secret`
+    #[allow(clippy::too_many_arguments)]
+    fn new_request_data(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+        q_type: u32,
+        num_probes: u32,
         client_port: u16,
         version: u32,
-    ) -> (RequestId, RequestSecret) {
-        let m1 = &mut *self.m1.write().await;
+        client_addr: SocketAddr,
+    ) -> QRequestData {
+        let identity_mode = self.identity_mode;
+        let (events, _) = broadcast::channel(SESSION_EVENT_CAPACITY);
+
+        QRequestData {
+            id,
+            secret,
+            client_identity: client_identity(identity_mode, client_addr, Some((id, secret))),
+            owner: client_identity(identity_mode, client_addr, None),
+            client_addr,
+            q_type,
+            num_probes,
+            client_port,
+            version,
+            expected_port: self.qos_port,
+            created_at: SystemTime::now(),
+            probes_received: 0,
+            first_probe_at: None,
+            last_probe_at: None,
+            completed_at: None,
+            probe_arrivals: Vec::with_capacity(num_probes as usize),
+            probe_sizes: Vec::with_capacity(num_probes as usize),
+            seen_probes: HashSet::new(),
+            next_probe: 0,
+            events,
+        }
+    }
 
-        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+    /// Allocates an ID and secret for a new latency session and inserts it
+    /// into the session store
+    async fn insert_request_data(
+        &self,
+        q_type: u32,
+        num_probes: u32,
+        client_port: u16,
+        version: u32,
+        client_addr: SocketAddr,
+    ) -> Result<(RequestId, RequestSecret), CreateSessionError> {
+        let id = self
+            .next_id(FlowType::Latency, client_addr)
+            .await
+            .ok_or(CreateSessionError::IdsExhausted)?;
         let mut rand = OsRng;
-        let secret: u32 = loop {
+
+        for _ in 0..self.secret_collision_retry_limit {
             let secret = (rand.next_u32() as u16) as u32;
-            if m1.contains_key(&(id, secret)) {
+            if self.sessions.contains((id, secret)).await {
                 continue;
             }
-            break secret;
+
+            let data = self.new_request_data(
+                id,
+                secret,
+                q_type,
+                num_probes,
+                client_port,
+                version,
+                client_addr,
+            );
+
+            // Another session may have taken the secret since it was checked
+            match self.sessions.insert((id, secret), data).await {
+                Ok(()) => {}
+                Err(StoreError::AlreadyExists) => continue,
+                Err(err) => return Err(CreateSessionError::Store(err)),
+            }
+
+            metrics::QOS_ACTIVE_SESSIONS.inc();
+            return Ok((id, secret));
+        }
+
+        Err(CreateSessionError::SecretsExhausted)
+    }
+
+    /// Creates a snapshot of all the current latency sessions
+    pub async fn list_sessions(&self) -> Vec<QRequestData> {
+        self.sessions.list().await
+    }
+
+    /// Snapshots all the latency sessions as a JSON array for bulk export,
+    /// the secrets are masked down to their last 4 bits
+    pub async fn export_sessions_json(&self) -> serde_json::Value {
+        let now = SystemTime::now();
+        let sessions = self.sessions.list().await;
+
+        sessions
+            .iter()
+            .map(|value| {
+                let age_ms = now
+                    .duration_since(value.created_at)
+                    .map(|age| age.as_millis() as u64)
+                    .unwrap_or_default();
+
+                json!({
+                    "id": value.id,
+                    "secret": value.secret & 0xF,
+                    "age_ms": age_ms,
+                    "probe_count": value.probes_received,
+                    "version": value.version,
+                    "q_type": value.q_type,
+                    "client_port": value.client_port,
+                })
+            })
+            .collect()
+    }
+
+    /// Determines the probe state of the latency or firewall session
+    /// with the provided `id` and `secret`
+    pub async fn probe_session_state(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+    ) -> ProbeSessionState {
+        let data = match self.sessions.get((id, secret)).await {
+            Some(value) => value,
+            None => return self.firewall_session_state(id, secret).await,
         };
 
-        let data = QRequestData {
-            q_type,
+        match data.completed_at {
+            Some(completed_at) => ProbeSessionState::Completed {
+                total_probes: data.probes_received,
+                duration_ms: millis_between(data.created_at, completed_at),
+            },
+            None => ProbeSessionState::Active {
+                probes_received: data.probes_received,
+                age_ms: millis_between(data.created_at, SystemTime::now()),
+            },
+        }
+    }
 
-            client_port,
-            version,
+    /// Determines the probe state of the firewall session with the provided
+    /// `id` and `secret`, completed once a firetype request has finished
+    /// receiving its probes
+    async fn firewall_session_state(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+    ) -> ProbeSessionState {
+        let (probes, created_at, completed_at, total_probes) = {
+            let m2 = &*self.m2.read().await;
+            match m2.get(&(id, secret)) {
+                Some(value) => (
+                    value.tx.probes.clone(),
+                    value.created_at,
+                    value.completed_at,
+                    value.total_probes,
+                ),
+                None => return ProbeSessionState::NotFound,
+            }
         };
 
-        m1.insert((id, secret), data);
+        match completed_at {
+            Some(completed_at) => ProbeSessionState::Completed {
+                total_probes,
+                duration_ms: millis_between(created_at, completed_at),
+            },
+            None => ProbeSessionState::Active {
+                probes_received: probes.lock().await.0.len() as u32,
+                age_ms: millis_between(created_at, SystemTime::now()),
+            },
+        }
+    }
+
+    /// Marks the firewall session with the provided `id` and `secret` as
+    /// completed by a firetype request that received `total_probes` probes
+    pub async fn complete_firewall(&self, id: RequestId, secret: RequestSecret, total_probes: u32) {
+        let m2 = &mut *self.m2.write().await;
+        if let Some(value) = m2.get_mut(&(id, secret)) {
+            value.completed_at = Some(SystemTime::now());
+            value.total_probes = total_probes;
+        }
+    }
+
+    /// Subscribes to the events for the latency session with the
+    /// provided `id` and `secret` if one exists
+    pub async fn watch_session(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+    ) -> Option<broadcast::Receiver<SessionEvent>> {
+        self.sessions
+            .get((id, secret))
+            .await
+            .map(|value| value.events.subscribe())
+    }
+
+    /// Records a probe being received for the latency session with the
+    /// provided `id` and `secret` emitting the relevant session events.
+    ///
+    /// The `probe_size` is the size in bytes of the probe datagram used
+    /// for computing the bandwidth of the session. Probes for sessions
+    /// older than `max_lifetime` are rejected
+    pub async fn record_probe(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+        probe_number: u32,
+        probe_size: usize,
+        local_port: u16,
+        max_lifetime: Duration,
+    ) -> ProbeResult {
+        let mut result = ProbeResult::UnknownSession;
+        self.sessions
+            .update((id, secret), &mut |data| {
+                result = self.apply_probe(data, probe_number, probe_size, local_port, max_lifetime);
+            })
+            .await;
+        result
+    }
+
+    /// Applies a probe received for the latency session `data`
+    fn apply_probe(
+        &self,
+        data: &mut QRequestData,
+        probe_number: u32,
+        probe_size: usize,
+        local_port: u16,
+        max_lifetime: Duration,
+    ) -> ProbeResult {
+        let (id, secret) = (data.id, data.secret);
+
+        let age = data.created_at.elapsed().unwrap_or_default();
+        if age > max_lifetime {
+            return ProbeResult::Expired(age);
+        }
+
+        if local_port != data.expected_port {
+            metrics::PROBE_PORT_MISMATCHES.inc();
+            if self.port_mismatch_policy == PortMismatchPolicy::Ignore {
+                return ProbeResult::PortMismatch(data.expected_port);
+            }
+            warn!(
+                "Probe {} for QoS session {} {} arrived on port {} instead of {}",
+                probe_number, id, secret, local_port, data.expected_port
+            );
+        }
+
+        // Probes too far ahead of the next expected probe are treated
+        // as retransmits along with already received probe numbers
+        let is_new = !data.seen_probes.contains(&probe_number)
+            && probe_number <= data.next_probe.saturating_add(self.probe_reorder_tolerance);
+
+        if is_new {
+            data.seen_probes.insert(probe_number);
+            if probe_number < data.next_probe {
+                debug!(
+                    "Reordered probe {} for QoS session {} {} (expected {})",
+                    probe_number, id, secret, data.next_probe
+                );
+            }
+            data.next_probe = data.next_probe.max(probe_number.saturating_add(1));
+        } else {
+            // Duplicates are only recorded when they're responded to normally
+            metrics::DUPLICATE_PROBES.inc();
+            if self.duplicate_probe_policy != DuplicateProbePolicy::Respond {
+                return ProbeResult::Duplicate;
+            }
+        }
+
+        let now = SystemTime::now();
+        data.probes_received += 1;
+        data.first_probe_at.get_or_insert(now);
+        data.last_probe_at = Some(now);
+        data.probe_arrivals.push(now);
+        data.probe_sizes.push(probe_size);
+
+        // Sending only fails when there are no subscribers
+        _ = data.events.send(SessionEvent::ProbeReceived(probe_number));
 
-        (id, secret)
+        if data.probes_received == data.num_probes {
+            data.completed_at = Some(now);
+            _ = data.events.send(SessionEvent::SessionCompleted);
+            webhook::notify(FlowCompletion::latency(data));
+        }
+
+        ProbeResult::Recorded
     }
 
-    pub async fn create_firewall_data(&self) -> (RequestId, RequestSecret) {
+    /// Removes any latency sessions that were created longer than `ttl`
+    /// ago notifying any subscribers that the session expired
+    pub async fn prune_expired_requests(&self, ttl: Duration) {
+        let now = SystemTime::now();
+
+        let expired = self
+            .sessions
+            .remove_where(&|value| {
+                now.duration_since(value.created_at)
+                    .is_ok_and(|age| age >= ttl)
+            })
+            .await;
+
+        for value in expired {
+            debug!("Pruning expired QoS session: {} {}", value.id, value.secret);
+            _ = value.events.send(SessionEvent::SessionExpired);
+            metrics::QOS_ACTIVE_SESSIONS.dec();
+            value.summary().record(self.region.as_deref());
+        }
+    }
+
+    /// Removes the latency session with the provided `id` and `secret`,
+    /// returns whether the session existed
+    pub async fn remove_request_data(&self, id: RequestId, secret: RequestSecret) -> bool {
+        let removed = self.sessions.remove((id, secret)).await;
+        if removed {
+            metrics::QOS_ACTIVE_SESSIONS.dec();
+        }
+        removed
+    }
+
+    /// Removes the firewall session with the provided `id` and `secret`
+    pub async fn remove_firewall_data(&self, id: RequestId, secret: RequestSecret) {
         let m2 = &mut *self.m2.write().await;
+        if m2.remove(&(id, secret)).is_some() {
+            metrics::FIREWALL_ACTIVE_SESSIONS.dec();
+        }
+    }
 
-        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
-        let mut rand = OsRng;
-        let secret: u32 = loop {
-            let secret = (rand.next_u32() as u16) as u32;
-            if m2.contains_key(&(id, secret)) {
-                continue;
+    /// Removes any firewall sessions that were created longer than `ttl`
+    /// ago, dropping the sender will end any waiting firetype requests
+    pub async fn prune_expired_firewalls(&self, ttl: Duration) {
+        let m2 = &mut *self.m2.write().await;
+        let now = SystemTime::now();
+
+        m2.retain(|(id, secret), value| {
+            let expired = now
+                .duration_since(value.created_at)
+                .is_ok_and(|age| age >= ttl);
+
+            if expired {
+                debug!("Pruning expired firewall session: {} {}", id, secret);
+                metrics::FIREWALL_ACTIVE_SESSIONS.dec();
             }
-            break secret;
-        };
 
-        let (tx, rx) = mpsc::unbounded_channel();
+            !expired
+        });
+    }
 
-        let data = QFirewallData { tx, rx: Some(rx) };
+    /// Lists the firewall sessions that were created longer than `ttl` ago
+    pub async fn list_expired_firewall_sessions(
+        &self,
+        ttl: Duration,
+    ) -> Vec<(RequestId, RequestSecret)> {
+        let m2 = &*self.m2.read().await;
+        let now = SystemTime::now();
+
+        m2.iter()
+            .filter(|(_, value)| {
+                now.duration_since(value.created_at)
+                    .is_ok_and(|age| age >= ttl)
+            })
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    pub async fn create_firewall_data(
+        &self,
+        client_addr: SocketAddr,
+    ) -> Result<(RequestId, RequestSecret), CreateSessionError> {
+        // Allocated before locking as checking whether IDs are in use reads the sessions
+        let id = self
+            .next_id(FlowType::Firewall, client_addr)
+            .await
+            .ok_or(CreateSessionError::IdsExhausted)?;
+
+        let m2 = &mut *self.m2.write().await;
+        let mut rand = OsRng;
+        let secret: u32 = (0..self.secret_collision_retry_limit)
+            .map(|_| (rand.next_u32() as u16) as u32)
+            .find(|secret| !m2.contains_key(&(id, *secret)))
+            .ok_or(CreateSessionError::SecretsExhausted)?;
+
+        let (tx, rx) = probe_channel::channel(
+            self.firewall_channel.capacity,
+            self.firewall_channel.policy,
+            self.firewall_channel.block_timeout,
+        );
+
+        let client_region = match (&self.geo, client_addr) {
+            (Some(geo), SocketAddr::V4(addr)) => geo.region(*addr.ip()),
+            _ => None,
+        };
+
+        let data = QFirewallData {
+            tx: FirewallSender {
+                tx,
+                probes: Default::default(),
+            },
+            rx: Some(rx),
+            created_at: SystemTime::now(),
+            completed_at: None,
+            total_probes: 0,
+            client_region,
+        };
 
         m2.insert((id, secret), data);
+        metrics::FIREWALL_ACTIVE_SESSIONS.inc();
 
-        (id, secret)
+        Ok((id, secret))
     }
 
     pub async fn get_firewall_tx(
         &self,
         id: RequestId,
         secret: RequestSecret,
-    ) -> Option<mpsc::UnboundedSender<SocketAddr>> {
+    ) -> Option<FirewallSender> {
         let m2 = &*self.m2.read().await;
         m2.get(&(id, secret)).map(|value| value.tx.clone())
     }
 
+    /// Provides a copy of the probes sent to the firewall session with the
+    /// provided `id` and `secret` without consuming its receiver, empty if
+    /// the session doesn't exist
+    pub async fn drain_channel(&self, id: RequestId, secret: RequestSecret) -> Vec<SocketAddr> {
+        let probes = {
+            let m2 = &*self.m2.read().await;
+            match m2.get(&(id, secret)) {
+                Some(value) => value.tx.probes.clone(),
+                None => return Vec::new(),
+            }
+        };
+
+        let probes = &*probes.lock().await;
+        probes.0.clone()
+    }
+
+    pub async fn get_firewall_info(
+        &self,
+        id: RequestId,
+        secret: RequestSecret,
+    ) -> Option<QFirewallInfo> {
+        let m2 = &*self.m2.read().await;
+        m2.get(&(id, secret)).map(|value| QFirewallInfo {
+            rx_taken: value.rx.is_none(),
+            client_region: value.client_region.clone(),
+        })
+    }
+
     pub async fn take_firewall_rx(
         &self,
         id: RequestId,
         secret: RequestSecret,
-    ) -> Option<mpsc::UnboundedReceiver<SocketAddr>> {
+    ) -> Option<ProbeReceiver> {
         let m2 = &mut *self.m2.write().await;
         m2.get_mut(&(id, secret)).and_then(|value| value.rx.take())
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct QRequestData {
+    pub id: RequestId,
+    pub secret: RequestSecret,
+    /// Identity of the client that created the session
+    pub client_identity: String,
+    /// Identity of the client used for limiting its concurrent flows
+    #[serde(skip)]
+    owner: String,
+    /// Address of the client that created the session
+    #[serde(skip)]
+    pub client_addr: SocketAddr,
     pub q_type: u32,
+    /// Number of probes the client was told to send
+    pub num_probes: u32,
     pub client_port: u16,
     pub version: u32,
+    /// UDP port the client was told to send its probes to
+    pub expected_port: u16,
+    /// Time the session was created at
+    pub created_at: SystemTime,
+    /// Number of probes received for this session
+    pub probes_received: u32,
+    /// Time the first probe for this session was received
+    pub first_probe_at: Option<SystemTime>,
+    /// Time the most recent probe for this session was received
+    pub last_probe_at: Option<SystemTime>,
+    /// Time all the expected probes for this session had been received
+    pub completed_at: Option<SystemTime>,
+    /// Times each probe for this session was received in arrival order
+    #[serde(skip)]
+    pub probe_arrivals: Vec<SystemTime>,
+    /// Sizes in bytes of each probe for this session in arrival order
+    #[serde(skip)]
+    pub probe_sizes: Vec<usize>,
+    /// Probe numbers that have been received for this session
+    #[serde(skip)]
+    seen_probes: HashSet<u32>,
+    /// Probe number expected next, one past the highest new probe number
+    #[serde(skip)]
+    next_probe: u32,
+    /// Sender for streaming session events to watchers
+    #[serde(skip)]
+    events: broadcast::Sender<SessionEvent>,
+}
+
+impl QRequestData {
+    /// Times between the arrival of each consecutive probe
+    pub fn inter_probe_gaps(&self) -> Vec<Duration> {
+        self.probe_arrivals
+            .windows(2)
+            .map(|pair| pair[1].duration_since(pair[0]).unwrap_or_default())
+            .collect()
+    }
+
+    /// Computes the upstream bits per second from the sizes and arrival
+    /// times of the received probes, the first probe only marks the start
+    /// of the span. Returns [None] without at least two probes received
+    /// at different times
+    pub fn computed_ubps(&self) -> Option<u32> {
+        let first = *self.probe_arrivals.first()?;
+        let last = *self.probe_arrivals.last()?;
+
+        let span_us = last.duration_since(first).ok()?.as_micros() as u64;
+        if span_us == 0 {
+            return None;
+        }
+
+        let bits = self.probe_sizes.iter().skip(1).sum::<usize>() as u64 * 8;
+        Some((bits * 1_000_000 / span_us).min(u32::MAX as u64) as u32)
+    }
+}
+
+/// Header row for sessions serialized with [QRequestData::to_csv_row]
+pub const CSV_HEADER: &str =
+    "session_id,secret,created_at_unix,completed_at_unix,probe_count,computed_ubps,client_port,version";
+
+impl QRequestData {
+    /// Serializes the session as a CSV row in the format of [CSV_HEADER]
+    pub fn to_csv_row(&self) -> String {
+        let unix_secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|value| value.as_secs())
+                .unwrap_or_default()
+        };
+
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.id,
+            self.secret,
+            unix_secs(self.created_at),
+            self.completed_at
+                .map(|value| unix_secs(value).to_string())
+                .unwrap_or_default(),
+            self.probes_received,
+            self.computed_ubps()
+                .map(|value| value.to_string())
+                .unwrap_or_default(),
+            self.client_port,
+            self.version
+        )
+    }
+
+    /// Serializes this session as an InfluxDB line protocol point in the
+    /// provided `measurement` timestamped with the completion time of the
+    /// session, or its creation time if it hasn't completed
+    pub fn to_influxdb_line(&self, measurement: &str) -> String {
+        let measurement = measurement.replace(',', "\\,").replace(' ', "\\ ");
+        let timestamp = self.completed_at.unwrap_or(self.created_at);
+
+        let duration_ms = timestamp
+            .duration_since(self.created_at)
+            .map(|value| value.as_millis())
+            .unwrap_or_default();
+        let timestamp_ns = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|value| value.as_nanos())
+            .unwrap_or_default();
+
+        // The bandwidth field is left out when it can't be computed
+        let ubps = self
+            .computed_ubps()
+            .map(|value| format!("ubps={},", value))
+            .unwrap_or_default();
+
+        format!(
+            "{},session_id={},version={} {}probe_count={},duration_ms={} {}",
+            measurement,
+            self.id,
+            self.version,
+            ubps,
+            self.probes_received,
+            duration_ms,
+            timestamp_ns
+        )
+    }
+
+    /// Creates a summary of the flow for this session
+    pub fn summary(&self) -> FlowSummary {
+        let probes_lost = self.num_probes.saturating_sub(self.probes_received);
+        let loss_percent = if self.num_probes > 0 {
+            probes_lost as f32 / self.num_probes as f32 * 100.0
+        } else {
+            0.0
+        };
+
+        let arrival_span_ms = match (self.first_probe_at, self.last_probe_at) {
+            (Some(first), Some(last)) => last
+                .duration_since(first)
+                .map(|value| value.as_millis() as u64)
+                .ok(),
+            _ => None,
+        };
+
+        FlowSummary {
+            id: self.id,
+            version: self.version,
+            probes_expected: self.num_probes,
+            probes_received: self.probes_received,
+            probes_lost,
+            loss_percent,
+            arrival_span_ms,
+        }
+    }
+}
+
+/// Summary of the quality of a completed latency flow
+#[derive(Debug, Serialize)]
+pub struct FlowSummary {
+    /// The ID of the flow
+    pub id: RequestId,
+    /// The client QoS version
+    pub version: u32,
+    /// Number of probes the client was told to send
+    pub probes_expected: u32,
+    /// Number of probes that were received
+    pub probes_received: u32,
+    /// Number of expected probes that were never received
+    pub probes_lost: u32,
+    /// Percentage of the expected probes that were lost
+    pub loss_percent: f32,
+    /// Time in milliseconds between the first and last probe arriving
+    pub arrival_span_ms: Option<u64>,
+}
+
+impl FlowSummary {
+    /// Logs the summary and records it in the flow metrics, the
+    /// `region` of the server is included in the log when set
+    pub fn record(&self, region: Option<&str>) {
+        info!(
+            "Flow summary: region={} id={} version={} probes={}/{} lost={} ({:.1}%) span={}",
+            region.unwrap_or("n/a"),
+            self.id,
+            self.version,
+            self.probes_received,
+            self.probes_expected,
+            self.probes_lost,
+            self.loss_percent,
+            self.arrival_span_ms
+                .map(|value| format!("{}ms", value))
+                .unwrap_or_else(|| "n/a".to_string())
+        );
+
+        if self.probes_lost == 0 {
+            metrics::FLOWS_COMPLETE.inc();
+        } else {
+            metrics::FLOWS_INCOMPLETE.inc();
+        }
+        metrics::FLOW_PROBES_LOST.add(self.probes_lost as u64);
+    }
+}
+
+/// Errors that can occur while creating a session
+#[derive(Debug, Error)]
+pub enum CreateSessionError {
+    /// All the session IDs have been allocated
+    #[error("all session IDs have been allocated")]
+    IdsExhausted,
+    /// No unique session secret was found within the retry limit
+    #[error("unable to generate a unique session secret")]
+    SecretsExhausted,
+    /// The client already has the maximum number of concurrent flows
+    #[error("client {0} has too many concurrent flows")]
+    ClientLimit(String),
+    /// The session couldn't be stored
+    #[error(transparent)]
+    Store(StoreError),
+}
+
+/// Outcome of recording a probe for a latency session
+#[derive(Debug)]
+pub enum ProbeResult {
+    /// The probe was recorded against the session
+    Recorded,
+    /// The session doesn't exist
+    UnknownSession,
+    /// The session has exceeded its maximum lifetime, contains
+    /// the age of the session
+    Expired(Duration),
+    /// The probe number was already received and wasn't recorded
+    Duplicate,
+    /// The probe arrived on a different port than the session expected
+    /// and wasn't recorded, contains the expected port
+    PortMismatch(u16),
+}
+
+/// Probe state of a latency or firewall session
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProbeSessionState {
+    /// The session doesn't exist
+    NotFound,
+    /// The session is still waiting on probes
+    Active { probes_received: u32, age_ms: u64 },
+    /// The latency session has received all its probes, or a firetype
+    /// request has finished receiving the firewall session probes
+    Completed { total_probes: u32, duration_ms: u64 },
+}
+
+/// Events emitted over the lifetime of a latency session
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// A probe with the contained probe number was received
+    ProbeReceived(u32),
+    /// All the expected probes for the session have been received
+    SessionCompleted,
+    /// The session was removed after exceeding its TTL
+    SessionExpired,
+}
+
+/// Maximum number of probes kept for inspection in each firewall session
+const FIREWALL_PROBE_LOG_CAPACITY: usize = 64;
+
+/// Sender for the probes of a firewall session, keeping a copy of
+/// each probe that can be inspected without taking the receiver
+#[derive(Clone)]
+pub struct FirewallSender {
+    /// Sender for the probe channel
+    tx: ProbeSender,
+    /// Probes that have been sent through the channel
+    probes: Arc<Mutex<ProbeLog>>,
+}
+
+/// Copies of the probes sent to a firewall session, the copies count
+/// towards the global [buffer_limit] until the log is dropped
+#[derive(Default)]
+struct ProbeLog(Vec<SocketAddr>);
+
+impl Drop for ProbeLog {
+    fn drop(&mut self) {
+        buffer_limit::release(self.0.len() * PROBE_BYTES);
+    }
+}
+
+impl FirewallSender {
+    /// Sends the provided probe `addr` through the channel after keeping
+    /// a copy of it, probes are kept even if the channel drops them
+    pub async fn send(&self, addr: SocketAddr) -> Result<(), SendError> {
+        {
+            let probes = &mut *self.probes.lock().await;
+            if probes.0.len() < FIREWALL_PROBE_LOG_CAPACITY
+                && buffer_limit::try_reserve(PROBE_BYTES)
+            {
+                probes.0.push(addr);
+            }
+        }
+
+        self.tx.send(addr).await
+    }
 }
 
 pub struct QFirewallData {
-    tx: mpsc::UnboundedSender<SocketAddr>,
-    rx: Option<mpsc::UnboundedReceiver<SocketAddr>>,
+    tx: FirewallSender,
+    rx: Option<ProbeReceiver>,
+    /// Time the session was created at
+    created_at: SystemTime,
+    /// Time a firetype request finished receiving the probes
+    completed_at: Option<SystemTime>,
+    /// Number of probes the firetype request received
+    total_probes: u32,
+    /// Approximate region of the client from the GeoIP database
+    client_region: Option<String>,
+}
+
+/// Serializable snapshot of the state of a [QFirewallData]
+#[derive(Debug, Serialize)]
+pub struct QFirewallInfo {
+    /// Whether the receiver has been taken by a firetype request
+    pub rx_taken: bool,
+    /// Approximate region of the client from the GeoIP database
+    pub client_region: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::Duration};
+
+    use log::Level;
+
+    use super::{CreateSessionError, FlowType, ProbeSessionState, QService, SESSION_ID_BITS};
+    use crate::{config::Config, logging::capture, metrics, session_store::InMemorySessionStore};
+
+    /// Address of the client the test sessions are created for
+    fn client() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 3659))
+    }
+
+    /// Session IDs wrap back around to the start of the sequence, skipping
+    /// IDs still in use and only running out when every ID is in use
+    #[tokio::test]
+    async fn session_ids_wrap_around_skipping_live_ids() {
+        let start = (1 << SESSION_ID_BITS) - 3;
+        let config = Config {
+            session_id_start: start,
+            ..Default::default()
+        };
+        let service = QService::new(&config, Box::new(InMemorySessionStore::default()));
+
+        let mut sessions = Vec::new();
+        for _ in 0..3 {
+            sessions.push(service.create_firewall_data(client()).await.unwrap());
+        }
+        let ids: Vec<u32> = sessions.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [start, start + 1, start + 2]);
+
+        assert!(matches!(
+            service.create_firewall_data(client()).await,
+            Err(CreateSessionError::IdsExhausted)
+        ));
+
+        // Freed IDs are allocated again, by latency sessions as well
+        let (id, secret) = sessions[1];
+        service.remove_firewall_data(id, secret).await;
+        let (id, _) = service
+            .create_request_data(1, 5, 0, 2, client())
+            .await
+            .unwrap();
+        assert_eq!(id, start + 1);
+
+        assert!(matches!(
+            service.create_request_data(1, 5, 0, 2, client()).await,
+            Err(CreateSessionError::IdsExhausted)
+        ));
+    }
+
+    /// A service created with a fixed start ID allocates the same
+    /// ID sequence every time and logs each allocation
+    #[tokio::test]
+    async fn fixed_start_id_allocation_sequence() {
+        let config = Config {
+            session_id_start: 100,
+            session_id_prefix: 3,
+            log_id_allocations: true,
+            ..Default::default()
+        };
+        let service = QService::new(&config, Box::new(InMemorySessionStore::default()));
+        let prefix = 3 << SESSION_ID_BITS;
+
+        let (first, _) = service
+            .create_request_data(1, 5, 0, 2, client())
+            .await
+            .unwrap();
+        let (second, _) = service.create_firewall_data(client()).await.unwrap();
+        let (third, _) = service
+            .create_request_data(1, 5, 0, 2, client())
+            .await
+            .unwrap();
+        assert_eq!(
+            [first, second, third],
+            [prefix | 100, prefix | 101, prefix | 102]
+        );
+
+        let log = service.id_allocations().await.unwrap();
+        let logged: Vec<(u32, bool)> = log
+            .iter()
+            .map(|entry| (entry.id, matches!(entry.flow, FlowType::Firewall)))
+            .collect();
+        assert_eq!(logged, [(first, false), (second, true), (third, false)]);
+        assert!(log.iter().all(|entry| entry.client == client()));
+    }
+
+    /// Firewall sessions are active until a firetype request completes
+    /// them, after which they report the number of probes it received
+    #[tokio::test]
+    async fn firewall_session_state_tracks_completion() {
+        let service = QService::new(
+            &Config::default(),
+            Box::new(InMemorySessionStore::default()),
+        );
+        let (id, secret) = service.create_firewall_data(client()).await.unwrap();
+
+        assert_eq!(
+            service.probe_session_state(id, secret + 1).await,
+            ProbeSessionState::NotFound
+        );
+
+        let tx = service.get_firewall_tx(id, secret).await.unwrap();
+        tx.send(client()).await.unwrap();
+        assert!(matches!(
+            service.probe_session_state(id, secret).await,
+            ProbeSessionState::Active {
+                probes_received: 1,
+                ..
+            }
+        ));
+
+        service.complete_firewall(id, secret, 4).await;
+        assert!(matches!(
+            service.probe_session_state(id, secret).await,
+            ProbeSessionState::Completed {
+                total_probes: 4,
+                ..
+            }
+        ));
+
+        service.remove_firewall_data(id, secret).await;
+        assert_eq!(
+            service.probe_session_state(id, secret).await,
+            ProbeSessionState::NotFound
+        );
+    }
+
+    /// Cleaning up a flow emits its summary with the probes received and
+    /// lost and records the loss in the flow metrics
+    #[tokio::test]
+    async fn flow_summary_emitted_on_cleanup() {
+        let config = Config {
+            region: Some("eu-west".to_string()),
+            ..Default::default()
+        };
+        let service = QService::new(&config, Box::new(InMemorySessionStore::default()));
+        let port = config.udp_port_1;
+        let _metrics = metrics::TEST_LOCK.read().await;
+
+        let (id, secret) = service
+            .create_request_data(1, 4, port, 2, client())
+            .await
+            .unwrap();
+        for probe_number in 0..3 {
+            if probe_number > 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            service
+                .record_probe(id, secret, probe_number, 64, port, Duration::from_secs(60))
+                .await;
+        }
+
+        let summary = service
+            .get_request_data(id, secret)
+            .await
+            .unwrap()
+            .summary();
+        assert_eq!(summary.id, id);
+        assert_eq!(summary.version, 2);
+        assert_eq!(summary.probes_expected, 4);
+        assert_eq!(summary.probes_received, 3);
+        assert_eq!(summary.probes_lost, 1);
+        assert_eq!(summary.loss_percent, 25.0);
+        assert!(summary.arrival_span_ms.is_some_and(|span| span >= 40));
+
+        let incomplete = metrics::FLOWS_INCOMPLETE.get();
+        let lost = metrics::FLOW_PROBES_LOST.get();
+
+        capture::start();
+        service.prune_expired_requests(Duration::ZERO).await;
+        let records = capture::take();
+
+        let expected = format!(
+            "Flow summary: region=eu-west id={} version=2 probes=3/4 lost=1 (25.0%) span=",
+            id
+        );
+        assert!(
+            records
+                .iter()
+                .any(|(level, message)| *level == Level::Info && message.starts_with(&expected)),
+            "summary not logged: {:?}",
+            records
+        );
+        assert!(metrics::FLOWS_INCOMPLETE.get() > incomplete);
+        assert!(metrics::FLOW_PROBES_LOST.get() > lost);
+        assert!(!service.session_exists(id, secret).await);
+    }
 }
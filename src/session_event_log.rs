@@ -0,0 +1,133 @@
+//! Binary log of probe arrivals, records are appended in the order
+//! they arrive so the log is always in chronological order
+
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use log::error;
+use serde::Serialize;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+/// Size of a single encoded record
+const RECORD_SIZE: usize = 22;
+
+/// Record of a single probe arriving for a session
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeArrivalRecord {
+    /// Unix timestamp in milliseconds the probe arrived at
+    pub timestamp_ms: u64,
+    /// The ID of the session the probe was for
+    pub session_id: u32,
+    /// The probe number from the probe header
+    pub probe_number: u32,
+    /// The address the probe was sent from
+    pub addr: SocketAddrV4,
+}
+
+impl ProbeArrivalRecord {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut out = [0u8; RECORD_SIZE];
+        out[0..8].copy_from_slice(&self.timestamp_ms.to_be_bytes());
+        out[8..12].copy_from_slice(&self.session_id.to_be_bytes());
+        out[12..16].copy_from_slice(&self.probe_number.to_be_bytes());
+        out[16..20].copy_from_slice(&self.addr.ip().octets());
+        out[20..22].copy_from_slice(&self.addr.port().to_be_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&bytes[0..8]);
+
+        Self {
+            timestamp_ms: u64::from_be_bytes(timestamp),
+            session_id: u32_at(8),
+            probe_number: u32_at(12),
+            addr: SocketAddrV4::new(
+                Ipv4Addr::new(bytes[16], bytes[17], bytes[18], bytes[19]),
+                u16::from_be_bytes([bytes[20], bytes[21]]),
+            ),
+        }
+    }
+}
+
+/// Appending writer for the probe arrival log
+struct ProbeArrivalLog {
+    /// The path of the log file
+    path: PathBuf,
+    /// The opened log file
+    file: Mutex<tokio::fs::File>,
+}
+
+/// The probe arrival log if logging is enabled
+static PROBE_LOG: OnceLock<ProbeArrivalLog> = OnceLock::new();
+
+/// Opens the probe arrival log at the provided `path`, probes recorded
+/// after this call will be appended to the log
+pub async fn init(path: &Path) -> std::io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    _ = PROBE_LOG.set(ProbeArrivalLog {
+        path: path.to_path_buf(),
+        file: Mutex::new(file),
+    });
+
+    Ok(())
+}
+
+/// Returns the path of the probe arrival log if logging is enabled
+pub fn path() -> Option<&'static Path> {
+    PROBE_LOG.get().map(|log| log.path.as_path())
+}
+
+/// Appends the provided `record` to the probe arrival log, does
+/// nothing if logging is not enabled
+pub async fn record(record: ProbeArrivalRecord) {
+    let log = match PROBE_LOG.get() {
+        Some(value) => value,
+        None => return,
+    };
+
+    let file = &mut *log.file.lock().await;
+    if let Err(err) = file.write_all(&record.encode()).await {
+        error!("Failed to write to probe arrival log: {}", err);
+    }
+}
+
+/// Replays the records for the session with the provided `session_id`
+/// from the probe arrival log at `path` in chronological order. Records
+/// are read lazily so the log is never loaded into memory at once
+pub fn replay(
+    path: &Path,
+    session_id: u32,
+) -> std::io::Result<impl Iterator<Item = ProbeArrivalRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let records = std::iter::from_fn(move || {
+        let mut bytes = [0u8; RECORD_SIZE];
+        match reader.read_exact(&mut bytes) {
+            Ok(_) => Some(ProbeArrivalRecord::decode(&bytes)),
+            Err(err) => {
+                // Trailing partial records are from an interrupted write
+                if err.kind() != ErrorKind::UnexpectedEof {
+                    error!("Failed to read probe arrival log: {}", err);
+                }
+                None
+            }
+        }
+    });
+
+    Ok(records.filter(move |record| record.session_id == session_id))
+}
@@ -0,0 +1,275 @@
+//! Storage backends for latency sessions.
+//!
+//! The [QService](crate::service::QService) accesses latency sessions only
+//! through the [SessionStore] trait so that the in-memory store can be
+//! swapped for a shared backend in clustered deployments
+
+use std::collections::{HashMap, HashSet};
+
+use axum::async_trait;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+use crate::service::QRequestData;
+
+/// Key of a latency session, the session ID and secret
+pub type SessionKey = (u32, u32);
+
+/// Errors that can occur when modifying a session store
+#[derive(Debug, Error)]
+pub enum StoreError {
+    /// A session with the same key already exists
+    #[error("session already exists")]
+    AlreadyExists,
+    /// The backend of the store couldn't be used
+    #[error("session store unavailable: {0}")]
+    Unavailable(String),
+    /// Fewer sessions than requested could be created for a batch
+    #[error("only {created} of {requested} sessions could be created")]
+    Incomplete { created: usize, requested: usize },
+}
+
+/// Creates a session for a batch given a check for whether a session ID
+/// is in use, [None] if no more sessions can be created
+pub type CreateSession<'a> =
+    dyn for<'b> FnMut(&'b dyn Fn(u32) -> bool) -> Option<QRequestData> + Send + 'a;
+
+/// Storage for latency sessions
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Inserts a new session, fails if a session with the same key exists
+    async fn insert(&self, key: SessionKey, data: QRequestData) -> Result<(), StoreError>;
+
+    /// Inserts `count` sessions from `create` without any other changes to
+    /// the store in between. Either every session is inserted or none are
+    async fn insert_many(
+        &self,
+        count: usize,
+        create: &mut CreateSession<'_>,
+    ) -> Result<Vec<SessionKey>, StoreError>;
+
+    /// Gets a copy of the session with the provided `key`
+    async fn get(&self, key: SessionKey) -> Option<QRequestData>;
+
+    /// Removes the session with the provided `key`, returns
+    /// whether the session existed
+    async fn remove(&self, key: SessionKey) -> bool;
+
+    /// Checks whether a session exists for the provided `key`
+    async fn contains(&self, key: SessionKey) -> bool;
+
+    /// Creates a snapshot of all the sessions
+    async fn list(&self) -> Vec<QRequestData>;
+
+    /// Counts the sessions matching the `predicate`
+    async fn count_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> usize;
+
+    /// Applies the `update` to the session with the provided `key`
+    /// atomically, returns whether the session existed
+    async fn update(
+        &self,
+        key: SessionKey,
+        update: &mut (dyn for<'a> FnMut(&'a mut QRequestData) + Send),
+    ) -> bool;
+
+    /// Removes and returns all the sessions matching the `predicate`
+    async fn remove_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> Vec<QRequestData>;
+}
+
+/// Session store keeping the sessions in memory
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: RwLock<HashMap<SessionKey, QRequestData>>,
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn insert(&self, key: SessionKey, data: QRequestData) -> Result<(), StoreError> {
+        let sessions = &mut *self.sessions.write().await;
+        if sessions.contains_key(&key) {
+            return Err(StoreError::AlreadyExists);
+        }
+        sessions.insert(key, data);
+        Ok(())
+    }
+
+    async fn insert_many(
+        &self,
+        count: usize,
+        create: &mut CreateSession<'_>,
+    ) -> Result<Vec<SessionKey>, StoreError> {
+        let sessions = &mut *self.sessions.write().await;
+        let mut ids: HashSet<u32> = sessions.keys().map(|(id, _)| *id).collect();
+
+        let mut keys = Vec::with_capacity(count);
+        while keys.len() < count {
+            let data = create(&|id| ids.contains(&id)).filter(|data| {
+                // Sessions already in the store are never replaced
+                !sessions.contains_key(&(data.id, data.secret))
+            });
+
+            let Some(data) = data else {
+                for key in &keys {
+                    sessions.remove(key);
+                }
+                return Err(StoreError::Incomplete {
+                    created: keys.len(),
+                    requested: count,
+                });
+            };
+
+            let key = (data.id, data.secret);
+            ids.insert(data.id);
+            sessions.insert(key, data);
+            keys.push(key);
+        }
+
+        Ok(keys)
+    }
+
+    async fn get(&self, key: SessionKey) -> Option<QRequestData> {
+        let sessions = &*self.sessions.read().await;
+        sessions.get(&key).cloned()
+    }
+
+    async fn remove(&self, key: SessionKey) -> bool {
+        let sessions = &mut *self.sessions.write().await;
+        sessions.remove(&key).is_some()
+    }
+
+    async fn contains(&self, key: SessionKey) -> bool {
+        let sessions = &*self.sessions.read().await;
+        sessions.contains_key(&key)
+    }
+
+    async fn list(&self) -> Vec<QRequestData> {
+        let sessions = &*self.sessions.read().await;
+        sessions.values().cloned().collect()
+    }
+
+    async fn count_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> usize {
+        let sessions = &*self.sessions.read().await;
+        sessions.values().filter(|value| predicate(value)).count()
+    }
+
+    async fn update(
+        &self,
+        key: SessionKey,
+        update: &mut (dyn for<'a> FnMut(&'a mut QRequestData) + Send),
+    ) -> bool {
+        let sessions = &mut *self.sessions.write().await;
+        match sessions.get_mut(&key) {
+            Some(value) => {
+                update(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn remove_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> Vec<QRequestData> {
+        let sessions = &mut *self.sessions.write().await;
+        let keys: Vec<SessionKey> = sessions
+            .iter()
+            .filter(|(_, value)| predicate(value))
+            .map(|(key, _)| *key)
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| sessions.remove(&key))
+            .collect()
+    }
+}
+
+/// Placeholder session store for sharing sessions between servers in a
+/// cluster through Redis. Session storage isn't implemented yet so new
+/// sessions are rejected and no sessions are ever found
+#[cfg(feature = "redis")]
+pub struct RedisSessionStore {
+    client: redis::Client,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionStore {
+    /// Creates the store for the Redis server at the `url`, fails if the
+    /// url isn't a valid Redis connection url
+    pub fn new(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self { client })
+    }
+
+    /// Client for the Redis server the sessions will be stored on
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn insert(&self, _key: SessionKey, _data: QRequestData) -> Result<(), StoreError> {
+        Err(StoreError::Unavailable(
+            "redis session storage is not implemented".to_string(),
+        ))
+    }
+
+    async fn insert_many(
+        &self,
+        _count: usize,
+        _create: &mut CreateSession<'_>,
+    ) -> Result<Vec<SessionKey>, StoreError> {
+        Err(StoreError::Unavailable(
+            "redis session storage is not implemented".to_string(),
+        ))
+    }
+
+    async fn get(&self, _key: SessionKey) -> Option<QRequestData> {
+        None
+    }
+
+    async fn remove(&self, _key: SessionKey) -> bool {
+        false
+    }
+
+    async fn contains(&self, _key: SessionKey) -> bool {
+        false
+    }
+
+    async fn list(&self) -> Vec<QRequestData> {
+        Vec::new()
+    }
+
+    async fn count_where(
+        &self,
+        _predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> usize {
+        0
+    }
+
+    async fn update(
+        &self,
+        _key: SessionKey,
+        _update: &mut (dyn for<'a> FnMut(&'a mut QRequestData) + Send),
+    ) -> bool {
+        false
+    }
+
+    async fn remove_where(
+        &self,
+        _predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> Vec<QRequestData> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use log::info;
+use tokio::signal;
+
+/// Whether the server has received the shutdown signal
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Number of in-flight message handlers
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Checks whether the server is shutting down
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Acquire)
+}
+
+/// Waits for the shutdown signal and marks the server as shutting down
+pub async fn listen() {
+    _ = signal::ctrl_c().await;
+    info!("Received shutdown signal, stopping servers");
+    begin();
+}
+
+/// Marks the server as shutting down, new messages are dropped from
+/// then on while in-flight handlers are left to finish
+pub fn begin() {
+    SHUTTING_DOWN.store(true, Ordering::Release);
+}
+
+/// Clears the shutting down flag set by [begin]
+#[cfg(test)]
+pub fn reset() {
+    SHUTTING_DOWN.store(false, Ordering::Release);
+}
+
+/// Gets the number of in-flight message handlers
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Acquire)
+}
+
+/// Guard tracking a single in-flight handler
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+        Self
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Wraps the provided handler future so that it is counted as in-flight
+/// from when this is called until the future completes or is dropped
+pub fn tracked<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let guard = InFlightGuard::new();
+    async move {
+        let _guard = guard;
+        future.await
+    }
+}
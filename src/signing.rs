@@ -0,0 +1,119 @@
+//! Optional signing of QoS sessions for deployments where only authorized
+//! clients should be able to use the server.
+//!
+//! When a latency session is created the client is given a token derived
+//! from the shared signing key and the session ID and secret. Each v2 probe
+//! must then end with a MAC of its header and probe count keyed by that
+//! token, probes with a missing or invalid MAC are rejected
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::udp::QosHeader;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the MAC at the end of signed probe payloads
+pub const PROBE_MAC_SIZE: usize = 8;
+
+/// Creates the session token for the session with the provided
+/// `id` and `secret` using the shared signing `key`
+pub fn session_token(key: &[u8], id: u32, secret: u32) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&id.to_be_bytes());
+    mac.update(&secret.to_be_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Creates the MAC for a probe with the provided `header` and `probe_count`
+fn probe_mac(token: &[u8], header: &QosHeader, probe_count: u32) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(token).expect("HMAC accepts keys of any length");
+    mac.update(&header.u1.to_be_bytes());
+    mac.update(&header.request_id.to_be_bytes());
+    mac.update(&header.request_secret.to_be_bytes());
+    mac.update(&header.probe_number.to_be_bytes());
+    mac.update(&probe_count.to_be_bytes());
+    mac
+}
+
+/// Verifies the MAC at the end of the `payload` of a probe using the
+/// signing `key`, the MAC is compared in constant time
+pub fn verify_probe(key: &[u8], header: &QosHeader, probe_count: u32, payload: &[u8]) -> bool {
+    let start = match payload.len().checked_sub(PROBE_MAC_SIZE) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    let token = session_token(key, header.request_id, header.request_secret);
+    probe_mac(&token, header, probe_count)
+        .verify_truncated_left(&payload[start..])
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use hmac::Mac;
+
+    use super::{probe_mac, session_token, verify_probe, PROBE_MAC_SIZE};
+    use crate::udp::QosHeader;
+
+    const KEY: &[u8] = b"signing-key";
+
+    fn header() -> QosHeader {
+        QosHeader {
+            u1: 0,
+            request_id: 42,
+            request_secret: 1234,
+            probe_number: 3,
+        }
+    }
+
+    /// Creates a probe payload ending with the MAC for the `header`
+    /// and `probe_count` signed with the `key`
+    fn signed_payload(key: &[u8], header: &QosHeader, probe_count: u32) -> Vec<u8> {
+        let token = session_token(key, header.request_id, header.request_secret);
+        let mac = probe_mac(&token, header, probe_count)
+            .finalize()
+            .into_bytes();
+        let mut payload = vec![0u8; 16];
+        payload.extend_from_slice(&mac[..PROBE_MAC_SIZE]);
+        payload
+    }
+
+    /// Probes signed with the session token are accepted
+    #[test]
+    fn valid_mac_accepted() {
+        let payload = signed_payload(KEY, &header(), 5);
+        assert!(verify_probe(KEY, &header(), 5, &payload));
+    }
+
+    /// Probes with a MAC that doesn't match the probe are rejected
+    #[test]
+    fn invalid_mac_rejected() {
+        let payload = signed_payload(KEY, &header(), 5);
+
+        // Signed with a different key
+        let other = signed_payload(b"other-key", &header(), 5);
+        assert!(!verify_probe(KEY, &header(), 5, &other));
+
+        // Signed fields changed after signing
+        let mut changed = header();
+        changed.probe_number = 4;
+        assert!(!verify_probe(KEY, &changed, 5, &payload));
+        assert!(!verify_probe(KEY, &header(), 6, &payload));
+
+        // MAC bytes tampered with
+        let mut tampered = payload.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!verify_probe(KEY, &header(), 5, &tampered));
+
+        // Payloads too short to hold a MAC
+        assert!(!verify_probe(
+            KEY,
+            &header(),
+            5,
+            &payload[..PROBE_MAC_SIZE - 1]
+        ));
+        assert!(!verify_probe(KEY, &header(), 5, &[]));
+    }
+}
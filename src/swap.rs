@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Number of consecutive packets shaped like the other protocol
+/// before warning that the ports may be swapped
+const SWAP_WARNING_THRESHOLD: u32 = 10;
+
+/// Heuristic detector for the QoS and firewall ports being swapped
+/// in the configuration, tracks the number of consecutive packets
+/// that parse better as the protocol of the other server
+pub struct PortSwapDetector {
+    /// Number of consecutive mismatched packets
+    mismatched: AtomicU32,
+}
+
+impl PortSwapDetector {
+    pub const fn new() -> Self {
+        Self {
+            mismatched: AtomicU32::new(0),
+        }
+    }
+
+    /// Records a packet that was shaped like the expected protocol
+    pub fn record_match(&self) {
+        self.mismatched.store(0, Ordering::Relaxed);
+    }
+
+    /// Records a packet that was shaped like the protocol of the other
+    /// server. Returns true once the threshold has been reached
+    pub fn record_mismatch(&self) -> bool {
+        self.mismatched.fetch_add(1, Ordering::Relaxed) + 1 == SWAP_WARNING_THRESHOLD
+    }
+}
+
+impl Default for PortSwapDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PortSwapDetector, SWAP_WARNING_THRESHOLD};
+
+    /// The warning is only reported once the threshold of consecutive
+    /// mismatches is reached and a matching packet resets the count
+    #[test]
+    fn threshold_and_reset() {
+        let detector = PortSwapDetector::new();
+
+        for _ in 1..SWAP_WARNING_THRESHOLD {
+            assert!(!detector.record_mismatch());
+        }
+        assert!(detector.record_mismatch());
+
+        // Only reported once for the same run of mismatches
+        assert!(!detector.record_mismatch());
+
+        detector.record_match();
+        for _ in 1..SWAP_WARNING_THRESHOLD {
+            assert!(!detector.record_mismatch());
+        }
+        detector.record_match();
+        assert!(!detector.record_mismatch());
+    }
+}
@@ -1,14 +1,143 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::{Buf, BufMut, BytesMut};
-use log::{debug, error, info};
-use tokio::{net::UdpSocket, sync::RwLock};
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{net::UdpSocket, sync::RwLock, time};
 
-use crate::{config::Config, service::QService};
+use crate::{
+    blocklist::BlocklistChecker,
+    config::Config,
+    firewall::FIREWALL_MESSAGE_SIZE,
+    metrics::{self, QosTimer},
+    pcap, rate_limit,
+    service::{DuplicateProbePolicy, ProbeResult, QService},
+    session_event_log::{self, ProbeArrivalRecord},
+    shutdown, signing,
+    swap::PortSwapDetector,
+    warmup,
+};
+
+/// Detector for firewall messages being sent to the QoS server
+static PORT_SWAP_DETECTOR: PortSwapDetector = PortSwapDetector::new();
+
+/// Size of the QoS message header
+pub const QOS_HEADER_SIZE: usize = 16;
+
+/// Size of the field following the header in both request
+/// versions, the v1 timestamp or the v2 probe count
+const REQUEST_FIELD_SIZE: usize = 4;
+
+/// Number of bytes of the v2 probe payload replaced by the ubps and port
+/// fields in the response, smaller payloads are rejected by the minimum
+/// probe size
+pub const V2_RESPONSE_FIELDS_SIZE: usize = 6;
+
+/// Upstream bits per second reported in v2 QoS responses
+pub const QOS_RESPONSE_UBPS: u32 = u32::from_be_bytes([0x00, 0x5b, 0x8d, 0x80]);
+
+/// Byte order configuration for parsing QoS requests. This is a
+/// compatibility shim for clients that send their multi-byte request
+/// fields in little-endian, responses always use the standard framing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestByteOrder {
+    /// Standard big-endian framing
+    #[default]
+    Big,
+    /// Little-endian framing
+    Little,
+    /// Detect the byte order of each request from its header
+    Auto,
+}
+
+/// Source of the address reported in v1 QoS responses to clients
+/// connecting from private or loopback addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpSource {
+    /// Echo the address the client connected from
+    ClientSource,
+    /// Use the configured address of the server
+    Configured,
+    /// Use the looked up public address of the server
+    Dynamic,
+}
+
+/// Address reported in v1 QoS responses to clients connecting from the
+/// same public address as the server, such as clients on the same network
+/// reaching the server through a NAT hairpin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedPublicIpPolicy {
+    /// Echo the shared public address like any other client
+    #[default]
+    Echo,
+    /// Use the configured address of the server, which clients
+    /// behind the same NAT can reach directly
+    SelfAddress,
+}
+
+/// Byte order of the fields in a QoS request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Big,
+    Little,
+}
+
+impl ByteOrder {
+    /// Determines the byte order to use for a request from the provided
+    /// `mode` and header `bytes`. Detection relies on the `u1` header field
+    /// always being a small value so the byte order that produces a small
+    /// value is assumed to be correct
+    pub fn resolve(mode: RequestByteOrder, bytes: &[u8]) -> Self {
+        match mode {
+            RequestByteOrder::Big => Self::Big,
+            RequestByteOrder::Little => Self::Little,
+            RequestByteOrder::Auto => {
+                let u1 = match bytes.get(..4) {
+                    Some(value) => [value[0], value[1], value[2], value[3]],
+                    None => return Self::Big,
+                };
+
+                if u32::from_be_bytes(u1) > 0xFFFF && u32::from_le_bytes(u1) <= 0xFFFF {
+                    Self::Little
+                } else {
+                    Self::Big
+                }
+            }
+        }
+    }
+
+    /// Reads a u32 in this byte order from the provided bytes
+    pub fn read_u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Self::Big => u32::from_be_bytes(bytes),
+            Self::Little => u32::from_le_bytes(bytes),
+        }
+    }
+
+    /// Reads a u32 in this byte order from the provided buffer
+    pub fn get_u32(self, buffer: &mut BytesMut) -> u32 {
+        match self {
+            Self::Big => buffer.get_u32(),
+            Self::Little => buffer.get_u32_le(),
+        }
+    }
+}
+
+/// Errors that can occur while parsing QoS messages
+#[derive(Debug, Error)]
+pub enum ParseError {
+    /// The message was shorter than the required length
+    #[error("expected at least {expected} bytes but got {got}")]
+    TooShort { expected: usize, got: usize },
+}
 
 #[derive(Debug, Clone)]
 pub struct QosHeader {
@@ -20,18 +149,32 @@ pub struct QosHeader {
 }
 
 impl QosHeader {
-    pub fn from_buffer(header: &mut BytesMut) -> QosHeader {
-        let u1 = header.get_u32();
-        let request_id = header.get_u32();
-        let request_secret = header.get_u32();
-        let probe_number = header.get_u32();
-
-        QosHeader {
-            u1,
-            request_id,
-            request_secret,
-            probe_number,
+    /// Parses a header from the start of the provided `bytes` where
+    /// each field is encoded in the provided byte `order`
+    pub fn from_slice(bytes: &[u8], order: ByteOrder) -> Result<Self, ParseError> {
+        if bytes.len() < QOS_HEADER_SIZE {
+            return Err(ParseError::TooShort {
+                expected: QOS_HEADER_SIZE,
+                got: bytes.len(),
+            });
         }
+
+        let u32_at =
+            |i: usize| order.read_u32([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        Ok(QosHeader {
+            u1: u32_at(0),
+            request_id: u32_at(4),
+            request_secret: u32_at(8),
+            probe_number: u32_at(12),
+        })
+    }
+
+    /// Whether this is the header of a v1 address query. Request ID 1 with
+    /// secret 0 is reserved by the protocol for address queries so it is
+    /// never allocated to a session and can't appear in a valid v2 probe
+    pub fn is_address_query(&self) -> bool {
+        self.request_id == 1 && self.request_secret == 0
     }
 
     pub fn write(&self, out: &mut BytesMut) {
@@ -48,8 +191,8 @@ pub struct QosRequestV1 {
 }
 
 impl QosRequestV1 {
-    pub fn from_buffer(buffer: &mut BytesMut) -> Self {
-        let timestamp = buffer.get_u32();
+    pub fn from_buffer(buffer: &mut BytesMut, order: ByteOrder) -> Self {
+        let timestamp = order.get_u32(buffer);
 
         if !buffer.is_empty() {
             debug!("QoS v1 message still had more bytes: {:?}", buffer.as_ref());
@@ -66,34 +209,130 @@ pub struct QosRequestV2 {
 }
 
 impl QosRequestV2 {
-    pub fn from_buffer(buffer: &mut BytesMut) -> Self {
-        let probe_count = buffer.get_u32();
+    pub fn from_buffer(buffer: &mut BytesMut, order: ByteOrder) -> Self {
+        let probe_count = order.get_u32(buffer);
         let payload = buffer.split();
         Self {
             probe_count,
             payload,
         }
     }
+
+    /// Decodes the per-probe records from the payload, any trailing
+    /// bytes that don't make up a full record are ignored
+    pub fn decode_payload(&self) -> Vec<ProbeDataRecord> {
+        self.payload
+            .chunks_exact(PROBE_DATA_RECORD_SIZE)
+            .map(|chunk| ProbeDataRecord {
+                sequence: u16::from_be_bytes([chunk[0], chunk[1]]),
+                send_ts: u32::from_be_bytes([chunk[2], chunk[3], chunk[4], chunk[5]]),
+            })
+            .collect()
+    }
+
+    /// Estimates the upstream bits per second from the send timestamps
+    /// of the payload records, returns [None] when the records don't
+    /// span any time to estimate from
+    pub fn estimate_ubps(&self) -> Option<u32> {
+        let records = self.decode_payload();
+
+        // Reordered records still give the same span but are worth knowing about
+        if records
+            .windows(2)
+            .any(|pair| pair[1].sequence <= pair[0].sequence)
+        {
+            debug!("QoS v2 payload records are out of order: {:?}", records);
+        }
+
+        let first = records.iter().map(|record| record.send_ts).min()?;
+        let last = records.iter().map(|record| record.send_ts).max()?;
+
+        let span_ms = last.wrapping_sub(first) as u64;
+        if span_ms == 0 {
+            return None;
+        }
+
+        let bits = (self.payload.len() as u64) * 8;
+        Some((bits * 1000 / span_ms).min(u32::MAX as u64) as u32)
+    }
 }
 
+/// Size of a single record within a v2 probe payload
+pub const PROBE_DATA_RECORD_SIZE: usize = 6;
+
+/// Record within a v2 probe payload
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeDataRecord {
+    /// Sequence number of the record
+    pub sequence: u16,
+    /// Time in milliseconds the record was sent by the client
+    pub send_ts: u32,
+}
+
+/// Flag set in the `u1` header field of v1 responses that use the extended
+/// timestamp layout, clients supporting the extension check for this flag
+/// to know the timestamp field is 8 bytes instead of 4
+pub const EXTENDED_TIMESTAMP_FLAG: u32 = 0x0100;
+
 #[derive(Debug)]
 pub struct QosResponseV1 {
     pub header: QosHeader,
     pub timestamp: u32,
+    /// Extension replacing the 4 byte timestamp with the 8 byte server
+    /// receive time in nanoseconds since the unix epoch. Only present
+    /// when the extended timestamp extension is enabled in which case
+    /// the header will have the [EXTENDED_TIMESTAMP_FLAG] set
+    pub timestamp_ns: Option<u64>,
     pub ip: Ipv4Addr,
     pub port: u16,
+    /// Trailing 4 bytes of the response. The meaning of these bytes is
+    /// unknown, they could be a checksum or flags but all known clients
+    /// accept zeros so that is what's sent by default
+    pub trailer: [u8; 4],
 }
 
 impl QosResponseV1 {
     pub fn write(&self, out: &mut BytesMut) {
         self.header.write(out);
-        out.put_u32(self.timestamp);
+        match self.timestamp_ns {
+            Some(timestamp_ns) => out.put_u64(timestamp_ns),
+            None => out.put_u32(self.timestamp),
+        }
         out.extend_from_slice(&self.ip.octets());
         out.put_u16(self.port);
-        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&self.trailer);
+    }
+}
+
+/// Flag set in the `u1` header field of invalid request responses
+pub const INVALID_REQUEST_FLAG: u32 = 0x0200;
+
+/// Response to a v2 probe for a session that doesn't exist, sent instead
+/// of the usual response when invalid flow responses are enabled.
+///
+/// The response is only the 16 byte header of the probe echoed back with
+/// the [INVALID_REQUEST_FLAG] set in the `u1` field. As every other
+/// response is longer than the header, clients can also tell it apart
+/// by its length alone
+#[derive(Debug)]
+pub struct QosInvalidResponse {
+    pub header: QosHeader,
+}
+
+impl QosInvalidResponse {
+    pub fn write(&self, out: &mut BytesMut) {
+        let mut header = self.header.clone();
+        header.u1 |= INVALID_REQUEST_FLAG;
+        header.write(out);
     }
 }
 
+/// Flag set in the `u1` header field of v2 responses that include the
+/// server receive timestamp. The timestamp is appended as the last 8 bytes
+/// of the response after the payload, a big-endian u64 of nanoseconds since
+/// the unix epoch at byte offset `26 + payload length`
+pub const RECEIVE_TIMESTAMP_FLAG: u32 = 0x0400;
+
 #[derive(Debug)]
 pub struct QosResponseV2 {
     pub header: QosHeader,
@@ -101,6 +340,9 @@ pub struct QosResponseV2 {
     pub ubps: u32,
     pub port: u16,
     pub payload: BytesMut,
+    /// Server receive time in nanoseconds since the unix epoch, only present
+    /// when the header has the [RECEIVE_TIMESTAMP_FLAG] set
+    pub receive_timestamp_ns: Option<u64>,
 }
 
 impl QosResponseV2 {
@@ -110,14 +352,39 @@ impl QosResponseV2 {
         out.put_u32(self.ubps);
         out.put_u16(self.port);
         out.extend_from_slice(&self.payload);
+        if let Some(timestamp_ns) = self.receive_timestamp_ns {
+            out.put_u64(timestamp_ns);
+        }
+    }
+}
+
+/// Binds the QoS socket and applies the socket options from the `config`
+fn bind_socket(config: &Config) -> std::io::Result<UdpSocket> {
+    // Bound through std so the socket options can be set before handing it to tokio
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.udp_port_1))?;
+    socket.set_ttl(config.udp_ttl as u32)?;
+
+    if config.udp_no_fragment {
+        apply_no_fragment(&socket);
     }
+
+    // Receive probes sent to the multicast group, responses are still
+    // sent directly to the unicast address of the sender
+    if let Some(group) = config.udp_multicast_group {
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        info!("QoS server joined multicast group {}", group);
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
 }
 
-pub async fn start_server(service: Arc<QService>, config: Arc<Config>) {
-    // Socket for handling connections
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.udp_port_1))
-        .await
-        .unwrap();
+/// Runs the QoS server until shutdown, fails if the socket couldn't be bound
+pub async fn start_server(
+    service: Arc<QService>,
+    config: Arc<Config>,
+    blocklist: Arc<BlocklistChecker>,
+) -> std::io::Result<()> {
+    let socket = bind_socket(&config)?;
 
     info!("Starting QoS server on 0.0.0.0:{}", config.udp_port_1);
 
@@ -126,36 +393,188 @@ pub async fn start_server(service: Arc<QService>, config: Arc<Config>) {
     // Buffer for reciving messages
     let mut buffer = [0u8; 65536 /* UDP allocated buffer size */];
 
+    // Time to wait for a message before checking for shutdown
+    let poll_timeout = Duration::from_millis(config.udp_poll_timeout_ms);
+
+    #[cfg(target_os = "linux")]
+    let mut batch = config.udp_batch_recv.then(crate::mmsg::BatchReceiver::new);
+
+    #[cfg(not(target_os = "linux"))]
+    if config.udp_batch_recv {
+        warn!("UDP batch receiving is only supported on Linux, receiving messages individually");
+    }
+
     loop {
+        let shutting_down = shutdown::is_shutting_down();
+
+        // Stop once shutting down and any in-flight handlers have finished
+        if shutting_down && (!config.shutdown_drain || shutdown::in_flight() == 0) {
+            info!("Stopping QoS server");
+            break;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(batch) = &mut batch {
+            let messages = match time::timeout(poll_timeout, batch.recv(&socket)).await {
+                Ok(Ok(value)) => value,
+                // Errors are transient so the server keeps receiving
+                Ok(Err(err)) => {
+                    error!("Failed to receive QoS messages: {}", err);
+                    continue;
+                }
+                // Timed out, check for shutdown and try again
+                Err(_) => continue,
+            };
+
+            for (buffer, addr) in messages {
+                dispatch(
+                    &service,
+                    &config,
+                    &socket,
+                    &blocklist,
+                    shutting_down,
+                    addr,
+                    &buffer,
+                );
+            }
+            continue;
+        }
+
         // Read bytes from the socket
-        let (length, addr) = socket.recv_from(&mut buffer).await.unwrap();
+        let (length, addr) = match time::timeout(poll_timeout, socket.recv_from(&mut buffer)).await
+        {
+            Ok(Ok(value)) => value,
+            // Errors are transient so the server keeps receiving
+            Ok(Err(err)) => {
+                error!("Failed to receive QoS message: {}", err);
+                continue;
+            }
+            // Timed out, check for shutdown and try again
+            Err(_) => continue,
+        };
+
+        dispatch(
+            &service,
+            &config,
+            &socket,
+            &blocklist,
+            shutting_down,
+            addr,
+            &buffer[..length],
+        );
+    }
+
+    Ok(())
+}
+
+/// Spawns a handler for a message received from `addr` unless the message
+/// is dropped for shutdown or the sender is blocked or rate limited
+fn dispatch(
+    service: &Arc<QService>,
+    config: &Arc<Config>,
+    socket: &Arc<UdpSocket>,
+    blocklist: &BlocklistChecker,
+    shutting_down: bool,
+    addr: SocketAddr,
+    buffer: &[u8],
+) {
+    if config.log_udp_packets {
+        trace!("UDP packet from {}: {}", addr, hex::encode(buffer));
+    }
 
-        // Copy the request bytes from the buffer
-        let buffer: BytesMut = BytesMut::from(&buffer[..length]);
-        tokio::spawn(handle(service.clone(), socket.clone(), addr, buffer));
+    // Drop new messages while draining in-flight handlers
+    if shutting_down {
+        metrics::SHUTDOWN_DROPPED.inc();
+        return;
+    }
+
+    // Ignore messages from blocked addresses
+    if blocklist.is_blocked(addr.ip()) {
+        debug!("Ignoring QoS message from blocked address: {}", addr);
+        return;
+    }
+
+    if !rate_limit::allow(addr.ip()) {
+        debug!("Dropping rate limited QoS message from {}", addr);
+        return;
     }
+
+    // Copy the request bytes from the buffer
+    let buffer: BytesMut = BytesMut::from(buffer);
+    tokio::spawn(shutdown::tracked(handle(
+        service.clone(),
+        config.clone(),
+        socket.clone(),
+        addr,
+        buffer,
+    )));
 }
 
 /// Handles a new udp request
 ///
 /// # Arguments
+/// * config - The server configuration
 /// * socket - The udp socket bound for sending the response
 /// * addr - The address of the message sender
 /// * buffer - The received message buffer
 async fn handle(
-    _service: Arc<QService>,
+    service: Arc<QService>,
+    config: Arc<Config>,
     socket: Arc<UdpSocket>,
     addr: SocketAddr,
     mut buffer: BytesMut,
 ) {
-    if buffer.len() < 16 {
-        error!(
-            "Client didn't send a message long enough to be a header: {:?}",
-            buffer.as_ref()
-        );
+    // Messages received while warming up are held until it completes
+    if !warmup::is_ready() {
+        warmup::wait_ready().await;
+    }
+
+    let _timer = QosTimer::new("qos");
+
+    // Empty datagrams are common from scanners so they're handled quietly
+    if buffer.is_empty() {
+        metrics::EMPTY_DATAGRAMS.inc();
+        if config.log_empty_datagrams {
+            debug!("Ignoring empty QoS datagram from {}", addr);
+        }
+        return;
+    }
+
+    if config.maintenance_mode {
+        debug!("Dropping QoS message from {} during maintenance", addr);
         return;
     }
 
+    // Firewall messages are exactly the id and secret
+    if buffer.len() == FIREWALL_MESSAGE_SIZE {
+        if PORT_SWAP_DETECTOR.record_mismatch() {
+            warn!(
+                "QoS server on port {} keeps receiving firewall shaped messages, \
+                 the udp_port_1 and udp_port_2 config values may be swapped",
+                config.udp_port_1
+            );
+        }
+    } else if buffer.len() >= QOS_HEADER_SIZE {
+        PORT_SWAP_DETECTOR.record_match();
+    }
+
+    let byte_order = ByteOrder::resolve(config.request_byte_order, &buffer);
+    if byte_order == ByteOrder::Little {
+        debug!("Parsing QoS request from {} as little-endian", addr);
+    }
+
+    let header = match QosHeader::from_slice(&buffer, byte_order) {
+        Ok(value) => value,
+        Err(err) => {
+            error!(
+                "Client didn't send a valid header ({}): {:?}",
+                err,
+                buffer.as_ref()
+            );
+            return;
+        }
+    };
+
     let addr = match addr {
         SocketAddr::V4(addr) => addr,
         SocketAddr::V6(_) => {
@@ -164,28 +583,56 @@ async fn handle(
         }
     };
 
-    let header = QosHeader::from_buffer(&mut buffer);
+    // Address of this server for captured packets
+    let server_addr = SocketAddrV4::new(config.self_address, config.udp_port_1);
+
+    if pcap::is_enabled() {
+        pcap::record(addr, server_addr, &buffer).await;
+    }
+
+    // Skip past the already parsed header
+    buffer.advance(QOS_HEADER_SIZE);
+
+    // Both versions start with a u32 after the header (timestamp or probe count)
+    if buffer.len() < REQUEST_FIELD_SIZE {
+        warn!(
+            "Discarding QoS message from {} with {} bytes after the header, expected at least {}",
+            addr,
+            buffer.len(),
+            REQUEST_FIELD_SIZE
+        );
+        return;
+    }
+
     let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
     let mut out: BytesMut = BytesMut::new();
 
-    let mut public_ip = *addr.ip();
-    // Only lookup public address of server if in debug mode
-    if cfg!(debug_assertions) && (public_ip.is_loopback() || public_ip.is_private()) {
-        if let Some(ip) = public_address().await {
-            public_ip = ip;
+    let public_ip = response_ip(&config, addr).await;
+
+    if header.is_address_query() {
+        let request = QosRequestV1::from_buffer(&mut buffer, byte_order);
+
+        let mut response_header = header.clone();
+        let mut timestamp_ns = None;
+
+        if let Some(flags) = config.qos_response_flags {
+            response_header.u1 = flags;
         }
-    }
 
-    if header.request_id == 1 && header.request_secret == 0 {
-        let request = QosRequestV1::from_buffer(&mut buffer);
+        if config.extended_timestamp {
+            response_header.u1 |= EXTENDED_TIMESTAMP_FLAG;
+            timestamp_ns = Some(time.as_nanos() as u64);
+        }
 
         let response = QosResponseV1 {
-            header: header.clone(),
+            header: response_header,
             timestamp: request.timestamp,
+            timestamp_ns,
             // ip: *addr.ip(),
             ip: public_ip,
             port: addr.port(),
+            trailer: config.qos_v1_trailer,
         };
         debug!(
             "RECV: {:?} AT: {:?}  DATA: {:?} RESP: {:?}",
@@ -197,19 +644,150 @@ async fn handle(
 
         response.write(&mut out);
     } else {
-        let request = QosRequestV2::from_buffer(&mut buffer);
+        let request = QosRequestV2::from_buffer(&mut buffer, byte_order);
+
+        let payload_len = request.payload.len();
+        if payload_len < config.probe_size_min || payload_len > config.probe_size_max {
+            warn!(
+                "Discarding QoS v2 probe from {} with payload of {} bytes (allowed {}..={})",
+                addr, payload_len, config.probe_size_min, config.probe_size_max
+            );
+            return;
+        }
+
+        if config.probe_signing
+            && !signing::verify_probe(
+                config.probe_signing_key.as_bytes(),
+                &header,
+                request.probe_count,
+                &request.payload,
+            )
+        {
+            debug!(
+                "Rejecting QoS v2 probe from {} with invalid signature: {} {}",
+                addr, header.request_id, header.request_secret
+            );
+            metrics::INVALID_PROBE_SIGNATURES.inc();
+            return;
+        }
+
+        let max_lifetime = Duration::from_secs(config.max_flow_lifetime_secs);
+        let local_port = receive_port(&*socket, config.udp_port_1);
+
+        // Check the session exists before taking the write lock to record the probe
+        let probe_result = if service
+            .session_exists(header.request_id, header.request_secret)
+            .await
+        {
+            service
+                .record_probe(
+                    header.request_id,
+                    header.request_secret,
+                    header.probe_number,
+                    QOS_HEADER_SIZE + REQUEST_FIELD_SIZE + payload_len,
+                    local_port,
+                    max_lifetime,
+                )
+                .await
+        } else {
+            ProbeResult::UnknownSession
+        };
+
+        match probe_result {
+            ProbeResult::Recorded => {
+                session_event_log::record(ProbeArrivalRecord {
+                    timestamp_ms: time.as_millis() as u64,
+                    session_id: header.request_id,
+                    probe_number: header.probe_number,
+                    addr,
+                })
+                .await;
+            }
+            ProbeResult::UnknownSession => {
+                debug!(
+                    "Probe for unknown QoS session: {} {}",
+                    header.request_id, header.request_secret
+                );
+
+                if config.invalid_flow_response {
+                    QosInvalidResponse { header }.write(&mut out);
+                    send_response(&socket, addr, server_addr, &out).await;
+                    return;
+                }
+            }
+            ProbeResult::Expired(age) => {
+                warn!(
+                    "Dropping probe for QoS session {} {} that exceeded its maximum lifetime (age: {}s)",
+                    header.request_id,
+                    header.request_secret,
+                    age.as_secs()
+                );
+                metrics::EXPIRED_FLOW_PROBES.inc();
+                return;
+            }
+            ProbeResult::Duplicate => match config.duplicate_probe_policy {
+                DuplicateProbePolicy::Anomaly => warn!(
+                    "Duplicate probe {} for QoS session {} {} from {}",
+                    header.probe_number, header.request_id, header.request_secret, addr
+                ),
+                _ => {
+                    debug!(
+                        "Ignoring duplicate probe {} for QoS session {} {}",
+                        header.probe_number, header.request_id, header.request_secret
+                    );
+                    return;
+                }
+            },
+            ProbeResult::PortMismatch(expected) => {
+                warn!(
+                    "Ignoring probe {} for QoS session {} {} that arrived on port {} instead of {}",
+                    header.probe_number,
+                    header.request_id,
+                    header.request_secret,
+                    local_port,
+                    expected
+                );
+                return;
+            }
+        }
+
+        let ubps = if config.estimate_ubps {
+            request.estimate_ubps().unwrap_or(QOS_RESPONSE_UBPS)
+        } else {
+            QOS_RESPONSE_UBPS
+        };
 
         let mut payload = request.payload.clone();
 
-        // Drop 6 bytes from the payload to fit the ubps and port1
-        payload.truncate(payload.len() - 6);
+        // Drop bytes from the payload to fit the ubps and port1
+        safe_truncate(&mut payload, V2_RESPONSE_FIELDS_SIZE);
+
+        // Pad tiny payloads so the response is large enough to rate
+        if payload.len() < config.response_payload_floor {
+            debug!(
+                "Padding QoS response payload to {} from {} bytes to floor of {} bytes",
+                addr,
+                payload.len(),
+                config.response_payload_floor
+            );
+            payload.resize(config.response_payload_floor, 0);
+        }
+
+        let mut response_header = header.clone();
+        let mut receive_timestamp_ns = None;
+
+        if config.v2_receive_timestamp {
+            response_header.u1 |= RECEIVE_TIMESTAMP_FLAG;
+            receive_timestamp_ns = Some(time.as_nanos() as u64);
+        }
 
         let response = QosResponseV2 {
-            header: header.clone(),
+            header: response_header,
             probe_count: request.probe_count,
-            ubps: u32::from_be_bytes([0x00, 0x5b, 0x8d, 0x80]),
+            ubps,
             port: addr.port(),
             payload,
+            receive_timestamp_ns,
         };
 
         debug!(
@@ -222,10 +800,163 @@ async fn handle(
         response.write(&mut out);
     }
 
-    if let Err(err) = socket.send_to(&out, addr).await {
-        // TODO: Handle server unable to reach
-        error!("Unable to return message to client {}: {}", addr, err);
+    // Simulate path MTU constraints by truncating the response
+    if let Some(mtu) = config.response_mtu {
+        if out.len() > mtu {
+            debug!(
+                "Truncating QoS response to {} from {} bytes to MTU of {} bytes",
+                addr,
+                out.len(),
+                mtu
+            );
+            out.truncate(mtu);
+        }
+    }
+
+    // Apply any artificial delay for simulating network conditions
+    let delay = config.response_delay();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
     }
+
+    send_response(&socket, addr, server_addr, &out).await;
+}
+
+/// Determines the address of the client `addr` to report in responses
+async fn response_ip(config: &Config, addr: SocketAddrV4) -> Ipv4Addr {
+    let client_ip = *addr.ip();
+
+    // Clients on the same network as the server aren't seen through their public address
+    if client_ip.is_loopback() || client_ip.is_private() {
+        return match config.probe_response_ip_source {
+            IpSource::ClientSource => client_ip,
+            IpSource::Configured => config.self_address,
+            IpSource::Dynamic => public_address().await.unwrap_or(client_ip),
+        };
+    }
+
+    if config.shared_public_ip_policy == SharedPublicIpPolicy::SelfAddress
+        && public_address().await == Some(client_ip)
+    {
+        // The public address is unusable from behind the same NAT as the server
+        debug!(
+            "Client {} shares the server public address, responding with {}",
+            addr, config.self_address
+        );
+        return config.self_address;
+    }
+
+    client_ip
+}
+
+/// Removes `n` bytes from the end of `buffer`, saturating at an empty
+/// buffer and logging when the buffer was shorter than `n` bytes
+fn safe_truncate(buffer: &mut BytesMut, n: usize) {
+    match buffer.len().checked_sub(n) {
+        Some(len) => buffer.truncate(len),
+        None => {
+            warn!(
+                "Buffer of {} bytes is too short to remove {} bytes, emptying it",
+                buffer.len(),
+                n
+            );
+            buffer.clear();
+        }
+    }
+}
+
+/// Socket with a local address that can be looked up
+trait LocalAddr {
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+impl LocalAddr for UdpSocket {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Port the `socket` received on, falling back to the configured `port`
+/// when the local address of the socket can't be determined
+fn receive_port(socket: &impl LocalAddr, port: u16) -> u16 {
+    match socket.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(err) => {
+            warn!(
+                "Unable to determine QoS socket local address, assuming port {}: {}",
+                port, err
+            );
+            port
+        }
+    }
+}
+
+/// Sends a response to the client at `addr` recording it in the packet capture
+async fn send_response(
+    socket: &UdpSocket,
+    addr: SocketAddrV4,
+    server_addr: SocketAddrV4,
+    out: &[u8],
+) {
+    if let Err(err) = socket.send_to(out, addr).await {
+        if is_message_too_large(&err) {
+            warn!(
+                "Response of {} bytes to {} is too large to send without fragmenting",
+                out.len(),
+                addr
+            );
+        } else {
+            // TODO: Handle server unable to reach
+            error!("Unable to return message to client {}: {}", addr, err);
+        }
+    }
+
+    pcap::record(server_addr, addr, out).await;
+}
+
+/// Sets the don't fragment bit on packets sent from the `socket` for path
+/// MTU discovery, packets too large for the path then fail to send with
+/// EMSGSIZE instead of being fragmented. Only supported on Linux
+#[cfg(target_os = "linux")]
+pub fn apply_no_fragment(socket: &impl std::os::fd::AsRawFd) {
+    let value: libc::c_int = libc::IP_PMTUDISC_DO;
+    // SAFETY: The option value is a valid c_int that outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        warn!(
+            "Failed to set IP_MTU_DISCOVER on UDP socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Sets the don't fragment bit on packets sent from the `socket`, only
+/// supported on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn apply_no_fragment<S>(_socket: &S) {
+    warn!("UDP no fragment is only supported on Linux, packets may be fragmented");
+}
+
+/// Whether sending failed with `err` because the packet was too
+/// large to send without fragmenting
+fn is_message_too_large(err: &std::io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    let too_large = err.raw_os_error() == Some(libc::EMSGSIZE);
+    #[cfg(not(target_os = "linux"))]
+    let too_large = {
+        let _ = err;
+        false
+    };
+    too_large
 }
 
 /// Caching structure for the public address value
@@ -239,6 +970,23 @@ enum PublicAddrCache {
         /// The system time the cache expires at
         expires: SystemTime,
     },
+    /// Computing the value failed
+    Failed {
+        /// The system time the value can be computed again at
+        retry_at: SystemTime,
+    },
+}
+
+impl PublicAddrCache {
+    /// Gets the cached lookup result, [None] when the value must be computed
+    fn get(&self) -> Option<Option<Ipv4Addr>> {
+        let now = SystemTime::now();
+        match self {
+            Self::Unset => None,
+            Self::Set { value, expires } => (now < *expires).then_some(Some(*value)),
+            Self::Failed { retry_at } => (now < *retry_at).then_some(None),
+        }
+    }
 }
 
 /// Cache value for storing the public address
@@ -247,23 +995,26 @@ static PUBLIC_ADDR_CACHE: RwLock<PublicAddrCache> = RwLock::const_new(PublicAddr
 /// Cache public address for 30 minutes
 const ADDR_CACHE_TIME: Duration = Duration::from_secs(60 * 30);
 
+/// Wait 30 seconds before retrying a failed public address lookup, so
+/// messages handled in the meantime don't each repeat the lookup
+const ADDR_RETRY_TIME: Duration = Duration::from_secs(30);
+
 /// Retrieves the public address of the server either using the cached
 /// value if its not expired or fetching the new value from the one of
-/// two possible APIs
-async fn public_address() -> Option<Ipv4Addr> {
-    {
-        let cached = &*PUBLIC_ADDR_CACHE.read().await;
-        if let PublicAddrCache::Set { value, expires } = cached {
-            let time = SystemTime::now();
-            if time.lt(expires) {
-                return Some(*value);
-            }
-        }
+/// two possible APIs. Failed lookups are cached for a short time
+pub async fn public_address() -> Option<Ipv4Addr> {
+    if let Some(value) = PUBLIC_ADDR_CACHE.read().await.get() {
+        return value;
     }
 
     // Hold the write lock to prevent others from attempting to update aswell
     let cached = &mut *PUBLIC_ADDR_CACHE.write().await;
 
+    // Another lookup may have completed while waiting for the lock
+    if let Some(value) = cached.get() {
+        return value;
+    }
+
     // API addresses for IP lookup
     let addresses = ["https://api.ipify.org/", "https://ipv4.icanhazip.com/"];
     let mut value: Option<Ipv4Addr> = None;
@@ -286,7 +1037,16 @@ async fn public_address() -> Option<Ipv4Addr> {
         }
     }
 
-    let value = value?;
+    let Some(value) = value else {
+        warn!(
+            "Failed to lookup public address, retrying in {}s",
+            ADDR_RETRY_TIME.as_secs()
+        );
+        *cached = PublicAddrCache::Failed {
+            retry_at: SystemTime::now() + ADDR_RETRY_TIME,
+        };
+        return None;
+    };
 
     // Update cached value with the new address
 
@@ -297,3 +1057,691 @@ async fn public_address() -> Option<Ipv4Addr> {
 
     Some(value)
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use bytes::{BufMut, BytesMut};
+    use log::Level;
+    use tokio::{net::UdpSocket, sync::RwLockReadGuard, time};
+
+    use super::{
+        dispatch, handle, public_address, receive_port, response_ip, safe_truncate, ByteOrder,
+        IpSource, LocalAddr, PublicAddrCache, QosHeader, QosRequestV1, QosRequestV2,
+        RequestByteOrder, SharedPublicIpPolicy, EXTENDED_TIMESTAMP_FLAG, INVALID_REQUEST_FLAG,
+        PUBLIC_ADDR_CACHE, QOS_HEADER_SIZE, RECEIVE_TIMESTAMP_FLAG,
+    };
+    use crate::{
+        blocklist::BlocklistChecker,
+        config::Config,
+        logging::capture,
+        metrics,
+        service::{DuplicateProbePolicy, PortMismatchPolicy, QService},
+        session_store::InMemorySessionStore,
+        shutdown, warmup,
+    };
+
+    /// Time to wait for a response before assuming none was sent
+    const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// QoS server handling messages from a single loopback client
+    struct Harness {
+        service: Arc<QService>,
+        config: Arc<Config>,
+        server: Arc<UdpSocket>,
+        client: UdpSocket,
+        /// Keeps warmup from being reset while the harness is in use
+        _warmup: Option<RwLockReadGuard<'static, ()>>,
+        /// Keeps the metrics from being reset while the harness is in use
+        _metrics: RwLockReadGuard<'static, ()>,
+    }
+
+    impl Harness {
+        /// Creates a server with the default config adjusted by `update`,
+        /// loopback clients are given their own address in v1 responses
+        async fn new(update: impl FnOnce(&mut Config)) -> Self {
+            let guard = warmup::TEST_LOCK.read().await;
+
+            // Handlers wait for warmup, which completes immediately without a delay
+            warmup::run(Arc::new(Config::default())).await;
+
+            let mut harness = Self::warming_up(update).await;
+            harness._warmup = Some(guard);
+            harness
+        }
+
+        /// Creates a server like [Harness::new] without completing warmup
+        async fn warming_up(update: impl FnOnce(&mut Config)) -> Self {
+            let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let mut config = Config {
+                udp_port_1: server.local_addr().unwrap().port(),
+                probe_response_ip_source: IpSource::ClientSource,
+                ..Default::default()
+            };
+            update(&mut config);
+
+            let service = Arc::new(QService::new(
+                &config,
+                Box::new(InMemorySessionStore::default()),
+            ));
+
+            Self {
+                service,
+                config: Arc::new(config),
+                server: Arc::new(server),
+                client,
+                _warmup: None,
+                _metrics: metrics::TEST_LOCK.read().await,
+            }
+        }
+
+        fn client_addr(&self) -> SocketAddr {
+            self.client.local_addr().unwrap()
+        }
+
+        /// Creates a latency session for the client
+        async fn create_session(&self) -> (u32, u32) {
+            self.service
+                .create_request_data(1, 5, self.client_addr().port(), 2, self.client_addr())
+                .await
+                .unwrap()
+        }
+
+        /// Handles `message` as if it was received from the client
+        async fn handle(&self, message: BytesMut) {
+            handle(
+                self.service.clone(),
+                self.config.clone(),
+                self.server.clone(),
+                self.client_addr(),
+                message,
+            )
+            .await;
+        }
+
+        /// Receives the next response sent to the client, [None]
+        /// if no response arrives in time
+        async fn recv(&self) -> Option<Vec<u8>> {
+            let mut buffer = [0u8; 2048];
+            let (length, _) = time::timeout(RESPONSE_TIMEOUT, self.client.recv_from(&mut buffer))
+                .await
+                .ok()?
+                .unwrap();
+            Some(buffer[..length].to_vec())
+        }
+    }
+
+    /// Creates a v1 address query message
+    fn address_query(probe_number: u32) -> BytesMut {
+        let mut message = BytesMut::new();
+        QosHeader {
+            u1: 0,
+            request_id: 1,
+            request_secret: 0,
+            probe_number,
+        }
+        .write(&mut message);
+        // Client timestamp
+        message.put_u32(0);
+        message
+    }
+
+    /// Creates a v2 probe message for the session `id` and `secret`
+    fn probe(id: u32, secret: u32, probe_number: u32, payload: &[u8]) -> BytesMut {
+        let mut message = BytesMut::new();
+        QosHeader {
+            u1: 0,
+            request_id: id,
+            request_secret: secret,
+            probe_number,
+        }
+        .write(&mut message);
+        // Probe count
+        message.put_u32(5);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    /// Probe number of the header at the start of a `response`
+    fn response_probe_number(response: &[u8]) -> u32 {
+        u32::from_be_bytes(response[12..QOS_HEADER_SIZE].try_into().unwrap())
+    }
+
+    /// Once shutdown begins new messages are dropped and counted while
+    /// handlers that were already in-flight still send their responses
+    #[tokio::test]
+    async fn shutdown_drops_new_messages_and_drains_in_flight() {
+        // Delay responses so the first handler is still in-flight at shutdown
+        let harness = Harness::new(|config| config.artificial_delay_ms = 200).await;
+        let blocklist = BlocklistChecker::default();
+
+        let send = |shutting_down: bool, message: BytesMut| {
+            dispatch(
+                &harness.service,
+                &harness.config,
+                &harness.server,
+                &blocklist,
+                shutting_down,
+                harness.client_addr(),
+                &message,
+            )
+        };
+
+        send(shutdown::is_shutting_down(), address_query(1));
+        assert!(shutdown::in_flight() >= 1);
+
+        /// Resets the global shutdown flag even if the test fails
+        struct ResetShutdown;
+
+        impl Drop for ResetShutdown {
+            fn drop(&mut self) {
+                shutdown::reset();
+            }
+        }
+
+        let _reset = ResetShutdown;
+        shutdown::begin();
+        assert!(shutdown::is_shutting_down());
+
+        let dropped = metrics::SHUTDOWN_DROPPED.get();
+        send(shutdown::is_shutting_down(), address_query(2));
+        assert_eq!(metrics::SHUTDOWN_DROPPED.get(), dropped + 1);
+
+        let response = harness.recv().await.expect("in-flight response");
+        assert_eq!(response_probe_number(&response), 1);
+        assert!(harness.recv().await.is_none(), "dropped message responded");
+    }
+
+    /// Duplicate probe numbers are responded to and recorded, dropped, or
+    /// responded to without being recorded depending on the policy
+    #[tokio::test]
+    async fn duplicate_probe_policies() {
+        let cases = [
+            (DuplicateProbePolicy::Respond, true, 2),
+            (DuplicateProbePolicy::Ignore, false, 1),
+            (DuplicateProbePolicy::Anomaly, true, 1),
+        ];
+
+        for (policy, responds, recorded) in cases {
+            let harness = Harness::new(|config| config.duplicate_probe_policy = policy).await;
+            let (id, secret) = harness.create_session().await;
+
+            harness.handle(probe(id, secret, 0, &[0; 16])).await;
+            assert!(harness.recv().await.is_some(), "{:?} first probe", policy);
+
+            let duplicates = metrics::DUPLICATE_PROBES.get();
+            harness.handle(probe(id, secret, 0, &[0; 16])).await;
+            assert_eq!(
+                harness.recv().await.is_some(),
+                responds,
+                "{:?} duplicate response",
+                policy
+            );
+            assert!(metrics::DUPLICATE_PROBES.get() > duplicates);
+
+            let data = harness.service.get_request_data(id, secret).await.unwrap();
+            assert_eq!(data.probes_received, recorded, "{:?} recorded", policy);
+        }
+    }
+
+    /// Responses to tiny probes have their payload zero-padded up to the floor
+    #[tokio::test]
+    async fn response_payload_padded_to_floor() {
+        let harness = Harness::new(|config| config.response_payload_floor = 64).await;
+        let (id, secret) = harness.create_session().await;
+
+        harness
+            .handle(probe(id, secret, 0, &[1, 2, 3, 4, 5, 6, 7, 8]))
+            .await;
+        let response = harness.recv().await.expect("probe response");
+
+        // Header, probe count, ubps and port precede the payload
+        let payload = &response[QOS_HEADER_SIZE + 10..];
+        assert_eq!(payload.len(), 64);
+        // The payload keeps what's left after the ubps and port fields
+        assert_eq!(&payload[..2], &[1, 2]);
+        assert!(payload[2..].iter().all(|value| *value == 0));
+    }
+
+    /// Clients sharing the public address of the server are given the self
+    /// address under the self address policy, failed public address lookups
+    /// are cached rather than repeated for every message
+    #[tokio::test]
+    async fn shared_public_ip_uses_self_address() {
+        let public_ip = Ipv4Addr::new(203, 0, 113, 7);
+        let self_address = Ipv4Addr::new(10, 0, 0, 5);
+        let shared_client = SocketAddrV4::new(public_ip, 3659);
+        let other_client = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 1), 3659);
+
+        /// Clears the faked public address even if the test fails
+        struct ResetCache;
+
+        impl Drop for ResetCache {
+            fn drop(&mut self) {
+                if let Ok(mut cache) = PUBLIC_ADDR_CACHE.try_write() {
+                    *cache = PublicAddrCache::Unset;
+                }
+            }
+        }
+
+        let _reset = ResetCache;
+        *PUBLIC_ADDR_CACHE.write().await = PublicAddrCache::Set {
+            value: public_ip,
+            expires: SystemTime::now() + Duration::from_secs(60),
+        };
+
+        let mut config = Config {
+            self_address,
+            shared_public_ip_policy: SharedPublicIpPolicy::SelfAddress,
+            ..Default::default()
+        };
+        assert_eq!(response_ip(&config, shared_client).await, self_address);
+        assert_eq!(response_ip(&config, other_client).await, *other_client.ip());
+
+        config.shared_public_ip_policy = SharedPublicIpPolicy::Echo;
+        assert_eq!(response_ip(&config, shared_client).await, public_ip);
+
+        // A cached failure is returned without attempting another lookup
+        *PUBLIC_ADDR_CACHE.write().await = PublicAddrCache::Failed {
+            retry_at: SystemTime::now() + Duration::from_secs(60),
+        };
+        let lookup = time::timeout(Duration::from_millis(50), public_address()).await;
+        assert_eq!(lookup, Ok(None));
+    }
+
+    /// Truncating removes bytes from the end and empties buffers that are
+    /// too short rather than panicking
+    #[test]
+    fn safe_truncate_lengths() {
+        let mut buffer = BytesMut::from(&[1u8, 2, 3, 4, 5, 6, 7, 8][..]);
+        safe_truncate(&mut buffer, 6);
+        assert_eq!(&buffer[..], &[1, 2]);
+
+        let mut buffer = BytesMut::from(&[1u8, 2, 3, 4, 5, 6][..]);
+        safe_truncate(&mut buffer, 6);
+        assert!(buffer.is_empty());
+
+        let mut buffer = BytesMut::from(&[1u8, 2, 3][..]);
+        safe_truncate(&mut buffer, 6);
+        assert!(buffer.is_empty());
+    }
+
+    /// V2 probes with payloads too small to hold the response fields are
+    /// discarded without a response or being recorded
+    #[tokio::test]
+    async fn tiny_v2_probe_discarded() {
+        let harness = Harness::new(|_| {}).await;
+        let (id, secret) = harness.create_session().await;
+
+        harness.handle(probe(id, secret, 0, &[0; 4])).await;
+        assert!(harness.recv().await.is_none(), "tiny probe responded");
+
+        let data = harness.service.get_request_data(id, secret).await.unwrap();
+        assert_eq!(data.probes_received, 0);
+    }
+
+    /// Socket whose local address lookup gives a fixed result
+    struct MockSocket(Option<SocketAddr>);
+
+    impl LocalAddr for MockSocket {
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.0
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "mock"))
+        }
+    }
+
+    /// The receive port comes from the local address of the socket, or
+    /// the configured port when the local address lookup fails
+    #[test]
+    fn receive_port_falls_back_on_local_addr_error() {
+        let bound = MockSocket(Some(SocketAddr::from(([127, 0, 0, 1], 17001))));
+        assert_eq!(receive_port(&bound, 17000), 17001);
+
+        let failing = MockSocket(None);
+        assert_eq!(receive_port(&failing, 17000), 17000);
+    }
+
+    /// Messages received during warmup are held and handled once it completes
+    #[tokio::test]
+    async fn messages_held_during_warmup() {
+        let _guard = warmup::TEST_LOCK.write().await;
+        warmup::reset();
+
+        let harness = Arc::new(Harness::warming_up(|_| {}).await);
+        let handler = tokio::spawn({
+            let harness = harness.clone();
+            async move { harness.handle(address_query(7)).await }
+        });
+
+        assert!(harness.recv().await.is_none(), "responded during warmup");
+        assert!(!handler.is_finished(), "message dropped during warmup");
+
+        warmup::run(Arc::new(Config::default())).await;
+        let response = harness.recv().await.expect("held message response");
+        assert_eq!(response_probe_number(&response), 7);
+        handler.await.unwrap();
+    }
+
+    /// Only request ID 1 with secret 0 is an address query
+    #[test]
+    fn address_query_headers() {
+        let header = |request_id: u32, request_secret: u32| QosHeader {
+            u1: 0,
+            request_id,
+            request_secret,
+            probe_number: 0,
+        };
+
+        assert!(header(1, 0).is_address_query());
+        assert!(!header(1, 1).is_address_query());
+        assert!(!header(2, 0).is_address_query());
+        assert!(!header(0x4000, 0x1234).is_address_query());
+    }
+
+    /// Probes for flows older than the maximum flow lifetime are dropped
+    /// and counted without being recorded or responded to
+    #[tokio::test]
+    async fn aged_flow_probe_rejected() {
+        // Every flow is aged out as soon as any time has passed
+        let harness = Harness::new(|config| config.max_flow_lifetime_secs = 0).await;
+        let (id, secret) = harness.create_session().await;
+        time::sleep(Duration::from_millis(10)).await;
+
+        let expired = metrics::EXPIRED_FLOW_PROBES.get();
+        harness.handle(probe(id, secret, 0, &[0; 16])).await;
+        assert!(harness.recv().await.is_none(), "aged flow responded");
+        assert!(metrics::EXPIRED_FLOW_PROBES.get() > expired);
+
+        let data = harness.service.get_request_data(id, secret).await.unwrap();
+        assert_eq!(data.probes_received, 0);
+    }
+
+    /// Responses larger than the response MTU are truncated to it while
+    /// smaller responses are sent in full
+    #[tokio::test]
+    async fn response_truncated_to_mtu() {
+        // Header, probe count, ubps and port precede the remaining payload
+        let full_length = QOS_HEADER_SIZE + 10 + 194;
+
+        let harness = Harness::new(|config| config.response_mtu = Some(64)).await;
+        let (id, secret) = harness.create_session().await;
+        harness.handle(probe(id, secret, 0, &[7; 200])).await;
+        let response = harness.recv().await.expect("truncated response");
+        assert_eq!(response.len(), 64);
+        assert_eq!(response_probe_number(&response), 0);
+        assert!(response[QOS_HEADER_SIZE + 10..]
+            .iter()
+            .all(|value| *value == 7));
+
+        let harness = Harness::new(|config| config.response_mtu = Some(1500)).await;
+        let (id, secret) = harness.create_session().await;
+        harness.handle(probe(id, secret, 0, &[7; 200])).await;
+        let response = harness.recv().await.expect("full response");
+        assert_eq!(response.len(), full_length);
+    }
+
+    /// V1 responses end with the configured trailer bytes, zeros by default
+    #[tokio::test]
+    async fn v1_response_trailer_bytes() {
+        let trailer = [0xde, 0xad, 0xbe, 0xef];
+        let harness = Harness::new(|config| config.qos_v1_trailer = trailer).await;
+        harness.handle(address_query(0)).await;
+        let response = harness.recv().await.expect("address query response");
+        // Header, timestamp, address and port precede the trailer
+        assert_eq!(response.len(), QOS_HEADER_SIZE + 14);
+        assert_eq!(&response[QOS_HEADER_SIZE + 10..], &trailer);
+
+        let harness = Harness::new(|_| {}).await;
+        harness.handle(address_query(0)).await;
+        let response = harness.recv().await.expect("address query response");
+        assert_eq!(&response[QOS_HEADER_SIZE + 10..], &[0; 4]);
+    }
+
+    /// Empty datagrams are counted and ignored without a response, only
+    /// logged at debug level and not at all when their logging is disabled
+    #[tokio::test]
+    async fn empty_datagram_ignored_quietly() {
+        for log_empty in [true, false] {
+            let harness = Harness::new(|config| config.log_empty_datagrams = log_empty).await;
+
+            let empty = metrics::EMPTY_DATAGRAMS.get();
+            capture::start();
+            harness.handle(BytesMut::new()).await;
+            let records = capture::take();
+
+            assert!(metrics::EMPTY_DATAGRAMS.get() > empty);
+            assert!(harness.recv().await.is_none(), "empty datagram responded");
+            assert!(
+                records.iter().all(|(level, _)| *level >= Level::Debug),
+                "{:?}",
+                records
+            );
+            assert_eq!(
+                records
+                    .iter()
+                    .any(|(_, message)| message.starts_with("Ignoring empty QoS datagram")),
+                log_empty
+            );
+        }
+    }
+
+    /// A known little-endian request is parsed correctly when the little
+    /// byte order is configured or detected from the header
+    #[test]
+    fn little_endian_request_parsed() {
+        #[rustfmt::skip]
+        let bytes: [u8; 24] = [
+            // u1 = 2
+            0x02, 0x00, 0x00, 0x00,
+            // request_id = 0x00012345
+            0x45, 0x23, 0x01, 0x00,
+            // request_secret = 0xDEADBEEF
+            0xEF, 0xBE, 0xAD, 0xDE,
+            // probe_number = 3
+            0x03, 0x00, 0x00, 0x00,
+            // v1 timestamp / v2 probe count = 0x0A0B0C0D
+            0x0D, 0x0C, 0x0B, 0x0A,
+            // v2 payload
+            0xAA, 0xBB, 0xCC, 0xDD,
+        ];
+
+        for mode in [RequestByteOrder::Little, RequestByteOrder::Auto] {
+            let order = ByteOrder::resolve(mode, &bytes);
+            assert_eq!(order, ByteOrder::Little, "{:?}", mode);
+
+            let header = QosHeader::from_slice(&bytes, order).unwrap();
+            assert_eq!(header.u1, 2);
+            assert_eq!(header.request_id, 0x00012345);
+            assert_eq!(header.request_secret, 0xDEADBEEF);
+            assert_eq!(header.probe_number, 3);
+
+            let mut buffer = BytesMut::from(&bytes[QOS_HEADER_SIZE..QOS_HEADER_SIZE + 4]);
+            let request = QosRequestV1::from_buffer(&mut buffer, order);
+            assert_eq!(request.timestamp, 0x0A0B0C0D);
+
+            let mut buffer = BytesMut::from(&bytes[QOS_HEADER_SIZE..]);
+            let request = QosRequestV2::from_buffer(&mut buffer, order);
+            assert_eq!(request.probe_count, 0x0A0B0C0D);
+            // The payload is passed through as-is
+            assert_eq!(&request.payload[..], &[0xAA, 0xBB, 0xCC, 0xDD]);
+        }
+
+        // The default big-endian framing misreads the request
+        let order = ByteOrder::resolve(RequestByteOrder::Big, &bytes);
+        assert_eq!(order, ByteOrder::Big);
+        let header = QosHeader::from_slice(&bytes, order).unwrap();
+        assert_eq!(header.u1, 0x02000000);
+
+        // Big-endian requests are still detected as such
+        let mut big = BytesMut::new();
+        QosHeader {
+            u1: 2,
+            request_id: 0x00012345,
+            request_secret: 0xDEADBEEF,
+            probe_number: 3,
+        }
+        .write(&mut big);
+        assert_eq!(
+            ByteOrder::resolve(RequestByteOrder::Auto, &big),
+            ByteOrder::Big
+        );
+    }
+
+    /// Maintenance mode drops QoS messages without responding to or
+    /// recording them
+    #[tokio::test]
+    async fn maintenance_mode_drops_messages() {
+        let harness = Harness::new(|config| config.maintenance_mode = true).await;
+        let (id, secret) = harness.create_session().await;
+
+        harness.handle(address_query(0)).await;
+        assert!(harness.recv().await.is_none(), "address query responded");
+
+        harness.handle(probe(id, secret, 0, &[0; 16])).await;
+        assert!(harness.recv().await.is_none(), "probe responded");
+
+        let data = harness.service.get_request_data(id, secret).await.unwrap();
+        assert_eq!(data.probes_received, 0);
+    }
+
+    /// Probes arriving on a different port than their flow expects are
+    /// counted and either recorded and responded to or dropped
+    #[tokio::test]
+    async fn port_mismatch_policies() {
+        let cases = [
+            (PortMismatchPolicy::Respond, true, 1),
+            (PortMismatchPolicy::Ignore, false, 0),
+        ];
+
+        for (policy, responds, recorded) in cases {
+            // Flows expect a port other than the one the server is bound to
+            let harness = Harness::new(|config| {
+                config.udp_port_1 = 1;
+                config.port_mismatch_policy = policy;
+            })
+            .await;
+            let (id, secret) = harness.create_session().await;
+
+            let mismatches = metrics::PROBE_PORT_MISMATCHES.get();
+            capture::start();
+            harness.handle(probe(id, secret, 0, &[0; 16])).await;
+            let records = capture::take();
+
+            assert!(metrics::PROBE_PORT_MISMATCHES.get() > mismatches);
+            assert!(
+                records
+                    .iter()
+                    .any(|(level, message)| *level == Level::Warn
+                        && message.contains("instead of 1")),
+                "{:?} {:?}",
+                policy,
+                records
+            );
+            assert_eq!(harness.recv().await.is_some(), responds, "{:?}", policy);
+
+            let data = harness.service.get_request_data(id, secret).await.unwrap();
+            assert_eq!(data.probes_received, recorded, "{:?}", policy);
+        }
+    }
+
+    /// Probes for unknown flows are answered with the invalid request
+    /// response when enabled and the regular response otherwise
+    #[tokio::test]
+    async fn invalid_response_for_unknown_flow() {
+        let harness = Harness::new(|config| config.invalid_flow_response = true).await;
+        harness.handle(probe(1234, 5678, 3, &[0; 16])).await;
+        let response = harness.recv().await.expect("invalid request response");
+
+        // Only the request header with the invalid request flag set
+        assert_eq!(response.len(), QOS_HEADER_SIZE);
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1, INVALID_REQUEST_FLAG);
+        assert_eq!(header.request_id, 1234);
+        assert_eq!(header.request_secret, 5678);
+        assert_eq!(header.probe_number, 3);
+
+        let harness = Harness::new(|config| config.invalid_flow_response = false).await;
+        harness.handle(probe(1234, 5678, 3, &[0; 16])).await;
+        let response = harness.recv().await.expect("regular response");
+        assert!(response.len() > QOS_HEADER_SIZE);
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1 & INVALID_REQUEST_FLAG, 0);
+    }
+
+    /// Nanoseconds since the unix epoch
+    fn unix_nanos() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+
+    /// V2 responses include the receive timestamp after the payload with
+    /// the receive timestamp flag set only when enabled
+    #[tokio::test]
+    async fn v2_receive_timestamp_flag() {
+        // Header, probe count, ubps and port precede the payload
+        let payload_start = QOS_HEADER_SIZE + 10;
+        let payload_len = 16 - 6;
+
+        let harness = Harness::new(|config| config.v2_receive_timestamp = true).await;
+        let (id, secret) = harness.create_session().await;
+        let before = unix_nanos();
+        harness.handle(probe(id, secret, 0, &[0; 16])).await;
+        let after = unix_nanos();
+        let response = harness.recv().await.expect("probe response");
+
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1 & RECEIVE_TIMESTAMP_FLAG, RECEIVE_TIMESTAMP_FLAG);
+        assert_eq!(response.len(), payload_start + payload_len + 8);
+        let timestamp =
+            u64::from_be_bytes(response[payload_start + payload_len..].try_into().unwrap());
+        assert!((before..=after).contains(&timestamp), "{}", timestamp);
+
+        let harness = Harness::new(|_| {}).await;
+        let (id, secret) = harness.create_session().await;
+        harness.handle(probe(id, secret, 0, &[0; 16])).await;
+        let response = harness.recv().await.expect("probe response");
+
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1 & RECEIVE_TIMESTAMP_FLAG, 0);
+        assert_eq!(response.len(), payload_start + payload_len);
+    }
+
+    /// V1 responses replace the timestamp with the 8 byte receive time
+    /// with the extended timestamp flag set only when enabled
+    #[tokio::test]
+    async fn v1_extended_timestamp_flag() {
+        let harness = Harness::new(|config| config.extended_timestamp = true).await;
+        let before = unix_nanos();
+        harness.handle(address_query(0)).await;
+        let after = unix_nanos();
+        let response = harness.recv().await.expect("address query response");
+
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1 & EXTENDED_TIMESTAMP_FLAG, EXTENDED_TIMESTAMP_FLAG);
+        // Timestamp, address, port and trailer follow the header
+        assert_eq!(response.len(), QOS_HEADER_SIZE + 8 + 10);
+        let timestamp = u64::from_be_bytes(
+            response[QOS_HEADER_SIZE..QOS_HEADER_SIZE + 8]
+                .try_into()
+                .unwrap(),
+        );
+        assert!((before..=after).contains(&timestamp), "{}", timestamp);
+
+        let harness = Harness::new(|_| {}).await;
+        harness.handle(address_query(0)).await;
+        let response = harness.recv().await.expect("address query response");
+
+        let header = QosHeader::from_slice(&response, ByteOrder::Big).unwrap();
+        assert_eq!(header.u1 & EXTENDED_TIMESTAMP_FLAG, 0);
+        assert_eq!(response.len(), QOS_HEADER_SIZE + 4 + 10);
+    }
+}
@@ -0,0 +1,156 @@
+//! Runtime the UDP QoS and firewall servers run on. By default the servers
+//! share the runtime of the HTTP server, with the `udp_runtime_threads`
+//! config they're given a dedicated runtime so HTTP load can't delay them
+
+use std::{future::Future, io};
+
+use log::error;
+use tokio::runtime::{Builder, Runtime};
+
+/// Name of the worker threads of a dedicated UDP runtime
+pub const UDP_THREAD_NAME: &str = "qos-udp";
+
+/// Runtime for the UDP servers
+pub enum UdpRuntime {
+    /// Run on the runtime the servers are started from
+    Shared,
+    /// Run on a dedicated multi-thread runtime
+    Dedicated(Runtime),
+}
+
+impl UdpRuntime {
+    /// Creates the runtime, a dedicated runtime with the provided number
+    /// of worker `threads` is created when set
+    pub fn new(threads: Option<usize>) -> io::Result<Self> {
+        let Some(threads) = threads else {
+            return Ok(Self::Shared);
+        };
+
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name(UDP_THREAD_NAME)
+            .enable_all()
+            .build()?;
+        Ok(Self::Dedicated(runtime))
+    }
+
+    /// Runs the `firewall` and `qos` servers on this runtime until
+    /// the QoS server stops
+    pub async fn run<F, Q>(self, firewall: F, qos: Q)
+    where
+        F: Future<Output = ()> + Send + 'static,
+        Q: Future<Output = ()> + Send + 'static,
+    {
+        let runtime = match self {
+            Self::Shared => {
+                tokio::spawn(firewall);
+                qos.await;
+                return;
+            }
+            Self::Dedicated(runtime) => runtime,
+        };
+
+        runtime.spawn(firewall);
+        if let Err(err) = runtime.spawn(qos).await {
+            error!("UDP server task failed: {}", err);
+        }
+
+        // Runtimes can't be dropped from within an async context
+        runtime.shutdown_background();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{net::UdpSocket, sync::oneshot, time};
+
+    use super::{UdpRuntime, UDP_THREAD_NAME};
+
+    /// Time to wait for a server to respond
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Mock server responding to a single message with the name of
+    /// the thread that handled it, sends its port once bound
+    async fn thread_name_server(port_tx: oneshot::Sender<u16>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        _ = port_tx.send(socket.local_addr().unwrap().port());
+
+        let mut buffer = [0u8; 16];
+        let (_, addr) = socket.recv_from(&mut buffer).await.unwrap();
+        let name = std::thread::current()
+            .name()
+            .unwrap_or_default()
+            .to_string();
+        socket.send_to(name.as_bytes(), addr).await.unwrap();
+    }
+
+    /// Starts the mock firewall and QoS servers on the `runtime`
+    /// providing the task running them and the ports they're bound to
+    async fn start(runtime: UdpRuntime) -> (tokio::task::JoinHandle<()>, [u16; 2]) {
+        let (firewall_tx, firewall_rx) = oneshot::channel();
+        let (qos_tx, qos_rx) = oneshot::channel();
+        let task =
+            tokio::spawn(runtime.run(thread_name_server(firewall_tx), thread_name_server(qos_tx)));
+        let ports = [firewall_rx.await.unwrap(), qos_rx.await.unwrap()];
+        (task, ports)
+    }
+
+    /// Sends a message to the server on the `port` blocking the current
+    /// thread until it responds with the name of its thread
+    fn query_blocking(port: u16) -> String {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        socket.send_to(&[0], ("127.0.0.1", port)).unwrap();
+
+        let mut buffer = [0u8; 64];
+        let (length, _) = socket.recv_from(&mut buffer).expect("no response");
+        String::from_utf8_lossy(&buffer[..length]).into_owned()
+    }
+
+    /// Servers on a dedicated runtime keep handling traffic while the
+    /// runtime they were started from is blocked
+    #[tokio::test]
+    async fn dedicated_runtime_independent() {
+        let runtime = UdpRuntime::new(Some(2)).unwrap();
+        assert!(matches!(runtime, UdpRuntime::Dedicated(_)));
+        let (task, ports) = start(runtime).await;
+
+        // The test runtime only has this thread so it's blocked until both respond
+        for port in ports {
+            assert_eq!(query_blocking(port), UDP_THREAD_NAME);
+        }
+
+        time::timeout(TIMEOUT, task)
+            .await
+            .expect("runtime didn't stop with the QoS server")
+            .unwrap();
+    }
+
+    /// Servers on the shared runtime are handled by the runtime they
+    /// were started from
+    #[tokio::test]
+    async fn shared_runtime() {
+        let runtime = UdpRuntime::new(None).unwrap();
+        assert!(matches!(runtime, UdpRuntime::Shared));
+        let (task, ports) = start(runtime).await;
+
+        let test_thread = std::thread::current().name().unwrap().to_string();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for port in ports {
+            socket.send_to(&[0], ("127.0.0.1", port)).await.unwrap();
+            let mut buffer = [0u8; 64];
+            let (length, _) = time::timeout(TIMEOUT, socket.recv_from(&mut buffer))
+                .await
+                .expect("no response")
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&buffer[..length]), test_thread);
+        }
+
+        time::timeout(TIMEOUT, task)
+            .await
+            .expect("servers didn't stop")
+            .unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use log::debug;
+use serde::de::DeserializeOwned;
+use tower::{Layer, Service};
+
+use crate::service::MIN_SESSION_ID;
+
+/// XML body returned for requests with invalid session parameters
+pub const INVALID_SESSION_XML: &str = "<error>invalid_session_parameters</error>";
+
+/// Creates the response for requests with invalid session parameters
+pub fn invalid_session_response() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        [(header::CONTENT_TYPE, "application/xml")],
+        INVALID_SESSION_XML,
+    )
+        .into_response()
+}
+
+/// Layer that rejects requests whose `rqid` and `rqsc` query parameters
+/// could never belong to a session allocated by the server
+#[derive(Clone, Copy)]
+pub struct SessionParamsLayer;
+
+impl<S> Layer<S> for SessionParamsLayer {
+    type Service = SessionParams<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SessionParams { inner }
+    }
+}
+
+/// Service created by [SessionParamsLayer]
+#[derive(Clone)]
+pub struct SessionParams<S> {
+    /// The wrapped service
+    inner: S,
+}
+
+/// Checks whether the query string contains a valid request ID and secret
+fn is_valid_session_query(query: &str) -> bool {
+    let mut request_id: Option<u32> = None;
+    let mut request_secret: Option<u32> = None;
+
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "rqid" => request_id = value.parse().ok(),
+            "rqsc" => request_secret = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    matches!(
+        (request_id, request_secret),
+        (Some(id), Some(secret)) if id >= MIN_SESSION_ID && secret != 0
+    )
+}
+
+impl<S, B> Service<Request<B>> for SessionParams<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let query = req.uri().query().unwrap_or_default();
+
+        if !is_valid_session_query(query) {
+            debug!(
+                "Rejecting request with invalid session parameters: {}",
+                query
+            );
+            return Box::pin(async { Ok(invalid_session_response()) });
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Query string extractor that rejects malformed queries with an XML error
+/// naming the invalid field rather than the generic plain text rejection:
+///
+/// `<error><field>vers</field><message>invalid digit found in string</message></error>`
+///
+/// The field is left empty when the error isn't specific to a single
+/// field such as when a required field is missing
+pub struct QosQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for QosQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+
+        match serde_path_to_error::deserialize(deserializer) {
+            Ok(value) => Ok(QosQuery(value)),
+            Err(err) => {
+                debug!("Rejecting request with invalid query {}: {}", query, err);
+
+                // The root path is reported for errors not tied to a field
+                let field = err.path().to_string();
+                let field = if field == "." { String::new() } else { field };
+
+                Err(invalid_query_response(&field, &err.inner().to_string()))
+            }
+        }
+    }
+}
+
+/// Creates the response for requests with an invalid query `field`
+fn invalid_query_response(field: &str, message: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        [(header::CONTENT_TYPE, "application/xml")],
+        format!(
+            "<error><field>{}</field><message>{}</message></error>",
+            escape_xml(field),
+            escape_xml(message)
+        ),
+    )
+        .into_response()
+}
+
+/// Escapes the characters of `value` that can't appear in XML text
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,82 @@
+//! Warmup phase before the UDP servers begin processing messages.
+//!
+//! The servers are bound straight away but messages received while warming
+//! up are held until warmup completes, giving dependent state such as the
+//! public address time to be resolved before the first probes are handled
+
+use std::{
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::info;
+use tokio::{sync::Notify, time};
+
+use crate::{
+    config::Config,
+    udp::{self, IpSource, SharedPublicIpPolicy},
+};
+
+/// Whether warmup has completed
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Notified once warmup has completed
+static READY_NOTIFY: Notify = Notify::const_new();
+
+/// Lock held by tests depending on the warmup state, shared by tests
+/// needing warmup to have completed and exclusive to tests resetting it
+#[cfg(test)]
+pub static TEST_LOCK: tokio::sync::RwLock<()> = tokio::sync::RwLock::const_new(());
+
+/// Marks warmup as not completed so it can be run again
+#[cfg(test)]
+pub fn reset() {
+    READY.store(false, Ordering::Release);
+}
+
+/// Checks whether warmup has completed
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Waits until warmup has completed
+pub async fn wait_ready() {
+    let mut notified = pin!(READY_NOTIFY.notified());
+    loop {
+        // Registered before checking so the notification can't be missed
+        notified.as_mut().enable();
+        if is_ready() {
+            return;
+        }
+        notified.as_mut().await;
+        notified.set(READY_NOTIFY.notified());
+    }
+}
+
+/// Runs the warmup phase, resolving the public address when it's used
+/// while waiting for at least the configured warmup delay, then marks
+/// the server as ready
+pub async fn run(config: Arc<Config>) {
+    if config.warmup_delay_ms > 0 {
+        info!("Warming up for {}ms", config.warmup_delay_ms);
+
+        let resolve = async {
+            if config.probe_response_ip_source == IpSource::Dynamic
+                || config.shared_public_ip_policy == SharedPublicIpPolicy::SelfAddress
+            {
+                _ = udp::public_address().await;
+            }
+        };
+        let delay = time::sleep(Duration::from_millis(config.warmup_delay_ms));
+        tokio::join!(resolve, delay);
+
+        info!("Warmup complete, processing messages");
+    }
+
+    READY.store(true, Ordering::Release);
+    READY_NOTIFY.notify_waiters();
+}
@@ -0,0 +1,325 @@
+//! Webhook notifications sent when flows complete.
+//!
+//! Completed flows are queued and delivered by a background task so a slow
+//! or unreachable webhook can never delay QoS handling. Each notification is
+//! attempted a bounded number of times with a timeout on every attempt,
+//! notifications are dropped when the queue is full or delivery keeps failing
+
+use std::{net::SocketAddr, sync::OnceLock, time::Duration};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{config::Config, service::QRequestData};
+
+/// Maximum number of notifications waiting to be delivered
+const QUEUE_CAPACITY: usize = 256;
+/// Maximum number of delivery attempts for each notification
+const MAX_ATTEMPTS: u32 = 3;
+/// Time to wait for the webhook to respond to each attempt
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before retrying a failed delivery, doubled for each retry
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Sender for queueing notifications, unset when no webhook is configured
+static WEBHOOK_TX: OnceLock<mpsc::Sender<FlowCompletion>> = OnceLock::new();
+
+/// Kind of flow that completed
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowKind {
+    /// Latency flow that received all its probes
+    Latency,
+    /// Firewall flow whose fire type was determined
+    Firewall,
+}
+
+/// Latency statistics of a completed latency flow
+#[derive(Debug, Serialize)]
+pub struct LatencyStats {
+    /// Number of probes received
+    pub probes: u32,
+    /// Time in milliseconds from the first to the last probe
+    pub duration_ms: u64,
+    /// Mean time in milliseconds between consecutive probes
+    pub mean_gap_ms: f64,
+    /// Longest time in milliseconds between consecutive probes
+    pub max_gap_ms: f64,
+}
+
+/// Payload posted to the webhook when a flow completes
+#[derive(Debug, Serialize)]
+pub struct FlowCompletion {
+    /// Kind of flow that completed
+    pub flow: FlowKind,
+    /// Session ID of the flow
+    pub id: u32,
+    /// Address of the client the flow belongs to
+    pub client_addr: SocketAddr,
+    /// Fire type of the client for firewall flows
+    pub fire_type: Option<u32>,
+    /// Latency statistics for latency flows
+    pub latency: Option<LatencyStats>,
+}
+
+impl FlowCompletion {
+    /// Creates the notification for the completed latency flow `data`
+    pub fn latency(data: &QRequestData) -> Self {
+        let gaps = data.inter_probe_gaps();
+        let millis = |value: Duration| value.as_secs_f64() * 1000.0;
+
+        let duration_ms = match (data.first_probe_at, data.last_probe_at) {
+            (Some(first), Some(last)) => last
+                .duration_since(first)
+                .map(|value| value.as_millis() as u64)
+                .unwrap_or_default(),
+            _ => 0,
+        };
+        let mean_gap_ms = if gaps.is_empty() {
+            0.0
+        } else {
+            gaps.iter().copied().map(millis).sum::<f64>() / gaps.len() as f64
+        };
+        let max_gap_ms = gaps.iter().copied().map(millis).fold(0.0, f64::max);
+
+        Self {
+            flow: FlowKind::Latency,
+            id: data.id,
+            client_addr: data.client_addr,
+            fire_type: None,
+            latency: Some(LatencyStats {
+                probes: data.probes_received,
+                duration_ms,
+                mean_gap_ms,
+                max_gap_ms,
+            }),
+        }
+    }
+
+    /// Creates the notification for a firewall flow that
+    /// determined the `fire_type` of the client
+    pub fn firewall(id: u32, client_addr: SocketAddr, fire_type: u32) -> Self {
+        Self {
+            flow: FlowKind::Firewall,
+            id,
+            client_addr,
+            fire_type: Some(fire_type),
+            latency: None,
+        }
+    }
+}
+
+/// Starts delivering notifications to the webhook from the `config`,
+/// does nothing if no webhook is configured
+pub fn init(config: &Config) {
+    let Some(url) = config.completion_webhook.clone() else {
+        return;
+    };
+
+    let client = match reqwest::Client::builder().timeout(ATTEMPT_TIMEOUT).build() {
+        Ok(value) => value,
+        Err(err) => {
+            warn!(
+                "Failed to create webhook client, notifications disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+    if WEBHOOK_TX.set(tx).is_err() {
+        return;
+    }
+
+    info!("Sending flow completion notifications to {}", url);
+    tokio::spawn(deliver_all(client, url, rx));
+}
+
+/// Queues the `completion` for delivery to the webhook, dropping it
+/// if no webhook is configured or the queue is full
+pub fn notify(completion: FlowCompletion) {
+    let Some(tx) = WEBHOOK_TX.get() else {
+        return;
+    };
+
+    if tx.try_send(completion).is_err() {
+        warn!("Webhook queue is full, dropping flow completion notification");
+    }
+}
+
+/// Delivers queued notifications one at a time until the queue is closed
+async fn deliver_all(client: reqwest::Client, url: String, mut rx: mpsc::Receiver<FlowCompletion>) {
+    while let Some(completion) = rx.recv().await {
+        deliver(&client, &url, &completion).await;
+    }
+}
+
+/// Posts the `completion` to the webhook `url`, retrying failed attempts
+async fn deliver(client: &reqwest::Client, url: &str, completion: &FlowCompletion) {
+    let mut delay = RETRY_DELAY;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client
+            .post(url)
+            .json(completion)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => {
+                debug!(
+                    "Delivered flow completion notification for {}",
+                    completion.id
+                );
+                return;
+            }
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                debug!(
+                    "Webhook attempt {} for flow {} failed, retrying: {}",
+                    attempt, completion.id, err
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => warn!(
+                "Failed to deliver flow completion notification for {} after {} attempts: {}",
+                completion.id, MAX_ATTEMPTS, err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router, Server};
+    use serde_json::Value;
+    use tokio::{sync::mpsc, time};
+
+    use super::init;
+    use crate::{config::Config, service::QService, session_store::InMemorySessionStore};
+
+    /// Number of flows completed while the webhook is failing, more than
+    /// the notification queue can hold
+    const FAILING_FLOWS: u16 = 300;
+
+    /// Mock webhook receiver state
+    struct Receiver {
+        /// Whether requests hang instead of being accepted
+        failing: AtomicBool,
+        /// Sender for the received payloads
+        tx: mpsc::UnboundedSender<Value>,
+    }
+
+    async fn receive(
+        State(receiver): State<Arc<Receiver>>,
+        Json(payload): Json<Value>,
+    ) -> StatusCode {
+        if receiver.failing.load(Ordering::Acquire) {
+            time::sleep(Duration::from_secs(60)).await;
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        _ = receiver.tx.send(payload);
+        StatusCode::OK
+    }
+
+    /// Completes a single probe latency flow for the client at `client_addr`
+    async fn complete_flow(service: &QService, client_addr: SocketAddr) -> u32 {
+        let (id, secret) = service
+            .create_request_data(1, 1, client_addr.port(), 2, client_addr)
+            .await
+            .unwrap();
+        service
+            .record_probe(
+                id,
+                secret,
+                0,
+                60,
+                Config::default().udp_port_1,
+                Duration::from_secs(60),
+            )
+            .await;
+        id
+    }
+
+    /// Completed flows are posted to the webhook, and flows still complete
+    /// straight away while the webhook is hanging
+    #[tokio::test]
+    async fn posts_completions_without_blocking_flows() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let receiver = Arc::new(Receiver {
+            failing: AtomicBool::new(false),
+            tx,
+        });
+
+        let router = Router::new()
+            .route("/webhook", post(receive))
+            .with_state(receiver.clone());
+        let server =
+            Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(router.into_make_service());
+        let url = format!("http://{}/webhook", server.local_addr());
+        tokio::spawn(server);
+
+        init(&Config {
+            completion_webhook: Some(url),
+            ..Default::default()
+        });
+
+        let service = QService::new(
+            &Config::default(),
+            Box::new(InMemorySessionStore::default()),
+        );
+
+        // Other tests may complete flows too, so only this client is checked
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 40123));
+        let id = complete_flow(&service, client_addr).await;
+
+        let payload = time::timeout(Duration::from_secs(5), async {
+            loop {
+                let payload = rx.recv().await.unwrap();
+                if payload["client_addr"] == client_addr.to_string() {
+                    return payload;
+                }
+            }
+        })
+        .await
+        .expect("webhook payload");
+
+        assert_eq!(payload["flow"], "latency");
+        assert_eq!(payload["id"], id);
+        assert_eq!(payload["fire_type"], Value::Null);
+        assert_eq!(payload["latency"]["probes"], 1);
+        assert_eq!(payload["latency"]["duration_ms"], 0);
+
+        receiver.failing.store(true, Ordering::Release);
+
+        let start = Instant::now();
+        for port in 0..FAILING_FLOWS {
+            let client_addr = SocketAddr::from(([127, 0, 0, 1], 41000 + port));
+            complete_flow(&service, client_addr).await;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "flow completion waited on the webhook"
+        );
+
+        let completed = service
+            .list_sessions()
+            .await
+            .iter()
+            .filter(|session| session.completed_at.is_some())
+            .count();
+        assert_eq!(completed, FAILING_FLOWS as usize + 1);
+    }
+}
@@ -0,0 +1,188 @@
+//! Load test creating a large batch of latency sessions at once
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use pocket_relay_qos::{
+    config::Config,
+    service::{QRequestData, QService},
+    session_store::{CreateSession, InMemorySessionStore, SessionKey, SessionStore, StoreError},
+};
+use tokio::sync::oneshot;
+
+/// Number of sessions created in the batch
+const BATCH_SIZE: usize = 10_000;
+
+/// Number of single sessions created while the batch is being created
+const CONCURRENT_SESSIONS: usize = 200;
+
+/// Session insertion recorded by the [RecordingStore]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Insertion {
+    Batch,
+    Single,
+}
+
+/// In-memory store recording the order sessions are inserted in
+#[derive(Default)]
+struct RecordingStore {
+    inner: InMemorySessionStore,
+    insertions: Arc<Mutex<Vec<Insertion>>>,
+}
+
+impl RecordingStore {
+    fn record(&self, insertion: Insertion) {
+        self.insertions.lock().unwrap().push(insertion);
+    }
+}
+
+#[async_trait]
+impl SessionStore for RecordingStore {
+    async fn insert(&self, key: SessionKey, data: QRequestData) -> Result<(), StoreError> {
+        // Inserted as a batch of one so the insertion is recorded while the
+        // inner store is locked, in the same order the sessions are inserted
+        let mut data = Some(data).filter(|data| (data.id, data.secret) == key);
+        let mut create = |_: &dyn Fn(u32) -> bool| {
+            let data = data.take();
+            self.record(Insertion::Single);
+            data
+        };
+
+        match self.inner.insert_many(1, &mut create).await {
+            Ok(_) => Ok(()),
+            Err(StoreError::Incomplete { .. }) => Err(StoreError::AlreadyExists),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn insert_many(
+        &self,
+        count: usize,
+        create: &mut CreateSession<'_>,
+    ) -> Result<Vec<SessionKey>, StoreError> {
+        // Recorded as each session is created, while the inner store is locked
+        let mut create = |in_use: &dyn Fn(u32) -> bool| {
+            let data = create(in_use);
+            self.record(Insertion::Batch);
+            data
+        };
+        self.inner.insert_many(count, &mut create).await
+    }
+
+    async fn get(&self, key: SessionKey) -> Option<QRequestData> {
+        self.inner.get(key).await
+    }
+
+    async fn remove(&self, key: SessionKey) -> bool {
+        self.inner.remove(key).await
+    }
+
+    async fn contains(&self, key: SessionKey) -> bool {
+        self.inner.contains(key).await
+    }
+
+    async fn list(&self) -> Vec<QRequestData> {
+        self.inner.list().await
+    }
+
+    async fn count_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> usize {
+        self.inner.count_where(predicate).await
+    }
+
+    async fn update(
+        &self,
+        key: SessionKey,
+        update: &mut (dyn for<'a> FnMut(&'a mut QRequestData) + Send),
+    ) -> bool {
+        self.inner.update(key, update).await
+    }
+
+    async fn remove_where(
+        &self,
+        predicate: &(dyn for<'a> Fn(&'a QRequestData) -> bool + Send + Sync),
+    ) -> Vec<QRequestData> {
+        self.inner.remove_where(predicate).await
+    }
+}
+
+/// Every session in a large batch is created with its own session ID
+/// and no other session is inserted while the batch is being created
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn batch_create_sessions_without_id_collisions() {
+    let store = RecordingStore::default();
+    let insertions = store.insertions.clone();
+    let service = Arc::new(QService::new(&Config::default(), Box::new(store)));
+
+    // Single sessions are still being created when the batch starts
+    let (started_tx, started_rx) = oneshot::channel();
+    let single = tokio::spawn({
+        let service = service.clone();
+        async move {
+            let client = SocketAddr::from(([127, 0, 0, 1], 3659));
+            let mut started_tx = Some(started_tx);
+            let mut sessions = Vec::with_capacity(CONCURRENT_SESSIONS);
+            for index in 0..CONCURRENT_SESSIONS {
+                if index == CONCURRENT_SESSIONS / 2 {
+                    _ = started_tx.take().unwrap().send(());
+                }
+
+                let session = service
+                    .create_request_data(1, 5, 3659, 2, client)
+                    .await
+                    .unwrap();
+                sessions.push(session);
+                tokio::task::yield_now().await;
+            }
+            sessions
+        }
+    });
+
+    started_rx.await.unwrap();
+    let sessions = service.batch_create_sessions(BATCH_SIZE, 1).await.unwrap();
+    let single = single.await.unwrap();
+    assert_eq!(sessions.len(), BATCH_SIZE);
+
+    let ids: HashSet<u32> = sessions
+        .iter()
+        .chain(single.iter())
+        .map(|(id, _)| *id)
+        .collect();
+    assert_eq!(
+        ids.len(),
+        BATCH_SIZE + CONCURRENT_SESSIONS,
+        "session IDs collided"
+    );
+
+    // The batch was inserted as one uninterrupted run
+    let insertions = insertions.lock().unwrap().clone();
+    let first = insertions
+        .iter()
+        .position(|value| *value == Insertion::Batch);
+    let last = insertions
+        .iter()
+        .rposition(|value| *value == Insertion::Batch);
+    let (first, last) = (first.unwrap(), last.unwrap());
+    assert!(first >= CONCURRENT_SESSIONS / 2);
+    assert_eq!(last - first + 1, BATCH_SIZE);
+    assert!(
+        insertions[first..=last]
+            .iter()
+            .all(|value| *value == Insertion::Batch),
+        "session inserted during the batch"
+    );
+
+    assert_eq!(
+        service.list_sessions().await.len(),
+        BATCH_SIZE + CONCURRENT_SESSIONS
+    );
+    for (id, secret) in sessions {
+        assert!(service.session_exists(id, secret).await);
+    }
+}